@@ -0,0 +1,162 @@
+use crate::stream::StereoSample;
+use std::fmt::{Debug, Display};
+
+/// Where a single output channel gets its signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelSource {
+    Left,
+    Right,
+
+    // The average of left and right.
+    Mid,
+
+    Silence,
+}
+impl ChannelSource {
+    pub fn sample_from(&self, sample: &StereoSample) -> f32 {
+        match self {
+            ChannelSource::Left => sample.left,
+            ChannelSource::Right => sample.right,
+            ChannelSource::Mid => (sample.left + sample.right) * 0.5,
+            ChannelSource::Silence => 0.0,
+        }
+    }
+}
+
+/// What to send to output channels beyond the first two on devices that have
+/// more than two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtraChannels {
+    #[default]
+    Silence,
+
+    // Left on odd-numbered channels, right on even-numbered ones, so that a
+    // 5.1 interface gets the stereo pair on each of its front, center/LFE, and
+    // rear pairs.
+    RepeatStereo,
+
+    // The mono downmix on every extra channel.
+    Mid,
+}
+impl ExtraChannels {
+    pub const ALL: [ExtraChannels; 3] = [
+        ExtraChannels::Silence,
+        ExtraChannels::RepeatStereo,
+        ExtraChannels::Mid,
+    ];
+}
+impl Display for ExtraChannels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExtraChannels::Silence => "Silence",
+            ExtraChannels::RepeatStereo => "Repeat stereo",
+            ExtraChannels::Mid => "Mono mix",
+        })
+    }
+}
+
+/// Describes how a [StereoSample] fills one frame of a device that has any
+/// number of channels. A mono device gets the downmix of left and right, a
+/// stereo device gets left and right, and any channels past the second are
+/// filled according to [ExtraChannels].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelMap {
+    sources: Vec<ChannelSource>,
+}
+impl ChannelMap {
+    pub fn new_with(channel_count: usize, extra_channels: ExtraChannels) -> Self {
+        let sources = match channel_count {
+            0 => Vec::default(),
+            1 => vec![ChannelSource::Mid],
+            _ => (0..channel_count)
+                .map(|channel| match channel {
+                    0 => ChannelSource::Left,
+                    1 => ChannelSource::Right,
+                    _ => match extra_channels {
+                        ExtraChannels::Silence => ChannelSource::Silence,
+                        ExtraChannels::RepeatStereo => {
+                            if channel % 2 == 0 {
+                                ChannelSource::Left
+                            } else {
+                                ChannelSource::Right
+                            }
+                        }
+                        ExtraChannels::Mid => ChannelSource::Mid,
+                    },
+                })
+                .collect(),
+        };
+        Self { sources }
+    }
+
+    /// The number of channels in each frame.
+    pub fn channel_count(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// The source of each channel, in frame order.
+    pub fn sources(&self) -> &[ChannelSource] {
+        &self.sources
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: StereoSample = StereoSample {
+        left: 0.5,
+        right: -0.25,
+    };
+
+    /// The frame that `map` makes of [SAMPLE].
+    fn frame(map: &ChannelMap) -> Vec<f32> {
+        map.sources()
+            .iter()
+            .map(|s| s.sample_from(&SAMPLE))
+            .collect()
+    }
+
+    #[test]
+    fn mono_gets_downmix() {
+        for extra_channels in ExtraChannels::ALL {
+            let map = ChannelMap::new_with(1, extra_channels);
+            assert_eq!(map.channel_count(), 1);
+            assert_eq!(frame(&map), vec![0.125]);
+        }
+    }
+
+    #[test]
+    fn stereo_is_untouched() {
+        for extra_channels in ExtraChannels::ALL {
+            assert_eq!(
+                frame(&ChannelMap::new_with(2, extra_channels)),
+                vec![0.5, -0.25]
+            );
+        }
+    }
+
+    #[test]
+    fn extra_channels_are_filled() {
+        for channel_count in [4, 6, 8] {
+            let silent = ChannelMap::new_with(channel_count, ExtraChannels::Silence);
+            assert_eq!(silent.channel_count(), channel_count);
+            let mut expected = vec![0.5, -0.25];
+            expected.resize(channel_count, 0.0);
+            assert_eq!(frame(&silent), expected);
+
+            let repeated = ChannelMap::new_with(channel_count, ExtraChannels::RepeatStereo);
+            assert_eq!(frame(&repeated), [0.5, -0.25].repeat(channel_count / 2));
+
+            let mid = ChannelMap::new_with(channel_count, ExtraChannels::Mid);
+            let mut expected = vec![0.5, -0.25];
+            expected.resize(channel_count, 0.125);
+            assert_eq!(frame(&mid), expected);
+        }
+    }
+
+    #[test]
+    fn no_channels_means_empty_frames() {
+        assert!(frame(&ChannelMap::new_with(0, ExtraChannels::Mid)).is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+//! Plays notes from the computer's keyboard, for when there's no MIDI
+//! controller around.
+//!
+//! The layout is the one that music trackers use. The bottom letter row is
+//! the lower octave, Z through M for the white keys and the row above for the
+//! black keys, running on through the punctuation. The top letter row is the
+//! upper octave, Q through U for the white keys and the number row for the
+//! black keys, running on through P. The left and right arrow keys shift
+//! both octaves, and the up and down arrow keys change the velocity.
+
+use crate::render::SynthesizerInput;
+use iced::keyboard::{KeyCode, Modifiers};
+use std::{collections::HashMap, time::Instant};
+
+#[derive(Debug)]
+pub struct ComputerKeyboard {
+    // The octave of the bottom row's Z key, where middle C starts octave 4.
+    octave: u8,
+
+    // What the next note gets played at.
+    velocity: u8,
+
+    // The keys that are down, and the notes they started. We check it to
+    // tell a new key press from the operating system's key repeat, and keep
+    // the note so that the right one stops even if the octave has changed
+    // since. The two rows overlap, so two keys can hold the same note, which
+    // stops only once both are up.
+    held_keys: HashMap<KeyCode, u8>,
+}
+impl Default for ComputerKeyboard {
+    fn default() -> Self {
+        Self {
+            octave: 3,
+            velocity: 100,
+            held_keys: HashMap::default(),
+        }
+    }
+}
+impl ComputerKeyboard {
+    pub const MAX_OCTAVE: u8 = 7;
+    const VELOCITY_STEP: u8 = 8;
+
+    pub fn octave(&self) -> u8 {
+        self.octave
+    }
+
+    pub fn velocity(&self) -> u8 {
+        self.velocity
+    }
+
+    /// Handles a key going down, and returns the note to start, if any. Keys
+    /// pressed along with Ctrl, Alt, or the logo key are left for shortcuts.
+    pub fn key_pressed(
+        &mut self,
+        key_code: KeyCode,
+        modifiers: Modifiers,
+    ) -> Option<SynthesizerInput> {
+        if modifiers.control() || modifiers.alt() || modifiers.logo() {
+            return None;
+        }
+        if self.held_keys.contains_key(&key_code) {
+            // Key repeat.
+            return None;
+        }
+        match key_code {
+            KeyCode::Left => self.octave = self.octave.saturating_sub(1),
+            KeyCode::Right => self.octave = (self.octave + 1).min(Self::MAX_OCTAVE),
+            KeyCode::Up => {
+                self.velocity = self.velocity.saturating_add(Self::VELOCITY_STEP).min(127)
+            }
+            KeyCode::Down => {
+                self.velocity = self.velocity.saturating_sub(Self::VELOCITY_STEP).max(1)
+            }
+            _ => {
+                let note = (self.octave + 1) * 12 + Self::semitone(key_code)?;
+                let is_sounding = self.is_holding(note);
+                self.held_keys.insert(key_code, note);
+                if !is_sounding {
+                    return Some(SynthesizerInput::NoteOn(
+                        note,
+                        self.velocity,
+                        Instant::now(),
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Handles a key coming up, and returns the note to stop, if any.
+    pub fn key_released(&mut self, key_code: KeyCode) -> Option<SynthesizerInput> {
+        let note = self.held_keys.remove(&key_code)?;
+        (!self.is_holding(note)).then(|| SynthesizerInput::NoteOff(note, Instant::now()))
+    }
+
+    /// Whether any key that's down is holding `note`.
+    fn is_holding(&self, note: u8) -> bool {
+        self.held_keys.values().any(|held| *held == note)
+    }
+
+    /// Stops every note whose key is down. We won't hear about those keys
+    /// coming up once the window loses focus, so this keeps their notes from
+    /// getting stuck.
+    pub fn release_all(&mut self) -> Vec<SynthesizerInput> {
+        let now = Instant::now();
+        let mut notes: Vec<u8> = self.held_keys.drain().map(|(_, note)| note).collect();
+        notes.sort_unstable();
+        notes.dedup();
+        notes
+            .into_iter()
+            .map(|note| SynthesizerInput::NoteOff(note, now))
+            .collect()
+    }
+
+    /// How many semitones above the lower octave's C a key plays, if it plays
+    /// anything.
+    fn semitone(key_code: KeyCode) -> Option<u8> {
+        Some(match key_code {
+            KeyCode::Z => 0,
+            KeyCode::S => 1,
+            KeyCode::X => 2,
+            KeyCode::D => 3,
+            KeyCode::C => 4,
+            KeyCode::V => 5,
+            KeyCode::G => 6,
+            KeyCode::B => 7,
+            KeyCode::H => 8,
+            KeyCode::N => 9,
+            KeyCode::J => 10,
+            KeyCode::M => 11,
+            KeyCode::Comma => 12,
+            KeyCode::L => 13,
+            KeyCode::Period => 14,
+            KeyCode::Semicolon => 15,
+            KeyCode::Slash => 16,
+
+            KeyCode::Q => 12,
+            KeyCode::Key2 => 13,
+            KeyCode::W => 14,
+            KeyCode::Key3 => 15,
+            KeyCode::E => 16,
+            KeyCode::R => 17,
+            KeyCode::Key5 => 18,
+            KeyCode::T => 19,
+            KeyCode::Key6 => 20,
+            KeyCode::Y => 21,
+            KeyCode::Key7 => 22,
+            KeyCode::U => 23,
+            KeyCode::I => 24,
+            KeyCode::Key9 => 25,
+            KeyCode::O => 26,
+            KeyCode::Key0 => 27,
+            KeyCode::P => 28,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(keyboard: &mut ComputerKeyboard, key_code: KeyCode) -> Option<(u8, u8)> {
+        match keyboard.key_pressed(key_code, Modifiers::default()) {
+            Some(SynthesizerInput::NoteOn(note, velocity, _)) => Some((note, velocity)),
+            None => None,
+            Some(input) => panic!("unexpected {:?}", input),
+        }
+    }
+
+    fn release(keyboard: &mut ComputerKeyboard, key_code: KeyCode) -> Option<u8> {
+        match keyboard.key_released(key_code) {
+            Some(SynthesizerInput::NoteOff(note, _)) => Some(note),
+            None => None,
+            Some(input) => panic!("unexpected {:?}", input),
+        }
+    }
+
+    #[test]
+    fn rows_play_tracker_layout() {
+        let mut keyboard = ComputerKeyboard::default();
+        for (key_code, note) in [
+            (KeyCode::Z, 48),
+            (KeyCode::S, 49),
+            (KeyCode::M, 59),
+            (KeyCode::Slash, 64),
+            (KeyCode::Q, 60),
+            (KeyCode::Key2, 61),
+            (KeyCode::P, 76),
+        ] {
+            assert_eq!(press(&mut keyboard, key_code), Some((note, 100)));
+            assert_eq!(release(&mut keyboard, key_code), Some(note));
+        }
+        assert_eq!(press(&mut keyboard, KeyCode::A), None);
+    }
+
+    #[test]
+    fn octave_stays_in_range() {
+        let mut keyboard = ComputerKeyboard::default();
+        for _ in 0..10 {
+            press(&mut keyboard, KeyCode::Left);
+        }
+        assert_eq!(keyboard.octave(), 0);
+        assert_eq!(press(&mut keyboard, KeyCode::Z), Some((12, 100)));
+        for _ in 0..10 {
+            press(&mut keyboard, KeyCode::Right);
+        }
+        assert_eq!(keyboard.octave(), ComputerKeyboard::MAX_OCTAVE);
+    }
+
+    #[test]
+    fn note_stops_even_after_octave_change() {
+        let mut keyboard = ComputerKeyboard::default();
+        assert_eq!(press(&mut keyboard, KeyCode::Z), Some((48, 100)));
+        press(&mut keyboard, KeyCode::Right);
+        assert_eq!(release(&mut keyboard, KeyCode::Z), Some(48));
+    }
+
+    #[test]
+    fn key_repeat_is_ignored() {
+        let mut keyboard = ComputerKeyboard::default();
+        assert!(press(&mut keyboard, KeyCode::X).is_some());
+        assert_eq!(press(&mut keyboard, KeyCode::X), None);
+        assert!(release(&mut keyboard, KeyCode::X).is_some());
+        assert_eq!(release(&mut keyboard, KeyCode::X), None);
+    }
+
+    #[test]
+    fn overlapping_keys_share_note() {
+        let mut keyboard = ComputerKeyboard::default();
+        assert_eq!(press(&mut keyboard, KeyCode::Comma), Some((60, 100)));
+        assert_eq!(press(&mut keyboard, KeyCode::Q), None);
+        assert_eq!(release(&mut keyboard, KeyCode::Q), None);
+        assert_eq!(release(&mut keyboard, KeyCode::Comma), Some(60));
+    }
+
+    #[test]
+    fn release_all_stops_each_note_once() {
+        let mut keyboard = ComputerKeyboard::default();
+        press(&mut keyboard, KeyCode::Comma);
+        press(&mut keyboard, KeyCode::Q);
+        press(&mut keyboard, KeyCode::Z);
+        let notes: Vec<u8> = keyboard
+            .release_all()
+            .into_iter()
+            .map(|input| match input {
+                SynthesizerInput::NoteOff(note, _) => note,
+                input => panic!("unexpected {:?}", input),
+            })
+            .collect();
+        assert_eq!(notes, vec![48, 60]);
+    }
+}
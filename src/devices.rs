@@ -0,0 +1,467 @@
+use crate::{
+    null_backend::{NullDevice, NullStream},
+    stream::AudioStreamError,
+};
+use cpal::{
+    traits::{DeviceTrait, HostTrait},
+    SupportedStreamConfigRange,
+};
+use std::fmt::{Debug, Display};
+
+/// The backend that an output device belongs to: either one of cpal's hosts,
+/// or our own [crate::null_backend].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputHost {
+    Cpal(cpal::HostId),
+    Null,
+}
+impl OutputHost {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputHost::Cpal(host_id) => host_id.name(),
+            OutputHost::Null => "Null",
+        }
+    }
+}
+
+/// Identifies an output device across all the backends on this machine. cpal
+/// doesn't give devices a stable ID, so we go by host and name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputDeviceId {
+    pub host: OutputHost,
+    pub name: String,
+}
+impl OutputDeviceId {
+    /// The null backend has exactly one device.
+    pub fn null() -> Self {
+        Self {
+            host: OutputHost::Null,
+            name: NullStream::DEVICE_NAME.to_string(),
+        }
+    }
+}
+impl Display for OutputDeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.host.name(), self.name)
+    }
+}
+
+/// An output device that we can open a stream on.
+#[derive(Clone)]
+pub enum OutputDevice {
+    Cpal(cpal::Device),
+    Null(NullDevice),
+}
+
+/// Describes an output device and the stream configurations it supports.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputDeviceInfo {
+    pub id: OutputDeviceId,
+
+    // Whether this is the default output device of the default host, i.e.,
+    // the one we'd pick if nobody told us otherwise.
+    pub is_default: bool,
+
+    pub configs: Vec<SupportedStreamConfigRange>,
+}
+impl Display for OutputDeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_default {
+            write!(f, "{} (default)", self.id)
+        } else {
+            write!(f, "{}", self.id)
+        }
+    }
+}
+
+/// Returns every output device on every available host, followed by the null
+/// device. Devices that fail to report a name are skipped, because we'd have no
+/// way to ask for them again.
+pub fn enumerate_output_devices() -> Vec<OutputDeviceInfo> {
+    let mut devices = Vec::default();
+    let default_host_id = cpal::default_host().id();
+    for host_id in cpal::available_hosts() {
+        let Ok(host) = cpal::host_from_id(host_id) else {
+            continue;
+        };
+        let default_name = if host_id == default_host_id {
+            host.default_output_device().and_then(|d| d.name().ok())
+        } else {
+            None
+        };
+        let Ok(output_devices) = host.output_devices() else {
+            continue;
+        };
+        for device in output_devices {
+            let Ok(name) = device.name() else {
+                continue;
+            };
+            let configs = device
+                .supported_output_configs()
+                .map(|configs| configs.collect())
+                .unwrap_or_default();
+            devices.push(OutputDeviceInfo {
+                is_default: default_name.as_ref() == Some(&name),
+                id: OutputDeviceId {
+                    host: OutputHost::Cpal(host_id),
+                    name,
+                },
+                configs,
+            });
+        }
+    }
+    devices.push(NullStream::device_info());
+    devices
+}
+
+/// Finds the output device with the given ID, if it's still around.
+pub fn find_output_device(id: &OutputDeviceId) -> Result<OutputDevice, AudioStreamError> {
+    match id.host {
+        OutputHost::Cpal(host_id) => {
+            let host = cpal::host_from_id(host_id)?;
+            host.output_devices()?
+                .find(|d| d.name().is_ok_and(|name| name == id.name))
+                .map(OutputDevice::Cpal)
+                .ok_or_else(|| AudioStreamError::NoDevice(id.to_string()))
+        }
+        OutputHost::Null => Ok(OutputDevice::Null(NullDevice::default())),
+    }
+}
+
+/// Formats a supported config range compactly for display, e.g., "2ch
+/// 44100-48000 Hz f32".
+pub fn describe_config_range(config: &SupportedStreamConfigRange) -> String {
+    let min = config.min_sample_rate().0;
+    let max = config.max_sample_rate().0;
+    if min == max {
+        format!(
+            "{}ch {} Hz {}",
+            config.channels(),
+            min,
+            config.sample_format()
+        )
+    } else {
+        format!(
+            "{}ch {}-{} Hz {}",
+            config.channels(),
+            min,
+            max,
+            config.sample_format()
+        )
+    }
+}
+
+/// What the app would like the audio stream to look like. Every field is a
+/// preference rather than a demand; [negotiate_output_config()] explains how
+/// we compromise when the device can't oblige. Fields left as None (or
+/// [cpal::BufferSize::Default]) take whatever the device's default config
+/// says.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamConfigRequest {
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub sample_format: Option<cpal::SampleFormat>,
+
+    // The number of frames per cpal callback. This is the device's buffer,
+    // not our [crate::stream::AudioQueue].
+    pub buffer_size: cpal::BufferSize,
+}
+impl Default for StreamConfigRequest {
+    fn default() -> Self {
+        Self {
+            sample_rate: None,
+            channels: None,
+            sample_format: None,
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+}
+
+/// The stream configuration that we actually got, along with the device it's
+/// on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamDescription {
+    pub device: OutputDeviceId,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: cpal::SampleFormat,
+    pub buffer_size: cpal::BufferSize,
+}
+impl StreamDescription {
+    /// Returns the cpal config to hand to `build_output_stream()`.
+    pub fn stream_config(&self) -> cpal::StreamConfig {
+        cpal::StreamConfig {
+            channels: self.channels,
+            sample_rate: cpal::SampleRate(self.sample_rate),
+            buffer_size: self.buffer_size,
+        }
+    }
+}
+impl Display for StreamDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} Hz, {}ch, {}",
+            self.sample_rate, self.channels, self.sample_format
+        )?;
+        match self.buffer_size {
+            cpal::BufferSize::Default => write!(f, ", default buffer"),
+            cpal::BufferSize::Fixed(frames) => write!(f, ", {} frame buffer", frames),
+        }
+    }
+}
+
+/// Picks the stream configuration for `device` that best satisfies `request`.
+///
+/// Anything the request leaves unspecified is filled in from the device's
+/// default output config. Then each of the device's supported config ranges is
+/// ranked by what it can match, in this order of importance:
+///
+/// 1. sample rate (the range contains the requested rate),
+/// 2. channel count,
+/// 3. sample format.
+///
+/// So if no range matches everything, we give up the sample format before the
+/// channel count, and the channel count before the sample rate. Ties go to
+/// whichever range the device listed first. If even the sample rate can't be
+/// matched, we use the closest rate the winning range supports.
+///
+/// A fixed buffer size is clamped to what the winning range supports, if the
+/// device says. Some devices accept the config here and then fail to build a
+/// stream with it anyway; [crate::stream::AudioStream] handles that by
+/// retrying with [cpal::BufferSize::Default].
+///
+/// If the device won't list its supported configs at all, we use its default
+/// config.
+///
+/// The null device is far more accommodating; see [NullStream::negotiate()].
+pub fn negotiate_output_config(
+    id: &OutputDeviceId,
+    device: &OutputDevice,
+    request: &StreamConfigRequest,
+) -> Result<StreamDescription, AudioStreamError> {
+    let device = match device {
+        OutputDevice::Cpal(device) => device,
+        OutputDevice::Null(_) => return Ok(NullStream::negotiate(id, request)),
+    };
+    let default_config = device.default_output_config()?;
+    let configs = device.supported_output_configs().ok();
+    Ok(choose_output_config(id, request, default_config, configs))
+}
+
+/// The part of [negotiate_output_config()] that doesn't need a device: picks
+/// from `configs`, or falls back to `default_config` if there aren't any.
+fn choose_output_config(
+    id: &OutputDeviceId,
+    request: &StreamConfigRequest,
+    default_config: cpal::SupportedStreamConfig,
+    configs: Option<impl Iterator<Item = cpal::SupportedStreamConfigRange>>,
+) -> StreamDescription {
+    let sample_rate = request
+        .sample_rate
+        .unwrap_or(default_config.sample_rate().0);
+    let channels = request.channels.unwrap_or(default_config.channels());
+    let sample_format = request
+        .sample_format
+        .unwrap_or(default_config.sample_format());
+
+    let best = configs.and_then(|configs| {
+        configs
+            .enumerate()
+            .max_by_key(|(index, config)| {
+                (
+                    config.min_sample_rate().0 <= sample_rate
+                        && sample_rate <= config.max_sample_rate().0,
+                    config.channels() == channels,
+                    config.sample_format() == sample_format,
+                    // max_by_key() prefers the last of equals, so reverse
+                    // the index to make ties go to the first.
+                    std::cmp::Reverse(*index),
+                )
+            })
+            .map(|(_, config)| config)
+    });
+
+    let (sample_rate, channels, sample_format, supported_buffer_size) = if let Some(best) = best {
+        (
+            sample_rate.clamp(best.min_sample_rate().0, best.max_sample_rate().0),
+            best.channels(),
+            best.sample_format(),
+            *best.buffer_size(),
+        )
+    } else {
+        (
+            default_config.sample_rate().0,
+            default_config.channels(),
+            default_config.sample_format(),
+            *default_config.buffer_size(),
+        )
+    };
+    let buffer_size = match (request.buffer_size, supported_buffer_size) {
+        (cpal::BufferSize::Fixed(frames), cpal::SupportedBufferSize::Range { min, max }) => {
+            cpal::BufferSize::Fixed(frames.clamp(min, max))
+        }
+        (buffer_size, _) => buffer_size,
+    };
+
+    StreamDescription {
+        device: id.clone(),
+        sample_rate,
+        channels,
+        sample_format,
+        buffer_size,
+    }
+}
+
+/// Returns the ID of the given device. This is the inverse of
+/// [find_output_device()].
+pub fn output_device_id(
+    host_id: cpal::HostId,
+    device: &cpal::Device,
+) -> Result<OutputDeviceId, AudioStreamError> {
+    Ok(OutputDeviceId {
+        host: OutputHost::Cpal(host_id),
+        name: device.name()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::{SampleFormat, SampleRate, SupportedBufferSize};
+
+    const BUFFER_SIZE: SupportedBufferSize = SupportedBufferSize::Range { min: 64, max: 4096 };
+
+    fn id() -> OutputDeviceId {
+        OutputDeviceId {
+            host: OutputHost::Null,
+            name: "test".to_string(),
+        }
+    }
+
+    fn default_config() -> cpal::SupportedStreamConfig {
+        cpal::SupportedStreamConfig::new(2, SampleRate(48000), BUFFER_SIZE, SampleFormat::F32)
+    }
+
+    fn range(
+        channels: u16,
+        sample_rates: (u32, u32),
+        sample_format: SampleFormat,
+    ) -> cpal::SupportedStreamConfigRange {
+        cpal::SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(sample_rates.0),
+            SampleRate(sample_rates.1),
+            BUFFER_SIZE,
+            sample_format,
+        )
+    }
+
+    fn choose(
+        request: &StreamConfigRequest,
+        configs: &[cpal::SupportedStreamConfigRange],
+    ) -> StreamDescription {
+        choose_output_config(
+            &id(),
+            request,
+            default_config(),
+            Some(configs.iter().cloned()),
+        )
+    }
+
+    #[test]
+    fn preferred_format_wins() {
+        let configs = [
+            range(2, (44100, 96000), SampleFormat::I16),
+            range(2, (44100, 96000), SampleFormat::F32),
+            range(2, (44100, 96000), SampleFormat::I32),
+        ];
+        let request = StreamConfigRequest {
+            sample_format: Some(SampleFormat::I32),
+            ..Default::default()
+        };
+        let description = choose(&request, &configs);
+        assert_eq!(description.sample_format, SampleFormat::I32);
+        assert_eq!(description.sample_rate, 48000);
+        assert_eq!(description.channels, 2);
+
+        // With no preference, the device's default format.
+        let description = choose(&StreamConfigRequest::default(), &configs);
+        assert_eq!(description.sample_format, SampleFormat::F32);
+    }
+
+    #[test]
+    fn sample_rate_outranks_format_and_channels() {
+        let configs = [
+            range(2, (44100, 48000), SampleFormat::I32),
+            range(1, (96000, 96000), SampleFormat::I16),
+        ];
+        let request = StreamConfigRequest {
+            sample_rate: Some(96000),
+            channels: Some(2),
+            sample_format: Some(SampleFormat::I32),
+            ..Default::default()
+        };
+        let description = choose(&request, &configs);
+        assert_eq!(description.sample_rate, 96000);
+        assert_eq!(description.channels, 1);
+        assert_eq!(description.sample_format, SampleFormat::I16);
+    }
+
+    #[test]
+    fn unsupported_rate_falls_back_to_closest() {
+        let configs = [
+            range(2, (44100, 48000), SampleFormat::F32),
+            range(2, (8000, 22050), SampleFormat::I16),
+        ];
+        let request = StreamConfigRequest {
+            sample_rate: Some(192000),
+            ..Default::default()
+        };
+        let description = choose(&request, &configs);
+        assert_eq!(description.sample_rate, 48000);
+        assert_eq!(description.sample_format, SampleFormat::F32);
+
+        let request = StreamConfigRequest {
+            sample_rate: Some(4000),
+            sample_format: Some(SampleFormat::I16),
+            ..Default::default()
+        };
+        assert_eq!(choose(&request, &configs).sample_rate, 8000);
+    }
+
+    #[test]
+    fn unsatisfiable_request_gets_something_playable() {
+        let request = StreamConfigRequest {
+            sample_rate: Some(1),
+            channels: Some(64),
+            sample_format: Some(SampleFormat::U64),
+            buffer_size: cpal::BufferSize::Fixed(1 << 20),
+        };
+        let configs = [range(2, (44100, 48000), SampleFormat::F32)];
+        assert_eq!(
+            choose(&request, &configs),
+            StreamDescription {
+                device: id(),
+                sample_rate: 44100,
+                channels: 2,
+                sample_format: SampleFormat::F32,
+                buffer_size: cpal::BufferSize::Fixed(4096),
+            }
+        );
+
+        // A device that won't list its configs gets its default.
+        let description = choose_output_config(
+            &id(),
+            &request,
+            default_config(),
+            None::<std::iter::Empty<_>>,
+        );
+        assert_eq!(description.sample_rate, 48000);
+        assert_eq!(description.channels, 2);
+        assert_eq!(description.sample_format, SampleFormat::F32);
+
+        // As does one that lists none.
+        assert_eq!(choose(&request, &[]), description);
+    }
+}
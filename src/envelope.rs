@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+
+/// The shape of each segment of an [Envelope].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnvelopeCurve {
+    // Straight lines from one level to the next.
+    #[default]
+    Linear,
+
+    // Fast at first, then easing into the next level, like an analog
+    // envelope's capacitor charging and discharging. Sounds more natural,
+    // especially on release.
+    Exponential,
+}
+impl EnvelopeCurve {
+    pub const ALL: [EnvelopeCurve; 2] = [EnvelopeCurve::Linear, EnvelopeCurve::Exponential];
+}
+impl Display for EnvelopeCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EnvelopeCurve::Linear => "Linear",
+            EnvelopeCurve::Exponential => "Exponential",
+        })
+    }
+}
+
+/// The shape of every note's [Envelope].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnvelopeSettings {
+    // Seconds from note-on to full level.
+    pub attack: f32,
+
+    // Seconds from full level to the sustain level.
+    pub decay: f32,
+
+    // The level, from 0.0 to 1.0, that the note holds until note-off.
+    pub sustain: f32,
+
+    // Seconds from note-off to silence.
+    pub release: f32,
+
+    pub curve: EnvelopeCurve,
+}
+impl EnvelopeSettings {
+    pub const MAX_ATTACK: f32 = 2.0;
+    pub const MAX_DECAY: f32 = 2.0;
+    pub const MAX_RELEASE: f32 = 5.0;
+
+    /// The same settings, but with everything within range.
+    pub fn clamped(&self) -> Self {
+        Self {
+            attack: self.attack.clamp(0.0, Self::MAX_ATTACK),
+            decay: self.decay.clamp(0.0, Self::MAX_DECAY),
+            sustain: self.sustain.clamp(0.0, 1.0),
+            release: self.release.clamp(0.0, Self::MAX_RELEASE),
+            curve: self.curve,
+        }
+    }
+}
+impl Default for EnvelopeSettings {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.8,
+            release: 0.3,
+            curve: EnvelopeCurve::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Stage {
+    #[default]
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// An attack/decay/sustain/release amplitude envelope for one voice.
+#[derive(Clone, Debug, Default)]
+pub struct Envelope {
+    stage: Stage,
+    level: f32,
+
+    // The level at note-off, so that a linear release takes the same time
+    // wherever it starts.
+    release_from: f32,
+}
+impl Envelope {
+    // How far past its target an exponential segment aims, as a fraction of
+    // full scale. Without overshooting, it would never quite get there. The
+    // attack aims further, so that it's closer to linear, which is how analog
+    // envelopes tend to behave.
+    const ATTACK_OVERSHOOT: f32 = 0.3;
+    const DECAY_OVERSHOOT: f32 = 0.001;
+
+    // How long the level takes to move all the way across the range when the
+    // sustain level changes under a held note. Quick enough to feel
+    // immediate, but slow enough not to click.
+    const SUSTAIN_GLIDE_SECONDS: f32 = 0.01;
+
+    /// Starts the attack from wherever the envelope is now, so that
+    /// retriggering a sounding voice doesn't click.
+    pub fn note_on(&mut self) {
+        self.stage = Stage::Attack;
+    }
+
+    /// Starts the release.
+    pub fn note_off(&mut self) {
+        if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+            self.release_from = self.level;
+        }
+    }
+
+    /// True once the release has finished.
+    pub fn is_idle(&self) -> bool {
+        self.stage == Stage::Idle
+    }
+
+    /// The current level, from 0.0 to 1.0.
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// Advances by one sample and returns the new level.
+    pub fn next_level(&mut self, settings: &EnvelopeSettings, sample_rate: usize) -> f32 {
+        let sample_rate = sample_rate as f32;
+        let sustain = settings.sustain.clamp(0.0, 1.0);
+        match self.stage {
+            Stage::Idle => self.level = 0.0,
+            Stage::Attack => {
+                if self.approach(1.0, 1.0, settings.attack, sample_rate, settings.curve) {
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                if self.approach(
+                    sustain,
+                    1.0 - sustain,
+                    settings.decay,
+                    sample_rate,
+                    settings.curve,
+                ) {
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => {
+                if self.level != sustain {
+                    self.approach(
+                        sustain,
+                        1.0,
+                        Self::SUSTAIN_GLIDE_SECONDS,
+                        sample_rate,
+                        EnvelopeCurve::Linear,
+                    );
+                }
+            }
+            Stage::Release => {
+                if self.approach(
+                    0.0,
+                    self.release_from,
+                    settings.release,
+                    sample_rate,
+                    settings.curve,
+                ) {
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+        self.level
+    }
+
+    /// Moves the level one sample's worth toward `target`, for a segment that
+    /// covers `distance` in `seconds`. Returns true when it gets there.
+    fn approach(
+        &mut self,
+        target: f32,
+        distance: f32,
+        seconds: f32,
+        sample_rate: f32,
+        curve: EnvelopeCurve,
+    ) -> bool {
+        let samples = seconds * sample_rate;
+        if samples < 1.0 {
+            self.level = target;
+            return true;
+        }
+        let is_rising = target > self.level;
+        match curve {
+            EnvelopeCurve::Linear => {
+                let step = distance.max(f32::EPSILON) / samples;
+                if is_rising {
+                    self.level += step;
+                } else {
+                    self.level -= step;
+                }
+            }
+            EnvelopeCurve::Exponential => {
+                let overshoot = if is_rising {
+                    Self::ATTACK_OVERSHOOT
+                } else {
+                    Self::DECAY_OVERSHOOT
+                };
+
+                // Chosen so that starting `distance` away, we reach the target
+                // after `samples` samples.
+                let coefficient = (-((distance + overshoot) / overshoot).ln() / samples).exp();
+                let aim = if is_rising {
+                    target + overshoot
+                } else {
+                    target - overshoot
+                };
+                self.level = aim + (self.level - aim) * coefficient;
+            }
+        }
+        if (is_rising && self.level >= target) || (!is_rising && self.level <= target) {
+            self.level = target;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A millisecond per sample keeps the arithmetic easy.
+    const SAMPLE_RATE: usize = 1000;
+
+    fn settings(curve: EnvelopeCurve) -> EnvelopeSettings {
+        EnvelopeSettings {
+            attack: 0.01,
+            decay: 0.02,
+            sustain: 0.5,
+            release: 0.04,
+            curve,
+        }
+    }
+
+    /// Runs the envelope for `samples` samples, returning the levels.
+    fn run(envelope: &mut Envelope, settings: &EnvelopeSettings, samples: usize) -> Vec<f32> {
+        (0..samples)
+            .map(|_| envelope.next_level(settings, SAMPLE_RATE))
+            .collect()
+    }
+
+    /// Runs the envelope until it leaves its current stage, returning the
+    /// levels.
+    fn run_stage(envelope: &mut Envelope, settings: &EnvelopeSettings) -> Vec<f32> {
+        let stage = envelope.stage;
+        let mut levels = Vec::default();
+        while envelope.stage == stage && levels.len() < SAMPLE_RATE {
+            levels.push(envelope.next_level(settings, SAMPLE_RATE));
+        }
+        levels
+    }
+
+    #[test]
+    fn goes_through_every_stage() {
+        for curve in EnvelopeCurve::ALL {
+            let settings = settings(curve);
+            let mut envelope = Envelope::default();
+            assert!(envelope.is_idle());
+            envelope.note_on();
+
+            // Each stage takes as long as it says, give or take a sample.
+            let attack = run_stage(&mut envelope, &settings);
+            assert!((10..=11).contains(&attack.len()), "{}", curve);
+            assert!(attack.windows(2).all(|w| w[1] >= w[0]), "{}", curve);
+            assert_eq!(attack.last(), Some(&1.0), "{}", curve);
+            assert_eq!(envelope.stage, Stage::Decay, "{}", curve);
+
+            let decay = run_stage(&mut envelope, &settings);
+            assert!((20..=21).contains(&decay.len()), "{}", curve);
+            assert!(decay.windows(2).all(|w| w[1] <= w[0]), "{}", curve);
+            assert_eq!(envelope.stage, Stage::Sustain, "{}", curve);
+            assert_eq!(envelope.level(), 0.5, "{}", curve);
+            assert_eq!(run(&mut envelope, &settings, 100), vec![0.5; 100]);
+
+            envelope.note_off();
+            let release = run_stage(&mut envelope, &settings);
+            assert!((40..=41).contains(&release.len()), "{}", curve);
+            assert!(release.windows(2).all(|w| w[1] <= w[0]), "{}", curve);
+            assert!(envelope.is_idle(), "{}", curve);
+            assert_eq!(envelope.level(), 0.0, "{}", curve);
+        }
+    }
+
+    #[test]
+    fn retrigger_starts_from_current_level() {
+        let settings = settings(EnvelopeCurve::Linear);
+        let mut envelope = Envelope::default();
+        envelope.note_on();
+        run(&mut envelope, &settings, 40);
+        envelope.note_off();
+        run(&mut envelope, &settings, 10);
+        let released_to = envelope.level();
+        assert!(released_to > 0.0 && released_to < 0.5);
+
+        envelope.note_on();
+        let next = envelope.next_level(&settings, SAMPLE_RATE);
+        assert!(next > released_to);
+        assert!(next - released_to <= 1.0 / 10.0 + f32::EPSILON);
+    }
+
+    #[test]
+    fn sustain_change_glides() {
+        let mut settings = settings(EnvelopeCurve::Linear);
+        let mut envelope = Envelope::default();
+        envelope.note_on();
+        run(&mut envelope, &settings, 40);
+        assert_eq!(envelope.level(), 0.5);
+
+        settings.sustain = 1.0;
+        let max_step = 1.0 / (Envelope::SUSTAIN_GLIDE_SECONDS * SAMPLE_RATE as f32);
+        let glide = run(&mut envelope, &settings, 20);
+        let mut previous = 0.5;
+        for level in glide {
+            assert!(level - previous <= max_step + f32::EPSILON);
+            previous = level;
+        }
+        assert_eq!(envelope.level(), 1.0);
+    }
+
+    #[test]
+    fn release_before_note_on_does_nothing() {
+        let mut envelope = Envelope::default();
+        envelope.note_off();
+        assert!(envelope.is_idle());
+    }
+}
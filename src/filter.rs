@@ -0,0 +1,177 @@
+use crate::envelope::EnvelopeSettings;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+
+/// Which part of the spectrum a [Filter] lets through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterMode {
+    // No filtering at all.
+    #[default]
+    Off,
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+impl FilterMode {
+    pub const ALL: [FilterMode; 5] = [
+        FilterMode::Off,
+        FilterMode::LowPass,
+        FilterMode::HighPass,
+        FilterMode::BandPass,
+        FilterMode::Notch,
+    ];
+}
+impl Display for FilterMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FilterMode::Off => "Off",
+            FilterMode::LowPass => "Low-pass",
+            FilterMode::HighPass => "High-pass",
+            FilterMode::BandPass => "Band-pass",
+            FilterMode::Notch => "Notch",
+        })
+    }
+}
+
+/// How every voice's [Filter] behaves.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterSettings {
+    pub mode: FilterMode,
+
+    // In Hz, before the envelope and key tracking move it.
+    pub cutoff: f32,
+
+    // From 0.0, which is gentle, to just under 1.0, which rings.
+    pub resonance: f32,
+
+    // How many octaves the filter's own envelope moves the cutoff at its peak.
+    // Negative amounts move it down.
+    pub envelope_amount: f32,
+
+    // How far the cutoff follows the note, from 0.0 (not at all) to 1.0 (an
+    // octave per octave), relative to middle C.
+    pub key_tracking: f32,
+
+    pub envelope: EnvelopeSettings,
+}
+impl FilterSettings {
+    pub const MIN_CUTOFF: f32 = 20.0;
+    pub const MAX_CUTOFF: f32 = 20000.0;
+    pub const MAX_RESONANCE: f32 = 0.98;
+    pub const MAX_ENVELOPE_AMOUNT: f32 = 5.0;
+
+    /// The same settings, but with everything within range.
+    pub fn clamped(&self) -> Self {
+        Self {
+            mode: self.mode,
+            cutoff: self.cutoff.clamp(Self::MIN_CUTOFF, Self::MAX_CUTOFF),
+            resonance: self.resonance.clamp(0.0, Self::MAX_RESONANCE),
+            envelope_amount: self
+                .envelope_amount
+                .clamp(-Self::MAX_ENVELOPE_AMOUNT, Self::MAX_ENVELOPE_AMOUNT),
+            key_tracking: self.key_tracking.clamp(0.0, 1.0),
+            envelope: self.envelope.clamped(),
+        }
+    }
+}
+impl Default for FilterSettings {
+    fn default() -> Self {
+        Self {
+            mode: FilterMode::default(),
+            cutoff: 2000.0,
+            resonance: 0.2,
+            envelope_amount: 0.0,
+            key_tracking: 0.0,
+            envelope: EnvelopeSettings::default(),
+        }
+    }
+}
+
+/// A resonant state-variable filter for one voice.
+///
+/// This is the trapezoidal-integration form described by Andrew Simper of
+/// Cytomic. Unlike the classic Chamberlin SVF, it stays stable at any cutoff
+/// below Nyquist, and it tolerates its cutoff changing every sample, which is
+/// what envelope modulation does to it.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    // The two integrators' states.
+    ic1eq: f32,
+    ic2eq: f32,
+}
+impl Filter {
+    /// Forgets any signal that's still ringing in the filter.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Filters one sample with the given `mode`, `cutoff` in Hz, and
+    /// `resonance` from 0.0 to just under 1.0.
+    pub fn process(
+        &mut self,
+        input: f32,
+        mode: FilterMode,
+        cutoff: f32,
+        resonance: f32,
+        sample_rate: usize,
+    ) -> f32 {
+        // Before the first stream, there's no rate to filter at.
+        if mode == FilterMode::Off || sample_rate == 0 {
+            return input;
+        }
+
+        // Just under Nyquist, where the tangent blows up. That comes first,
+        // since at very low rates it's below our usual minimum.
+        let cutoff = cutoff
+            .max(FilterSettings::MIN_CUTOFF)
+            .min(sample_rate as f32 * 0.49);
+        let g = (std::f32::consts::PI * cutoff / sample_rate as f32).tan();
+        let k = 2.0 - 2.0 * resonance.clamp(0.0, FilterSettings::MAX_RESONANCE);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        match mode {
+            FilterMode::Off => input,
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => input - k * v1 - v2,
+            FilterMode::BandPass => v1,
+            FilterMode::Notch => input - k * v1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_input_through_without_sample_rate() {
+        for mode in FilterMode::ALL {
+            let mut filter = Filter::default();
+            assert_eq!(filter.process(0.5, mode, 1000.0, 0.5, 0), 0.5);
+        }
+    }
+
+    #[test]
+    fn stays_finite_below_minimum_cutoff_rate() {
+        // Nyquist is below FilterSettings::MIN_CUTOFF here.
+        let sample_rate = 30;
+        for mode in FilterMode::ALL {
+            let mut filter = Filter::default();
+            for i in 0..100 {
+                let input = if i % 2 == 0 { 1.0 } else { -1.0 };
+                let output = filter.process(input, mode, 1000.0, 0.5, sample_rate);
+                assert!(output.is_finite(), "{} gave {}", mode, output);
+            }
+        }
+    }
+}
@@ -4,79 +4,352 @@
 //! [cpal](https://github.com/RustAudio/cpal), and concurrency/parallelism.
 //!
 //! The project opens an Iced application and a default cpal audio stream, then
-//! plays an audio tone at the indicated frequency.
+//! plays whichever notes the user picks on a polyphonic synthesizer.
 //!
 //! [stream::AudioStream] consumes a [crossbeam::queue::ArrayQueue] of
 //! [stream::StereoSample]s representing left and right channels.
 //! [stream::AudioStream] is available to the app through
-//! [AudioInterfaceSubscription] as an Iced subscription. The subscription lets
-//! the app know when it needs more data to feed to the audio interface, which
-//! the app asks [Synthesizer] to provide through the crossbeam ArrayQueue.
+//! [AudioInterfaceSubscription] as an Iced subscription. When the audio
+//! interface needs more data, [render::RenderThread] asks [Synthesizer] to
+//! provide it through the crossbeam ArrayQueue, without involving the app.
 //!
 //! The subscription accepts various input. It can play and pause the stream,
 //! which controls whether the audio interface consumes samples from the queue.
 //! The app can play and pause [Synthesizer] as well, controlling whether it
-//! produces samples for the queue, and it can change the frequency of the
-//! synthesized tone.
+//! produces samples for the queue, and it can start and stop notes. It does
+//! so by sending [render::SynthesizerInput] to the
+//! render thread, which owns the synthesizer.
+//!
+//! Run with `--null` to play to [null_backend] instead of a real device, which
+//! is handy on machines without a sound card. `--sample-rate=<Hz>` and
+//! `--device-buffer=<frames>` set the initial [devices::StreamConfigRequest]
+//! for either kind of device.
+//!
+//! `--render=<path>` skips the UI and the audio interface altogether, and
+//! instead writes `--render-seconds=<seconds>` (default 5) of [Synthesizer]
+//! output to a WAV file at `--sample-rate`, in the format given by
+//! `--render-format=int16|int24|float32`, playing the MIDI notes listed in
+//! `--render-notes=<note>,<note>,...` (default 69, which is A440), with the
+//! [patch::Patch] saved at `--patch=<path>` if there is one. See
+//! [wav::render_to_wav].
+//!
+//! With the window focused, the computer's keyboard plays notes too. See
+//! [computer_keyboard].
+//!
+//! `--midi=virtual` starts the app listening to a virtual MIDI port of its
+//! own, which other programs can connect to, and `--midi=<port name>` to an
+//! existing port. The MIDI card can change ports later. See [midi].
 //!
 //! Now that the interface is nicely encapsulated as a subscription, I'm going
 //! to try turning [Synthesizer] into something that demands more computing
 //! resources to force the issue of async and/or threading.
 
 use crate::subscription::AudioInterfaceSubscription;
+use channels::ExtraChannels;
+use computer_keyboard::ComputerKeyboard;
 use crossbeam_channel::Sender;
+use devices::{
+    describe_config_range, OutputDeviceId, OutputDeviceInfo, StreamConfigRequest, StreamDescription,
+};
+use envelope::{EnvelopeCurve, EnvelopeSettings};
+use filter::{FilterMode, FilterSettings};
 use iced::{
-    widget::{Button, Column, Container, Row, Text},
-    window, Application, Command, Event, Settings, Subscription, Theme,
+    keyboard, theme,
+    widget::{
+        Button, Canvas, Column, Container, PickList, ProgressBar, Row, Scrollable, Slider, Text,
+    },
+    window, Application, Command, Event, Length, Settings, Subscription, Theme,
 };
 use iced_aw::Card;
-use std::{fmt::Debug, time::Instant};
-use stream::AudioQueue;
+use meters::{ChannelMeter, LevelMeters};
+use metrics::{Metric, Metrics};
+use midi::{MidiError, MidiEvent, MidiInterfaceInput, MidiMessage, MidiPort, MidiSubscription};
+use modulation::{LfoSettings, LfoShape, LfoSync, ModDestination, ModRoute, ModSource};
+use oscillator::Waveform;
+use oscilloscope::{Oscilloscope, ScopeMode, ScopeTap};
+use patch::Patch;
+use queue_history::{HistorySpan, QueueHistory};
+use render::{SynthesizerInput, SynthesizerStatus};
+use spectrum::{SpectrumAnalyzer, SpectrumWindow};
+use std::{
+    fmt::{Debug, Display},
+    path::PathBuf,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use stream::{AudioQueue, AudioStream, AudioStreamError};
 use subscription::{AudioInterfaceEvent, AudioInterfaceInput};
-use synthesizer::Synthesizer;
+use synthesizer::{Synthesizer, VoiceRendering};
+use voice::{note_name, VoiceStealing};
+use wav::{render_to_wav, WavFormat};
 
+mod channels;
+mod computer_keyboard;
+mod devices;
+mod envelope;
+mod filter;
+mod meters;
+mod metrics;
+mod midi;
+mod modulation;
+mod null_backend;
+mod oscillator;
+mod oscilloscope;
+mod patch;
+mod queue_history;
+mod render;
+mod spectrum;
 mod stream;
 mod subscription;
 mod synthesizer;
+mod voice;
+mod voice_pool;
+mod wav;
 
 #[derive(Clone, Debug)]
 enum Message {
     AudioInterface(AudioInterfaceEvent),
     Event(iced::Event),
-    SourceChangeFrequency,
+    MetricsReset,
+    MetricsSaveCsv,
+    MetricsSelect(Metric),
+    Midi(MidiEvent),
+    MidiDisconnect,
+    MidiRefreshPorts,
+    MidiSelectPort(MidiPort),
+    ScopeFrame,
+    ScopeSelectMode(ScopeMode),
+    ScopeToggle,
+    SpectrumResetPeaks,
+    SpectrumSelectWindow(SpectrumWindow),
+    SpectrumToggle,
+    SpectrumTogglePeakHold,
+    SourceAllNotesOff,
     SourceDecreaseDelay,
+    SourceDecreasePulseWidth,
+    SourceDecreaseVoiceCount,
     SourceIncreaseDelay,
+    SourceIncreasePulseWidth,
+    SourceIncreaseVoiceCount,
+    SourceLoadPatch,
     SourcePause,
     SourcePlay,
+    SourceSavePatch,
+    SourceSelectVoiceRendering(VoiceRendering),
+    SourceSelectVoiceStealing(VoiceStealing),
+    SourceSelectWaveform(Waveform),
+    SourceSetEnvelope(EnvelopeSettings),
+    SourceSetFilter(FilterSettings),
+    SourceSetFilterEnvelope(EnvelopeSettings),
+    SourceSetLfo(usize, LfoSettings),
+    SourceSetModRoute(usize, ModRoute),
+    SourceSetModWheel(f32),
+    SourceSetTempo(f32),
+    SourceToggleNote(u8),
+    StreamDecreaseBufferSize,
+    StreamIncreaseBufferSize,
     StreamPause,
     StreamPlay,
+    StreamResetClips,
+    StreamResetUnderruns,
+    StreamSelectRecordingFormat(WavFormat),
+    StreamSelectDeviceBufferSize(Preference),
+    StreamSelectExtraChannels(ExtraChannels),
+    StreamSelectHistorySpan(HistorySpan),
+    StreamSelectOutputDevice(OutputDeviceInfo),
+    StreamSelectSampleRate(Preference),
+    StreamToggleRecording,
 }
 
-#[derive(Debug)]
-struct AudioPrototype {
-    synthesizer: Option<Synthesizer>,
-    queue: Option<AudioQueue>,
-    audio_interface_sender: Option<Sender<AudioInterfaceInput>>,
-    sample_rate: Option<usize>,
+/// A [PickList] entry for one of the numeric fields of [StreamConfigRequest],
+/// where None means that we'll take whatever the device prefers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Preference(Option<u32>);
+impl Preference {
+    const SAMPLE_RATES: [Preference; 5] = [
+        Preference(None),
+        Preference(Some(44100)),
+        Preference(Some(48000)),
+        Preference(Some(88200)),
+        Preference(Some(96000)),
+    ];
+    const DEVICE_BUFFER_SIZES: [Preference; 7] = [
+        Preference(None),
+        Preference(Some(32)),
+        Preference(Some(64)),
+        Preference(Some(128)),
+        Preference(Some(256)),
+        Preference(Some(512)),
+        Preference(Some(1024)),
+    ];
 }
-impl Default for AudioPrototype {
-    fn default() -> Self {
-        Self {
-            synthesizer: Some(Synthesizer::new_with(0)),
-            queue: None,
-            audio_interface_sender: None,
-            sample_rate: None,
+impl Display for Preference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(value) => write!(f, "{}", value),
+            None => write!(f, "Default"),
         }
     }
 }
+
+/// Command-line options that affect how the app starts up.
+#[derive(Debug, Default)]
+struct StartupOptions {
+    // The device to start on, if not the default.
+    output_device: Option<OutputDeviceId>,
+
+    stream_config_request: StreamConfigRequest,
+
+    // Where to render to instead of starting the app, if anywhere.
+    render_path: Option<PathBuf>,
+    render_seconds: f32,
+    render_format: WavFormat,
+    render_notes: Vec<u8>,
+
+    // The patch to render with, if not the default.
+    render_patch: Option<PathBuf>,
+
+    // The MIDI port to listen to, if any.
+    midi_port: Option<MidiPort>,
+}
+impl StartupOptions {
+    fn new_from_args(args: impl Iterator<Item = String>) -> Self {
+        let mut r = Self {
+            render_seconds: 5.0,
+            render_notes: vec![69],
+            ..Default::default()
+        };
+        for arg in args {
+            if arg == "--null" {
+                r.output_device = Some(OutputDeviceId::null());
+            } else if let Some(Ok(sample_rate)) = arg.strip_prefix("--sample-rate=").map(str::parse)
+            {
+                r.stream_config_request.sample_rate = Some(sample_rate);
+            } else if let Some(Ok(frames)) = arg.strip_prefix("--device-buffer=").map(str::parse) {
+                r.stream_config_request.buffer_size = cpal::BufferSize::Fixed(frames);
+            } else if let Some(path) = arg.strip_prefix("--render=") {
+                r.render_path = Some(PathBuf::from(path));
+            } else if let Some(Ok(seconds)) = arg.strip_prefix("--render-seconds=").map(str::parse)
+            {
+                r.render_seconds = seconds;
+            } else if let Some(format) = arg
+                .strip_prefix("--render-format=")
+                .and_then(WavFormat::from_arg)
+            {
+                r.render_format = format;
+            } else if let Some(Ok(notes)) = arg.strip_prefix("--render-notes=").map(|notes| {
+                notes
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<u8>, _>>()
+            }) {
+                r.render_notes = notes;
+            } else if let Some(path) = arg.strip_prefix("--patch=") {
+                r.render_patch = Some(PathBuf::from(path));
+            } else if let Some(port) = arg.strip_prefix("--midi=") {
+                r.midi_port = Some(MidiPort::from_arg(port));
+            } else {
+                eprintln!("Ignoring unrecognized argument {}", arg);
+            }
+        }
+        r
+    }
+}
+
+#[derive(Debug, Default)]
+struct AudioPrototype {
+    // The synthesizer itself lives on the render thread. This is what it last
+    // told us about itself.
+    synthesizer: SynthesizerStatus,
+
+    queue: Option<AudioQueue>,
+    audio_interface_sender: Option<Sender<AudioInterfaceInput>>,
+    output_devices: Vec<OutputDeviceInfo>,
+    output_device: Option<OutputDeviceInfo>,
+
+    // What the audio stream should start on. Only matters until the
+    // subscription starts.
+    initial_output_device: Option<OutputDeviceId>,
+
+    stream_config_request: StreamConfigRequest,
+    stream_description: Option<StreamDescription>,
+    extra_channels: ExtraChannels,
+
+    // The most recent thing that went wrong with the audio stream. Cleared
+    // when a new stream comes up.
+    stream_error: Option<AudioStreamError>,
+
+    // Running totals of AudioInterfaceEvent::Underrun since startup or the
+    // last time the user reset them.
+    underrun_count: usize,
+    underrun_frames: usize,
+
+    // When the most recent underrun happened, and what the synthesizer's
+    // fake delay was at the time.
+    last_underrun: Option<(Instant, u64)>,
+
+    // The last several seconds of queue fill levels, requests, and underruns.
+    queue_history: QueueHistory,
+
+    // Latency and jitter measurements, and what happened the last time the
+    // user saved them.
+    metrics: Metrics,
+    metrics_message: Option<String>,
+
+    // How loud the stream is playing, according to its level reports.
+    level_meters: LevelMeters,
+
+    // Where the audio stream is recording to, if it is.
+    recording_path: Option<PathBuf>,
+    recording_format: WavFormat,
+
+    // What happened the last time the user saved or loaded the patch.
+    patch_message: Option<String>,
+
+    midi_sender: Option<Sender<MidiInterfaceInput>>,
+
+    // What the MIDI subscription should start on.
+    initial_midi_port: Option<MidiPort>,
+
+    // Our virtual port, followed by every port that existed last time we
+    // looked.
+    midi_ports: Vec<MidiPort>,
+
+    // The port we're listening to, if any.
+    midi_port: Option<MidiPort>,
+
+    // The most recent thing that went wrong with MIDI. Cleared when we
+    // connect to a port.
+    midi_error: Option<MidiError>,
+
+    // The most recent message, and its MIDI timestamp in microseconds.
+    last_midi_message: Option<(u64, MidiMessage)>,
+
+    computer_keyboard: ComputerKeyboard,
+
+    // Every stream copies what it plays here, while the oscilloscope or the
+    // spectrum analyzer is showing.
+    scope_tap: Arc<ScopeTap>,
+
+    oscilloscope: Oscilloscope,
+    spectrum_analyzer: SpectrumAnalyzer,
+}
 impl Application for AudioPrototype {
     type Message = Message;
     type Theme = Theme;
     type Executor = iced::executor::Default;
-    type Flags = ();
+    type Flags = StartupOptions;
 
-    fn new(_flags: Self::Flags) -> (Self, Command<Self::Message>) {
-        (Self::default(), Command::none())
+    fn new(flags: Self::Flags) -> (Self, Command<Self::Message>) {
+        (
+            Self {
+                initial_output_device: flags.output_device,
+                stream_config_request: flags.stream_config_request,
+                initial_midi_port: flags.midi_port,
+                ..Default::default()
+            },
+            Command::none(),
+        )
     }
 
     fn title(&self) -> String {
@@ -87,37 +360,194 @@ impl Application for AudioPrototype {
         match message {
             Message::AudioInterface(event) => return self.audio_interface_update(event),
             Message::Event(event) => return self.handle_system_event(event),
-            Message::SourceChangeFrequency => {
-                if let Some(s) = self.synthesizer.as_mut() {
-                    s.change_frequency()
+            Message::Midi(event) => self.midi_update(event),
+            Message::MidiSelectPort(port) => self.send_to_midi(MidiInterfaceInput::Connect(port)),
+            Message::MidiDisconnect => self.send_to_midi(MidiInterfaceInput::Disconnect),
+            Message::MidiRefreshPorts => self.send_to_midi(MidiInterfaceInput::RefreshPorts),
+            Message::ScopeFrame => {
+                let samples = self.scope_tap.take();
+                if self.oscilloscope.is_enabled() {
+                    self.oscilloscope.update(&samples);
                 }
-            }
-            Message::SourcePlay => {
-                if let Some(s) = self.synthesizer.as_mut() {
-                    s.play()
+                if self.spectrum_analyzer.is_enabled() {
+                    let sample_rate = self
+                        .stream_description
+                        .as_ref()
+                        .map_or(0, |d| d.sample_rate as usize);
+                    self.spectrum_analyzer.update(&samples, sample_rate);
                 }
             }
-            Message::SourcePause => {
-                if let Some(s) = self.synthesizer.as_mut() {
-                    s.pause()
-                }
+            Message::ScopeSelectMode(mode) => self.oscilloscope.set_mode(mode),
+            Message::ScopeToggle => {
+                let is_enabled = !self.oscilloscope.is_enabled();
+                self.oscilloscope.set_enabled(is_enabled);
+                self.update_scope_tap();
+            }
+            Message::SpectrumResetPeaks => self.spectrum_analyzer.reset_peaks(),
+            Message::SpectrumSelectWindow(window) => self.spectrum_analyzer.set_window(window),
+            Message::SpectrumToggle => {
+                let is_enabled = !self.spectrum_analyzer.is_enabled();
+                self.spectrum_analyzer.set_enabled(is_enabled);
+                self.update_scope_tap();
+            }
+            Message::SpectrumTogglePeakHold => {
+                let is_holding_peaks = !self.spectrum_analyzer.is_holding_peaks();
+                self.spectrum_analyzer.set_holding_peaks(is_holding_peaks);
+            }
+            Message::SourceToggleNote(note) => {
+                self.send_to_synthesizer(if self.synthesizer.active_notes.contains(&note) {
+                    SynthesizerInput::NoteOff(note, Instant::now())
+                } else {
+                    SynthesizerInput::NoteOn(note, Self::NOTE_VELOCITY, Instant::now())
+                })
+            }
+            Message::SourceAllNotesOff => {
+                self.send_to_synthesizer(SynthesizerInput::AllNotesOff(Instant::now()))
+            }
+            Message::SourceDecreaseVoiceCount => self.send_to_synthesizer(
+                SynthesizerInput::SetVoiceCount(self.synthesizer.voice_count.saturating_sub(1)),
+            ),
+            Message::SourceIncreaseVoiceCount => self.send_to_synthesizer(
+                SynthesizerInput::SetVoiceCount(self.synthesizer.voice_count + 1),
+            ),
+            Message::SourceSelectVoiceStealing(voice_stealing) => {
+                self.send_to_synthesizer(SynthesizerInput::SetVoiceStealing(voice_stealing))
+            }
+            Message::SourcePlay => self.send_to_synthesizer(SynthesizerInput::Play),
+            Message::SourcePause => self.send_to_synthesizer(SynthesizerInput::Pause),
+            Message::SourceSelectVoiceRendering(voice_rendering) => {
+                self.send_to_synthesizer(SynthesizerInput::SetVoiceRendering(voice_rendering))
+            }
+            Message::SourceSetEnvelope(envelope) => {
+                self.send_to_synthesizer(SynthesizerInput::SetEnvelope(envelope))
+            }
+            Message::SourceSetFilter(filter) => {
+                self.send_to_synthesizer(SynthesizerInput::SetFilter(filter))
+            }
+            Message::SourceSetFilterEnvelope(envelope) => {
+                self.send_to_synthesizer(SynthesizerInput::SetFilter(FilterSettings {
+                    envelope,
+                    ..self.synthesizer.patch.filter
+                }))
+            }
+            Message::SourceSelectWaveform(waveform) => {
+                self.send_to_synthesizer(SynthesizerInput::SetWaveform(waveform))
+            }
+            Message::SourceDecreasePulseWidth => self.send_to_synthesizer(
+                SynthesizerInput::SetPulseWidth(self.synthesizer.patch.pulse_width - 0.05),
+            ),
+            Message::SourceIncreasePulseWidth => self.send_to_synthesizer(
+                SynthesizerInput::SetPulseWidth(self.synthesizer.patch.pulse_width + 0.05),
+            ),
+            Message::SourceSetLfo(index, lfo) => {
+                self.send_to_synthesizer(SynthesizerInput::SetLfo(index, lfo))
+            }
+            Message::SourceSetModRoute(index, route) => {
+                self.send_to_synthesizer(SynthesizerInput::SetModRoute(index, route))
+            }
+            Message::SourceSetTempo(tempo) => {
+                self.send_to_synthesizer(SynthesizerInput::SetTempo(tempo))
+            }
+            Message::SourceSetModWheel(mod_wheel) => {
+                self.send_to_synthesizer(SynthesizerInput::SetModWheel(mod_wheel, Instant::now()))
+            }
+            Message::SourceSavePatch => {
+                let path = PathBuf::from(Self::PATCH_FILE);
+                self.patch_message = Some(match self.synthesizer.patch.save(&path) {
+                    Ok(()) => format!("Saved {}", path.display()),
+                    Err(e) => e.to_string(),
+                });
             }
+            Message::SourceLoadPatch => {
+                let path = PathBuf::from(Self::PATCH_FILE);
+                self.patch_message = Some(match Patch::load(&path) {
+                    Ok(patch) => {
+                        self.send_to_synthesizer(SynthesizerInput::SetPatch(patch));
+                        format!("Loaded {}", path.display())
+                    }
+                    Err(e) => e.to_string(),
+                });
+            }
+            Message::MetricsReset => {
+                self.metrics.reset();
+                self.metrics_message = None;
+            }
+            Message::MetricsSaveCsv => {
+                let path = PathBuf::from(Self::METRICS_FILE);
+                self.metrics_message = Some(match self.metrics.save_csv(&path) {
+                    Ok(()) => format!("Saved {}", path.display()),
+                    Err(e) => e.to_string(),
+                });
+            }
+            Message::MetricsSelect(metric) => self.metrics.select(metric),
             Message::StreamPause => self.audio_interface_pause(),
             Message::StreamPlay => self.audio_interface_play(),
-            Message::SourceDecreaseDelay => {
-                if let Some(s) = self.synthesizer.as_mut() {
-                    s.set_fake_delay(s.fake_delay() >> 1);
+            Message::StreamSelectOutputDevice(device) => {
+                // The selection changes when the Reset for the new device
+                // arrives, so that it never shows a device that isn't
+                // playing.
+                self.send_to_audio_interface(AudioInterfaceInput::SetOutputDevice(device.id));
+            }
+            Message::StreamSelectHistorySpan(span) => self.queue_history.set_span(span),
+            Message::StreamSelectSampleRate(preference) => {
+                self.stream_config_request.sample_rate = preference.0;
+                self.audio_interface_set_stream_config();
+            }
+            Message::StreamSelectDeviceBufferSize(preference) => {
+                self.stream_config_request.buffer_size = match preference.0 {
+                    Some(frames) => cpal::BufferSize::Fixed(frames),
+                    None => cpal::BufferSize::Default,
+                };
+                self.audio_interface_set_stream_config();
+            }
+            Message::StreamSelectExtraChannels(extra_channels) => {
+                self.extra_channels = extra_channels;
+                self.send_to_audio_interface(AudioInterfaceInput::SetExtraChannels(extra_channels));
+            }
+            Message::StreamSelectRecordingFormat(format) => self.recording_format = format,
+            Message::StreamToggleRecording => {
+                if self.recording_path.is_some() {
+                    self.send_to_audio_interface(AudioInterfaceInput::StopRecording);
+                } else {
+                    let secs = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs());
+                    self.send_to_audio_interface(AudioInterfaceInput::StartRecording(
+                        PathBuf::from(format!("recording-{}.wav", secs)),
+                        self.recording_format,
+                    ));
                 }
             }
-            Message::SourceIncreaseDelay => {
-                if let Some(s) = self.synthesizer.as_mut() {
-                    s.set_fake_delay(if s.fake_delay() == 0 {
-                        1
-                    } else {
-                        s.fake_delay() << 1
-                    });
+            Message::StreamResetClips => self.level_meters.reset_clips(),
+            Message::StreamResetUnderruns => {
+                self.underrun_count = 0;
+                self.underrun_frames = 0;
+                self.last_underrun = None;
+            }
+            Message::StreamDecreaseBufferSize => {
+                if let Some(queue) = &self.queue {
+                    self.audio_interface_set_buffer_size((queue.capacity() >> 1).max(1));
+                }
+            }
+            Message::StreamIncreaseBufferSize => {
+                if let Some(queue) = &self.queue {
+                    if queue.capacity() < AudioStream::MAX_BUFFER_SIZE {
+                        self.audio_interface_set_buffer_size(
+                            (queue.capacity() << 1).min(AudioStream::MAX_BUFFER_SIZE),
+                        );
+                    }
                 }
             }
+            Message::SourceDecreaseDelay => self.send_to_synthesizer(
+                SynthesizerInput::SetFakeDelay(self.synthesizer.fake_delay >> 1),
+            ),
+            Message::SourceIncreaseDelay => self.send_to_synthesizer(
+                SynthesizerInput::SetFakeDelay(if self.synthesizer.fake_delay == 0 {
+                    1
+                } else {
+                    self.synthesizer.fake_delay << 1
+                }),
+            ),
         }
         Command::none()
     }
@@ -127,21 +557,53 @@ impl Application for AudioPrototype {
             Text::new("Synthesizer"),
             Column::new()
                 .push(Button::new(Text::new("Play")).on_press(Message::SourcePlay))
+                .push(Self::NOTE_BUTTONS.iter().fold(Row::new(), |row, note| {
+                    row.push(
+                        Button::new(Text::new(note_name(*note)))
+                            .on_press(Message::SourceToggleNote(*note)),
+                    )
+                }))
+                .push(Text::new(format!(
+                    "Keyboard: octave {} (← →), velocity {} (↑ ↓)",
+                    self.computer_keyboard.octave(),
+                    self.computer_keyboard.velocity()
+                )))
                 .push(
                     Row::new()
                         .push(
-                            Button::new(Text::new("Change Frequency"))
-                                .on_press(Message::SourceChangeFrequency),
+                            Button::new(Text::new("All notes off"))
+                                .on_press(Message::SourceAllNotesOff),
                         )
                         .push(Text::new(format!(
-                            "Frequency: {:0.2} Hz",
-                            if let Some(s) = self.synthesizer.as_ref() {
-                                s.frequency()
-                            } else {
-                                0.0
-                            }
+                            "Notes: {}",
+                            self.synthesizer
+                                .active_notes
+                                .iter()
+                                .map(|note| note_name(*note))
+                                .collect::<Vec<_>>()
+                                .join(" ")
                         ))),
                 )
+                .push(
+                    Row::new()
+                        .push(
+                            Button::new(Text::new("Voices +"))
+                                .on_press(Message::SourceIncreaseVoiceCount),
+                        )
+                        .push(
+                            Button::new(Text::new("Voices -"))
+                                .on_press(Message::SourceDecreaseVoiceCount),
+                        )
+                        .push(Text::new(format!(
+                            "Voices: {}",
+                            self.synthesizer.voice_count
+                        )))
+                        .push(PickList::new(
+                            &VoiceStealing::ALL[..],
+                            Some(self.synthesizer.voice_stealing),
+                            Message::SourceSelectVoiceStealing,
+                        )),
+                )
                 .push(Button::new(Text::new("Pause")).on_press(Message::SourcePause))
                 .push(
                     Row::new()
@@ -155,27 +617,330 @@ impl Application for AudioPrototype {
                         )
                         .push(Text::new(format!(
                             "Delay: {} usec",
-                            if let Some(s) = self.synthesizer.as_ref() {
-                                s.fake_delay()
-                            } else {
-                                0
-                            }
+                            self.synthesizer.fake_delay
                         ))),
-                ),
+                )
+                .push(Row::new().push(Text::new("Waveform")).push(PickList::new(
+                    &Waveform::ALL[..],
+                    Some(self.synthesizer.patch.waveform),
+                    Message::SourceSelectWaveform,
+                )))
+                .push(
+                    Row::new()
+                        .push(
+                            Button::new(Text::new("Width +"))
+                                .on_press(Message::SourceIncreasePulseWidth),
+                        )
+                        .push(
+                            Button::new(Text::new("Width -"))
+                                .on_press(Message::SourceDecreasePulseWidth),
+                        )
+                        .push(Text::new(format!(
+                            "Pulse width: {:0.0}%",
+                            self.synthesizer.patch.pulse_width * 100.0
+                        ))),
+                )
+                .push(Self::envelope_view(
+                    self.synthesizer.patch.envelope,
+                    Message::SourceSetEnvelope,
+                ))
+                .push(self.filter_view())
+                .push(self.modulation_view())
+                .push(
+                    Row::new()
+                        .push(
+                            Button::new(Text::new("Save patch")).on_press(Message::SourceSavePatch),
+                        )
+                        .push(
+                            Button::new(Text::new("Load patch")).on_press(Message::SourceLoadPatch),
+                        )
+                        .push(Text::new(self.patch_message.as_deref().unwrap_or(""))),
+                )
+                .push(Row::new().push(Text::new("Rendering")).push(PickList::new(
+                    &VoiceRendering::ALL[..],
+                    Some(self.synthesizer.voice_rendering),
+                    Message::SourceSelectVoiceRendering,
+                )))
+                .push(Text::new(self.synthesizer.frames_per_second.map_or_else(
+                    || "Throughput: not measured yet".to_string(),
+                    |fps| format!("Throughput: {:0.0} frames/sec", fps),
+                ))),
         );
-        let queue_len = if let Some(queue) = &self.queue {
-            queue.len()
+        let (queue_len, queue_capacity) = if let Some(queue) = &self.queue {
+            (queue.len(), queue.capacity())
         } else {
-            0
+            (0, 0)
         };
+        let output_device_configs =
+            self.output_device
+                .as_ref()
+                .map_or_else(Column::new, |device| {
+                    device.configs.iter().fold(Column::new(), |column, config| {
+                        column.push(Text::new(describe_config_range(config)).size(14))
+                    })
+                });
         let audio_stream_card = Card::new(
             Text::new("Audio Stream"),
             Column::new()
+                .push(
+                    PickList::new(
+                        &self.output_devices[..],
+                        self.output_device.clone(),
+                        Message::StreamSelectOutputDevice,
+                    )
+                    .placeholder("Output device"),
+                )
+                .push(output_device_configs)
+                .push(
+                    Row::new()
+                        .push(Text::new("Sample rate"))
+                        .push(PickList::new(
+                            &Preference::SAMPLE_RATES[..],
+                            Some(Preference(self.stream_config_request.sample_rate)),
+                            Message::StreamSelectSampleRate,
+                        )),
+                )
+                .push(
+                    Row::new()
+                        .push(Text::new("Device buffer"))
+                        .push(PickList::new(
+                            &Preference::DEVICE_BUFFER_SIZES[..],
+                            Some(Preference(match self.stream_config_request.buffer_size {
+                                cpal::BufferSize::Fixed(frames) => Some(frames),
+                                cpal::BufferSize::Default => None,
+                            })),
+                            Message::StreamSelectDeviceBufferSize,
+                        )),
+                )
+                .push(
+                    Row::new()
+                        .push(Text::new("Extra channels"))
+                        .push(PickList::new(
+                            &ExtraChannels::ALL[..],
+                            Some(self.extra_channels),
+                            Message::StreamSelectExtraChannels,
+                        )),
+                )
+                .push(Text::new(
+                    self.stream_description
+                        .as_ref()
+                        .map_or_else(|| "No stream".to_string(), |d| d.to_string()),
+                ))
+                .push(Text::new(
+                    self.stream_error
+                        .as_ref()
+                        .map_or_else(String::default, |e| e.to_string()),
+                ))
                 .push(Button::new(Text::new("Play")).on_press(Message::StreamPlay))
                 .push(Button::new(Text::new("Pause")).on_press(Message::StreamPause))
-                .push(Text::new(format!("Queue: {} elements", queue_len))),
+                .push(
+                    Row::new()
+                        .push(
+                            Button::new(Text::new("Buffer +"))
+                                .on_press(Message::StreamIncreaseBufferSize),
+                        )
+                        .push(
+                            Button::new(Text::new("Buffer -"))
+                                .on_press(Message::StreamDecreaseBufferSize),
+                        )
+                        .push(Text::new(format!("Buffer: {} samples", queue_capacity))),
+                )
+                .push(Text::new(format!("Queue: {} elements", queue_len)))
+                .push(
+                    Row::new()
+                        .push(
+                            Button::new(Text::new("Reset")).on_press(Message::StreamResetUnderruns),
+                        )
+                        .push(Text::new(format!(
+                            "Underruns: {} ({} frames zero-filled)",
+                            self.underrun_count, self.underrun_frames
+                        ))),
+                )
+                .push(Text::new(
+                    if let Some((when, fake_delay)) = self.last_underrun {
+                        format!(
+                            "Last underrun: {:0.1} sec ago, delay {} usec",
+                            when.elapsed().as_secs_f32(),
+                            fake_delay
+                        )
+                    } else {
+                        "Last underrun: never".to_string()
+                    },
+                ))
+                .push(Self::meter_row("L", self.level_meters.left()))
+                .push(Self::meter_row("R", self.level_meters.right()))
+                .push(
+                    Row::new()
+                        .push(
+                            Button::new(Text::new(if self.recording_path.is_some() {
+                                "Stop"
+                            } else {
+                                "Record"
+                            }))
+                            .on_press(Message::StreamToggleRecording),
+                        )
+                        .push(PickList::new(
+                            &WavFormat::ALL[..],
+                            Some(self.recording_format),
+                            Message::StreamSelectRecordingFormat,
+                        )),
+                )
+                .push(Text::new(self.recording_path.as_ref().map_or_else(
+                    || "Not recording".to_string(),
+                    |path| format!("Recording to {}", path.display()),
+                ))),
+        );
+        let midi_card = Card::new(
+            Text::new("MIDI"),
+            Column::new()
+                .push(
+                    Row::new()
+                        .push(PickList::new(
+                            &self.midi_ports[..],
+                            self.midi_port.clone(),
+                            Message::MidiSelectPort,
+                        ))
+                        .push(Button::new(Text::new("Refresh")).on_press(Message::MidiRefreshPorts))
+                        .push(
+                            Button::new(Text::new("Disconnect")).on_press(Message::MidiDisconnect),
+                        ),
+                )
+                .push(Text::new(
+                    self.midi_error
+                        .as_ref()
+                        .map_or_else(String::default, |e| e.to_string()),
+                ))
+                .push(Text::new(self.last_midi_message.as_ref().map_or_else(
+                    || "Last message: none".to_string(),
+                    |(timestamp, message)| {
+                        format!(
+                            "Last message: {} at {:0.3} sec",
+                            message,
+                            *timestamp as f64 / 1_000_000.0
+                        )
+                    },
+                )))
+                .push(Text::new(format!(
+                    "Pitch bend: {:+0.2}",
+                    self.synthesizer.pitch_bend
+                ))),
+        );
+        let mut oscilloscope_column = Column::new().push(
+            Row::new()
+                .push(
+                    Button::new(Text::new(if self.oscilloscope.is_enabled() {
+                        "Hide"
+                    } else {
+                        "Show"
+                    }))
+                    .on_press(Message::ScopeToggle),
+                )
+                .push(PickList::new(
+                    &ScopeMode::ALL[..],
+                    Some(self.oscilloscope.mode()),
+                    Message::ScopeSelectMode,
+                )),
+        );
+        if self.oscilloscope.is_enabled() {
+            oscilloscope_column = oscilloscope_column.push(
+                Canvas::new(&self.oscilloscope)
+                    .width(Length::Fixed(Self::SCOPE_WIDTH))
+                    .height(Length::Fixed(Self::SCOPE_HEIGHT)),
+            );
+        }
+        let oscilloscope_card = Card::new(Text::new("Oscilloscope"), oscilloscope_column);
+        let mut spectrum_column = Column::new().push(
+            Row::new()
+                .push(
+                    Button::new(Text::new(if self.spectrum_analyzer.is_enabled() {
+                        "Hide"
+                    } else {
+                        "Show"
+                    }))
+                    .on_press(Message::SpectrumToggle),
+                )
+                .push(PickList::new(
+                    &SpectrumWindow::ALL[..],
+                    Some(self.spectrum_analyzer.window()),
+                    Message::SpectrumSelectWindow,
+                ))
+                .push(
+                    Button::new(Text::new(if self.spectrum_analyzer.is_holding_peaks() {
+                        "Release peaks"
+                    } else {
+                        "Hold peaks"
+                    }))
+                    .on_press(Message::SpectrumTogglePeakHold),
+                )
+                .push(Button::new(Text::new("Reset peaks")).on_press(Message::SpectrumResetPeaks)),
+        );
+        if self.spectrum_analyzer.is_enabled() {
+            spectrum_column = spectrum_column.push(
+                Canvas::new(&self.spectrum_analyzer)
+                    .width(Length::Fixed(Self::SCOPE_WIDTH))
+                    .height(Length::Fixed(Self::SCOPE_HEIGHT)),
+            );
+        }
+        let spectrum_card = Card::new(Text::new("Spectrum"), spectrum_column);
+        let queue_history_card = Card::new(
+            Text::new("Queue History"),
+            Column::new()
+                .push(Row::new().push(Text::new("Last")).push(PickList::new(
+                    &HistorySpan::ALL[..],
+                    Some(self.queue_history.span()),
+                    Message::StreamSelectHistorySpan,
+                )))
+                .push(
+                    Canvas::new(&self.queue_history)
+                        .width(Length::Fixed(Self::SCOPE_WIDTH))
+                        .height(Length::Fixed(Self::SCOPE_HEIGHT)),
+                )
+                .push(
+                    Text::new("Line: queue fill level. Bars: request sizes. Red: underruns.")
+                        .size(14),
+                ),
         );
-        Container::new(Row::new().push(synthesizer_card).push(audio_stream_card)).into()
+        let metrics_card = Card::new(
+            Text::new("Metrics"),
+            Column::new()
+                .push(
+                    Row::new()
+                        .push(Button::new(Text::new("Reset")).on_press(Message::MetricsReset))
+                        .push(Button::new(Text::new("Save CSV")).on_press(Message::MetricsSaveCsv))
+                        .push(Text::new(self.metrics_message.as_deref().unwrap_or(""))),
+                )
+                .push(Metric::ALL.iter().fold(Column::new(), |column, metric| {
+                    column.push(
+                        Text::new(self.metrics.summary(*metric).map_or_else(
+                            || format!("{}: not measured yet", metric),
+                            |summary| format!("{}: {}", metric, summary),
+                        ))
+                        .size(14),
+                    )
+                }))
+                .push(Row::new().push(Text::new("Histogram")).push(PickList::new(
+                    &Metric::ALL[..],
+                    Some(self.metrics.selected()),
+                    Message::MetricsSelect,
+                )))
+                .push(
+                    Canvas::new(&self.metrics)
+                        .width(Length::Fixed(Self::SCOPE_WIDTH))
+                        .height(Length::Fixed(Self::SCOPE_HEIGHT)),
+                ),
+        );
+        Scrollable::new(Container::new(
+            Column::new()
+                .push(
+                    Row::new()
+                        .push(synthesizer_card)
+                        .push(audio_stream_card)
+                        .push(midi_card),
+                )
+                .push(Row::new().push(oscilloscope_card).push(spectrum_card))
+                .push(Row::new().push(queue_history_card).push(metrics_card)),
+        ))
+        .into()
     }
 
     fn theme(&self) -> Self::Theme {
@@ -187,34 +952,314 @@ impl Application for AudioPrototype {
     }
 
     fn subscription(&self) -> iced::Subscription<Self::Message> {
-        Subscription::batch(vec![
+        let mut subscriptions = vec![
             iced_native::subscription::events().map(Message::Event),
-            AudioInterfaceSubscription::subscription().map(Message::AudioInterface),
-        ])
+            AudioInterfaceSubscription::subscription(
+                self.initial_output_device.clone(),
+                self.stream_config_request.clone(),
+                Arc::clone(&self.scope_tap),
+            )
+            .map(Message::AudioInterface),
+            MidiSubscription::subscription(self.initial_midi_port.clone()).map(Message::Midi),
+        ];
+
+        // Only while there's something to animate, because asking for every
+        // frame keeps the app redrawing as fast as the display refreshes.
+        if self.scope_tap.is_enabled() {
+            subscriptions.push(iced_native::window::frames().map(|_| Message::ScopeFrame));
+        }
+        Subscription::batch(subscriptions)
     }
 }
 impl AudioPrototype {
+    /// The notes that the Synthesizer card has buttons for: the white keys
+    /// from middle C up an octave.
+    const NOTE_BUTTONS: [u8; 8] = [60, 62, 64, 65, 67, 69, 71, 72];
+
+    /// How hard the note buttons play their notes.
+    const NOTE_VELOCITY: u8 = 100;
+
+    /// Where the Save and Load patch buttons keep the patch, relative to the
+    /// working directory.
+    const PATCH_FILE: &'static str = "patch.json";
+
+    /// Where the Save CSV button writes the metrics, relative to the working
+    /// directory.
+    const METRICS_FILE: &'static str = "metrics.csv";
+
+    /// The size of the oscilloscope's canvas, and the other graphs'.
+    const SCOPE_WIDTH: f32 = 600.0;
+    const SCOPE_HEIGHT: f32 = 200.0;
+
+    /// The size of each bar of the level meters.
+    const METER_WIDTH: f32 = 150.0;
+    const METER_HEIGHT: f32 = 8.0;
+
+    /// A label showing `value`, followed by a slider that changes it.
+    fn slider_row<'a>(
+        label: String,
+        range: std::ops::RangeInclusive<f32>,
+        value: f32,
+        on_change: impl Fn(f32) -> Message + 'a,
+    ) -> Row<'a, Message> {
+        Row::new()
+            .push(Text::new(label))
+            .push(Slider::new(range, value, on_change).step(0.01))
+    }
+
+    /// Peak and RMS bars for one channel, their readings, and a clip light
+    /// that resets both channels' lights when pressed.
+    fn meter_row<'a>(label: &str, meter: &ChannelMeter) -> Row<'a, Message> {
+        let bar = |db: f32| {
+            ProgressBar::new(LevelMeters::FLOOR_DB..=0.0, db)
+                .width(Length::Fixed(Self::METER_WIDTH))
+                .height(Length::Fixed(Self::METER_HEIGHT))
+        };
+        Row::new()
+            .push(Text::new(label.to_string()))
+            .push(
+                Column::new()
+                    .push(bar(meter.peak_db))
+                    .push(bar(meter.rms_db())),
+            )
+            .push(Text::new(format!(
+                "Peak {:0.1} dB, RMS {:0.1} dB",
+                meter.peak_db,
+                meter.rms_db()
+            )))
+            .push(
+                Button::new(Text::new(format!("Clip {}", meter.clipped_samples)))
+                    .style(if meter.is_clipped {
+                        theme::Button::Destructive
+                    } else {
+                        theme::Button::Secondary
+                    })
+                    .on_press(Message::StreamResetClips),
+            )
+    }
+
+    /// Sliders for each of an envelope's times and levels, and a picker for
+    /// its curve. `on_change` makes the message that applies the change.
+    fn envelope_view<'a>(
+        envelope: EnvelopeSettings,
+        on_change: fn(EnvelopeSettings) -> Message,
+    ) -> Column<'a, Message> {
+        let slider = |label: &str, value: f32, max: f32, set: fn(&mut EnvelopeSettings, f32)| {
+            Self::slider_row(
+                format!("{} {:0.2}", label, value),
+                0.0..=max,
+                value,
+                move |value| {
+                    let mut envelope = envelope;
+                    set(&mut envelope, value);
+                    on_change(envelope)
+                },
+            )
+        };
+        Column::new()
+            .push(slider(
+                "Attack (sec)",
+                envelope.attack,
+                EnvelopeSettings::MAX_ATTACK,
+                |e, v| e.attack = v,
+            ))
+            .push(slider(
+                "Decay (sec)",
+                envelope.decay,
+                EnvelopeSettings::MAX_DECAY,
+                |e, v| e.decay = v,
+            ))
+            .push(slider("Sustain", envelope.sustain, 1.0, |e, v| {
+                e.sustain = v
+            }))
+            .push(slider(
+                "Release (sec)",
+                envelope.release,
+                EnvelopeSettings::MAX_RELEASE,
+                |e, v| e.release = v,
+            ))
+            .push(Row::new().push(Text::new("Curve")).push(PickList::new(
+                &EnvelopeCurve::ALL[..],
+                Some(envelope.curve),
+                move |curve| on_change(EnvelopeSettings { curve, ..envelope }),
+            )))
+    }
+
+    /// The filter's mode, cutoff, and resonance, how its envelope and the note
+    /// move the cutoff, and the envelope itself.
+    fn filter_view(&self) -> Column<'_, Message> {
+        let filter = self.synthesizer.patch.filter;
+        let slider = |label: String,
+                      range: std::ops::RangeInclusive<f32>,
+                      value: f32,
+                      set: fn(&mut FilterSettings, f32)| {
+            Self::slider_row(label, range, value, move |value| {
+                let mut filter = filter;
+                set(&mut filter, value);
+                Message::SourceSetFilter(filter)
+            })
+        };
+
+        // The cutoff slider moves in octaves, which is how we hear pitch.
+        let max_cutoff_octaves = (FilterSettings::MAX_CUTOFF / FilterSettings::MIN_CUTOFF).log2();
+        Column::new()
+            .push(Row::new().push(Text::new("Filter")).push(PickList::new(
+                &FilterMode::ALL[..],
+                Some(filter.mode),
+                move |mode| Message::SourceSetFilter(FilterSettings { mode, ..filter }),
+            )))
+            .push(slider(
+                format!("Cutoff {:0.0} Hz", filter.cutoff),
+                0.0..=max_cutoff_octaves,
+                (filter.cutoff / FilterSettings::MIN_CUTOFF).log2(),
+                |f, v| f.cutoff = FilterSettings::MIN_CUTOFF * v.exp2(),
+            ))
+            .push(slider(
+                format!("Resonance {:0.2}", filter.resonance),
+                0.0..=FilterSettings::MAX_RESONANCE,
+                filter.resonance,
+                |f, v| f.resonance = v,
+            ))
+            .push(slider(
+                format!("Envelope {:+0.2} oct", filter.envelope_amount),
+                -FilterSettings::MAX_ENVELOPE_AMOUNT..=FilterSettings::MAX_ENVELOPE_AMOUNT,
+                filter.envelope_amount,
+                |f, v| f.envelope_amount = v,
+            ))
+            .push(slider(
+                format!("Key tracking {:0.0}%", filter.key_tracking * 100.0),
+                0.0..=1.0,
+                filter.key_tracking,
+                |f, v| f.key_tracking = v,
+            ))
+            .push(Self::envelope_view(
+                filter.envelope,
+                Message::SourceSetFilterEnvelope,
+            ))
+    }
+
+    /// Each LFO's shape, rate, and sync, the tempo they sync to, the mod
+    /// wheel, and the routes of the modulation matrix.
+    fn modulation_view(&self) -> Column<'_, Message> {
+        let patch = &self.synthesizer.patch;
+        let lfos = patch.lfos.iter().enumerate().map(|(index, &lfo)| {
+            Column::new()
+                .push(
+                    Row::new()
+                        .push(Text::new(format!("LFO {}", index + 1)))
+                        .push(PickList::new(
+                            &LfoShape::ALL[..],
+                            Some(lfo.shape),
+                            move |shape| Message::SourceSetLfo(index, LfoSettings { shape, ..lfo }),
+                        ))
+                        .push(PickList::new(
+                            &LfoSync::ALL[..],
+                            Some(lfo.sync),
+                            move |sync| Message::SourceSetLfo(index, LfoSettings { sync, ..lfo }),
+                        )),
+                )
+                .push(Self::slider_row(
+                    format!("Rate {:0.2} Hz", lfo.frequency(self.synthesizer.tempo)),
+                    LfoSettings::MIN_RATE..=LfoSettings::MAX_RATE,
+                    lfo.rate,
+                    move |rate| Message::SourceSetLfo(index, LfoSettings { rate, ..lfo }),
+                ))
+        });
+        let routes = patch
+            .mod_matrix
+            .routes
+            .iter()
+            .enumerate()
+            .map(|(index, &route)| {
+                Row::new()
+                    .push(PickList::new(
+                        &ModSource::ALL[..],
+                        Some(route.source),
+                        move |source| {
+                            Message::SourceSetModRoute(index, ModRoute { source, ..route })
+                        },
+                    ))
+                    .push(PickList::new(
+                        &ModDestination::ALL[..],
+                        Some(route.destination),
+                        move |destination| {
+                            Message::SourceSetModRoute(
+                                index,
+                                ModRoute {
+                                    destination,
+                                    ..route
+                                },
+                            )
+                        },
+                    ))
+                    .push(Self::slider_row(
+                        format!("Depth {:+0.2}", route.depth),
+                        -1.0..=1.0,
+                        route.depth,
+                        move |depth| Message::SourceSetModRoute(index, ModRoute { depth, ..route }),
+                    ))
+            });
+        lfos.fold(Column::new(), |column, lfo| column.push(lfo))
+            .push(Self::slider_row(
+                format!("Tempo {:0.0} BPM", self.synthesizer.tempo),
+                Synthesizer::MIN_TEMPO..=Synthesizer::MAX_TEMPO,
+                self.synthesizer.tempo,
+                Message::SourceSetTempo,
+            ))
+            .push(Self::slider_row(
+                format!("Mod wheel {:0.2}", self.synthesizer.mod_wheel),
+                0.0..=1.0,
+                self.synthesizer.mod_wheel,
+                Message::SourceSetModWheel,
+            ))
+            .push(Text::new("Modulation"))
+            .push(routes.fold(Column::new(), |column, route| column.push(route)))
+    }
+
     fn audio_interface_update(&mut self, event: AudioInterfaceEvent) -> Command<Message> {
         match event {
             AudioInterfaceEvent::Ready(sender) => self.audio_interface_sender = Some(sender),
-            AudioInterfaceEvent::Reset(sample_rate, queue) => {
-                self.sample_rate = Some(sample_rate);
+            AudioInterfaceEvent::Reset(description, queue) => {
+                self.output_device = self
+                    .output_devices
+                    .iter()
+                    .find(|d| d.id == description.device)
+                    .cloned()
+                    .or(self.output_device.take());
+                self.stream_description = Some(description);
+                self.stream_error = None;
                 self.queue = Some(queue);
-                self.synthesizer = Some(Synthesizer::new_with(sample_rate));
             }
-            AudioInterfaceEvent::NeedsAudio(when, count) => {
-                if let Some(queue) = &self.queue {
-                    if let Some(synthesizer) = self.synthesizer.as_mut() {
-                        let now = Instant::now();
-                        let _time_to_receive_event = now - when;
-                        // eprintln!(
-                        //     "Time to receive AudioInterfaceEvent::NeedsAudio: {:?}",
-                        //     _time_to_receive_event
-                        // );
-                        synthesizer.generate_audio(count, queue.clone());
-                    }
+            AudioInterfaceEvent::Devices(devices) => {
+                if self.output_device.is_none() {
+                    self.output_device = devices.iter().find(|d| d.is_default).cloned();
                 }
+                self.output_devices = devices;
+            }
+            AudioInterfaceEvent::NeedsAudio(..) => {
+                // The render thread takes care of these.
             }
+            AudioInterfaceEvent::Underrun(report) => {
+                self.underrun_count += 1;
+                self.underrun_frames += report.frames_zero_filled;
+                self.last_underrun = Some((report.when, self.synthesizer.fake_delay));
+                self.queue_history.add_underrun(report.when);
+            }
+            AudioInterfaceEvent::QueueActivity(activity) => {
+                self.metrics.add_queue_activity(&activity);
+                self.queue_history.add_activity(activity);
+            }
+            AudioInterfaceEvent::CallbackTimings(timings) => {
+                self.metrics.add_callback_timings(&timings)
+            }
+            AudioInterfaceEvent::Levels(report) => {
+                if let Some(description) = &self.stream_description {
+                    self.level_meters.update(&report, description.sample_rate);
+                }
+            }
+            AudioInterfaceEvent::Error(e) => self.stream_error = Some(e),
+            AudioInterfaceEvent::Recording(path) => self.recording_path = path,
+            AudioInterfaceEvent::Synthesizer(status) => self.synthesizer = status,
             AudioInterfaceEvent::Quit => {
                 // Acknowledged. If we needed to be picky about the shutdown
                 // sequence, for example closing one resource only after the
@@ -225,6 +1270,29 @@ impl AudioPrototype {
         Command::none()
     }
 
+    fn midi_update(&mut self, event: MidiEvent) {
+        match event {
+            MidiEvent::Ready(sender) => self.midi_sender = Some(sender),
+            MidiEvent::Ports(ports) => {
+                self.midi_ports = std::iter::once(MidiPort::Virtual).chain(ports).collect();
+            }
+            MidiEvent::Connected(port) => {
+                if port.is_some() {
+                    self.midi_error = None;
+                }
+                self.midi_port = port;
+            }
+            MidiEvent::Message(timestamp, when, message) => {
+                if let Some(input) = message.synthesizer_input(when) {
+                    self.send_to_synthesizer(input);
+                }
+                self.last_midi_message = Some((timestamp, message));
+            }
+            MidiEvent::Error(e) => self.midi_error = Some(e),
+            MidiEvent::Quit => {}
+        }
+    }
+
     fn audio_interface_play(&self) {
         self.send_to_audio_interface(AudioInterfaceInput::Play);
     }
@@ -233,23 +1301,103 @@ impl AudioPrototype {
         self.send_to_audio_interface(AudioInterfaceInput::Pause);
     }
 
+    fn audio_interface_set_buffer_size(&self, buffer_size: usize) {
+        self.send_to_audio_interface(AudioInterfaceInput::SetBufferSize(buffer_size));
+    }
+
+    fn audio_interface_set_stream_config(&self) {
+        self.send_to_audio_interface(AudioInterfaceInput::SetStreamConfig(
+            self.stream_config_request.clone(),
+        ));
+    }
+
+    fn send_to_synthesizer(&self, input: SynthesizerInput) {
+        self.send_to_audio_interface(AudioInterfaceInput::Synthesizer(input));
+    }
+
     fn send_to_audio_interface(&self, input: AudioInterfaceInput) {
         if let Some(sender) = &self.audio_interface_sender {
             let _ = sender.send(input);
         }
     }
 
+    /// Copies samples from the stream only while something is showing them.
+    fn update_scope_tap(&self) {
+        self.scope_tap
+            .set_enabled(self.oscilloscope.is_enabled() || self.spectrum_analyzer.is_enabled());
+    }
+
+    fn send_to_midi(&self, input: MidiInterfaceInput) {
+        if let Some(sender) = &self.midi_sender {
+            let _ = sender.send(input);
+        }
+    }
+
     fn handle_system_event(&mut self, event: Event) -> Command<Message> {
-        if let Event::Window(window::Event::CloseRequested) = event {
-            self.send_to_audio_interface(AudioInterfaceInput::Quit);
-            return window::close::<Message>();
+        match event {
+            Event::Window(window::Event::CloseRequested) => {
+                self.send_to_audio_interface(AudioInterfaceInput::Quit);
+                self.send_to_midi(MidiInterfaceInput::Quit);
+                return window::close::<Message>();
+            }
+            Event::Window(window::Event::Unfocused) => {
+                for input in self.computer_keyboard.release_all() {
+                    self.send_to_synthesizer(input);
+                }
+            }
+            Event::Keyboard(keyboard::Event::KeyPressed {
+                key_code,
+                modifiers,
+            }) => {
+                if let Some(input) = self.computer_keyboard.key_pressed(key_code, modifiers) {
+                    self.send_to_synthesizer(input);
+                }
+            }
+            Event::Keyboard(keyboard::Event::KeyReleased { key_code, .. }) => {
+                if let Some(input) = self.computer_keyboard.key_released(key_code) {
+                    self.send_to_synthesizer(input);
+                }
+            }
+            _ => {}
         }
         Command::none()
     }
 }
 
 pub fn main() -> iced::Result {
+    let options = StartupOptions::new_from_args(std::env::args().skip(1));
+    if let Some(path) = &options.render_path {
+        let sample_rate = options
+            .stream_config_request
+            .sample_rate
+            .unwrap_or(null_backend::NullStream::DEFAULT_SAMPLE_RATE);
+        let mut synthesizer = Synthesizer::new_with(sample_rate as usize);
+        if let Some(patch_path) = &options.render_patch {
+            match Patch::load(patch_path) {
+                Ok(patch) => synthesizer.set_patch(&patch),
+                Err(e) => {
+                    eprintln!("Couldn't load {}: {}", patch_path.display(), e);
+                    return Ok(());
+                }
+            }
+        }
+        for note in &options.render_notes {
+            synthesizer.note_on(*note, 127);
+        }
+        match render_to_wav(
+            &mut synthesizer,
+            options.render_seconds,
+            path,
+            options.render_format,
+        ) {
+            Ok(()) => eprintln!("Rendered {}", path.display()),
+            Err(e) => eprintln!("Couldn't render {}: {}", path.display(), e),
+        }
+        return Ok(());
+    }
+
     AudioPrototype::run(Settings {
+        flags: options,
         exit_on_close_request: false,
         window: window::Settings {
             size: (800, 600),
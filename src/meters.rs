@@ -0,0 +1,183 @@
+//! Peak and RMS level meters, with clip lights, for what the audio stream
+//! plays.
+
+use crate::stream::LevelReport;
+
+/// The meter for one channel. Levels are in dB relative to full scale.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelMeter {
+    // Jumps up to each new peak, then falls back slowly, so that short peaks
+    // stay visible long enough to see.
+    pub peak_db: f32,
+
+    // The mean of the squared samples, averaged over the last few hundred
+    // milliseconds or so.
+    mean_square: f32,
+
+    // Sticky: stays lit after a clip until the user resets it.
+    pub is_clipped: bool,
+
+    // How many samples have clipped since the user last reset the light.
+    pub clipped_samples: usize,
+}
+impl Default for ChannelMeter {
+    fn default() -> Self {
+        Self {
+            peak_db: LevelMeters::FLOOR_DB,
+            mean_square: 0.0,
+            is_clipped: false,
+            clipped_samples: 0,
+        }
+    }
+}
+impl ChannelMeter {
+    pub fn rms_db(&self) -> f32 {
+        (10.0 * self.mean_square.log10()).max(LevelMeters::FLOOR_DB)
+    }
+}
+
+/// Turns the stream's [LevelReport]s into meter readings for the left and
+/// right channels.
+#[derive(Debug, Default)]
+pub struct LevelMeters {
+    channels: [ChannelMeter; 2],
+}
+impl LevelMeters {
+    /// The quietest level the meters show.
+    pub const FLOOR_DB: f32 = -60.0;
+
+    /// How fast the peak meter falls back after a peak.
+    const PEAK_FALL_DB_PER_SECOND: f32 = 20.0;
+
+    /// How long the RMS meter takes to get most of the way to a new level.
+    /// This is about what a VU meter does.
+    const RMS_TIME_CONSTANT_SECONDS: f32 = 0.3;
+
+    pub fn left(&self) -> &ChannelMeter {
+        &self.channels[0]
+    }
+
+    pub fn right(&self) -> &ChannelMeter {
+        &self.channels[1]
+    }
+
+    /// Moves the meters according to `report`, which covers some number of
+    /// frames at `sample_rate`.
+    pub fn update(&mut self, report: &LevelReport, sample_rate: u32) {
+        if report.frames == 0 || sample_rate == 0 {
+            return;
+        }
+        let seconds = report.frames as f32 / sample_rate as f32;
+        let smoothing = (-seconds / Self::RMS_TIME_CONSTANT_SECONDS).exp();
+        for (channel, meter) in self.channels.iter_mut().enumerate() {
+            let peak_db = (20.0 * report.peak[channel].log10()).max(Self::FLOOR_DB);
+            meter.peak_db = peak_db.max(meter.peak_db - Self::PEAK_FALL_DB_PER_SECOND * seconds);
+
+            let mean_square = report.sum_of_squares[channel] / report.frames as f32;
+            meter.mean_square = smoothing * meter.mean_square + (1.0 - smoothing) * mean_square;
+
+            if report.clipped_samples[channel] > 0 {
+                meter.is_clipped = true;
+                meter.clipped_samples += report.clipped_samples[channel];
+            }
+        }
+    }
+
+    /// Turns off both clip lights, and starts counting clipped samples again
+    /// from zero.
+    pub fn reset_clips(&mut self) {
+        for meter in self.channels.iter_mut() {
+            meter.is_clipped = false;
+            meter.clipped_samples = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// A report of `frames` frames of a constant `level` on both channels.
+    fn report(frames: usize, level: f32) -> LevelReport {
+        let magnitude = level.abs();
+        LevelReport {
+            frames,
+            peak: [magnitude; 2],
+            sum_of_squares: [level * level * frames as f32; 2],
+            clipped_samples: [if magnitude > 1.0 { frames } else { 0 }; 2],
+        }
+    }
+
+    #[test]
+    fn peak_jumps_up_and_falls_back() {
+        let mut meters = LevelMeters::default();
+        assert_eq!(meters.left().peak_db, LevelMeters::FLOOR_DB);
+
+        meters.update(&report(480, 1.0), SAMPLE_RATE);
+        assert_eq!(meters.left().peak_db, 0.0);
+        assert_eq!(meters.right().peak_db, 0.0);
+
+        // Half a second of silence takes off half a second's worth.
+        meters.update(&report(24000, 0.0), SAMPLE_RATE);
+        let expected = -LevelMeters::PEAK_FALL_DB_PER_SECOND * 0.5;
+        assert!((meters.left().peak_db - expected).abs() < 1e-3);
+
+        // A louder peak than where the meter has fallen to takes over.
+        meters.update(&report(480, 0.5), SAMPLE_RATE);
+        assert!((meters.left().peak_db - 20.0 * 0.5f32.log10()).abs() < 1e-3);
+
+        // And the meter never falls below the floor.
+        meters.update(&report(SAMPLE_RATE as usize * 10, 0.0), SAMPLE_RATE);
+        assert_eq!(meters.left().peak_db, LevelMeters::FLOOR_DB);
+    }
+
+    #[test]
+    fn rms_settles_at_level() {
+        let mut meters = LevelMeters::default();
+        assert_eq!(meters.left().rms_db(), LevelMeters::FLOOR_DB);
+
+        // One time constant gets most of the way there.
+        let frames = (LevelMeters::RMS_TIME_CONSTANT_SECONDS * SAMPLE_RATE as f32) as usize;
+        meters.update(&report(frames, 0.5), SAMPLE_RATE);
+        let expected = 0.25 * (1.0 - (-1.0f32).exp());
+        assert!((meters.left().mean_square - expected).abs() < 1e-3);
+
+        // Many time constants get all the way, however they're split up.
+        for _ in 0..100 {
+            meters.update(&report(frames / 10, 0.5), SAMPLE_RATE);
+        }
+        assert!((meters.left().rms_db() - 20.0 * 0.5f32.log10()).abs() < 0.01);
+
+        // And it decays just as smoothly.
+        meters.update(&report(frames, 0.0), SAMPLE_RATE);
+        assert!((meters.left().mean_square - 0.25 * (-1.0f32).exp()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn clip_light_sticks_until_reset() {
+        let mut meters = LevelMeters::default();
+        meters.update(&report(10, 1.5), SAMPLE_RATE);
+        meters.update(&report(480, 0.1), SAMPLE_RATE);
+        meters.update(&report(5, -2.0), SAMPLE_RATE);
+        assert!(meters.left().is_clipped);
+        assert_eq!(meters.left().clipped_samples, 15);
+
+        meters.reset_clips();
+        assert!(!meters.left().is_clipped);
+        assert_eq!(meters.left().clipped_samples, 0);
+        assert!(!meters.right().is_clipped);
+    }
+
+    #[test]
+    fn empty_report_changes_nothing() {
+        let mut meters = LevelMeters::default();
+        meters.update(&report(480, 1.0), SAMPLE_RATE);
+        let before = *meters.left();
+        meters.update(&report(0, 0.0), SAMPLE_RATE);
+        meters.update(&report(480, 0.0), 0);
+        assert_eq!(meters.left().peak_db, before.peak_db);
+        assert_eq!(meters.left().mean_square, before.mean_square);
+    }
+}
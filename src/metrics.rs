@@ -0,0 +1,366 @@
+//! Timing measurements from every stage between a stream callback asking for
+//! audio and that audio being heard, kept as rolling distributions so that
+//! we can see percentiles and histograms, and save them as CSV for closer
+//! study.
+
+use crate::{render::QueueActivity, stream::CallbackTiming};
+use iced::{
+    widget::canvas::{self, Cursor, Frame, Geometry, Path, Stroke},
+    Color, Point, Rectangle, Size, Theme,
+};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path as FilePath,
+    time::Duration,
+};
+
+/// One of the things that [Metrics] measures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Metric {
+    // From the stream callback sending NeedsAudio to the render thread
+    // receiving it.
+    #[default]
+    EventLatency,
+
+    // How long the render thread took to answer a NeedsAudio, including any
+    // fake delay.
+    SynthesisTime,
+
+    // The time between stream callbacks, by the stream's own clock.
+    CallbackInterval,
+
+    // How far each callback interval was from the time it took to play what
+    // the previous callback wrote.
+    CallbackJitter,
+
+    // How long a sample waits between being pushed onto the queue and being
+    // heard.
+    OutputLatency,
+}
+impl Metric {
+    pub const ALL: [Metric; 5] = [
+        Metric::EventLatency,
+        Metric::SynthesisTime,
+        Metric::CallbackInterval,
+        Metric::CallbackJitter,
+        Metric::OutputLatency,
+    ];
+
+    /// The name of the metric in CSV files.
+    fn csv_name(&self) -> &'static str {
+        match self {
+            Metric::EventLatency => "event_latency",
+            Metric::SynthesisTime => "synthesis_time",
+            Metric::CallbackInterval => "callback_interval",
+            Metric::CallbackJitter => "callback_jitter",
+            Metric::OutputLatency => "output_latency",
+        }
+    }
+}
+impl Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Metric::EventLatency => "Event latency",
+            Metric::SynthesisTime => "Synthesis time",
+            Metric::CallbackInterval => "Callback interval",
+            Metric::CallbackJitter => "Callback jitter",
+            Metric::OutputLatency => "Output latency",
+        })
+    }
+}
+
+/// The percentiles and such of one [Metric], in microseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n {}, mean {:0.0}, p50 {:0.0}, p90 {:0.0}, p99 {:0.0}, max {:0.0} usec",
+            self.count, self.mean, self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+/// The most recent measurements of each [Metric], and which one the
+/// histogram shows.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    selected: Metric,
+
+    // In microseconds, oldest first, indexed like Metric::ALL.
+    samples: [VecDeque<f64>; Metric::ALL.len()],
+
+    // Worked out whenever samples change, rather than every time the view
+    // asks, because it means sorting them all.
+    summaries: [Option<Summary>; Metric::ALL.len()],
+}
+impl Metrics {
+    /// How many measurements of each metric we keep. At a few hundred
+    /// callbacks a second, this is most of a minute.
+    const MAX_SAMPLES: usize = 10000;
+
+    /// How many bars the histogram has.
+    const HISTOGRAM_BINS: usize = 50;
+
+    pub fn selected(&self) -> Metric {
+        self.selected
+    }
+
+    pub fn select(&mut self, metric: Metric) {
+        self.selected = metric;
+    }
+
+    /// Records what the render thread did for each NeedsAudio.
+    pub fn add_queue_activity(&mut self, activity: &[QueueActivity]) {
+        for entry in activity {
+            self.record(
+                Metric::EventLatency,
+                entry
+                    .received_at
+                    .saturating_duration_since(entry.requested_at),
+            );
+            self.record(
+                Metric::SynthesisTime,
+                entry.filled_at.saturating_duration_since(entry.received_at),
+            );
+        }
+        self.summarize(&[Metric::EventLatency, Metric::SynthesisTime]);
+    }
+
+    /// Records the stream callback's timings.
+    pub fn add_callback_timings(&mut self, timings: &[CallbackTiming]) {
+        for timing in timings {
+            if let Some(interval) = timing.interval {
+                self.record(Metric::CallbackInterval, interval);
+            }
+            if let Some(jitter) = timing.jitter {
+                self.record(Metric::CallbackJitter, jitter);
+            }
+            self.record(Metric::OutputLatency, timing.output_latency);
+        }
+        self.summarize(&[
+            Metric::CallbackInterval,
+            Metric::CallbackJitter,
+            Metric::OutputLatency,
+        ]);
+    }
+
+    /// Forgets everything, so that a new experiment starts clean.
+    pub fn reset(&mut self) {
+        for samples in self.samples.iter_mut() {
+            samples.clear();
+        }
+        self.summaries = Default::default();
+    }
+
+    /// Summarizes `metric`, or returns None if we haven't measured it yet.
+    pub fn summary(&self, metric: Metric) -> Option<Summary> {
+        self.summaries[metric as usize]
+    }
+
+    fn summarize(&mut self, metrics: &[Metric]) {
+        for metric in metrics {
+            self.summaries[*metric as usize] = self.work_out_summary(*metric);
+        }
+    }
+
+    fn work_out_summary(&self, metric: Metric) -> Option<Summary> {
+        let mut sorted: Vec<f64> = self.samples[metric as usize].iter().copied().collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+        Some(Summary {
+            count: sorted.len(),
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+
+    /// Writes every measurement we have to `path` as CSV, one row per
+    /// measurement, oldest first within each metric.
+    pub fn save_csv(&self, path: &FilePath) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "metric,microseconds")?;
+        for metric in Metric::ALL {
+            for sample in self.samples[metric as usize].iter() {
+                writeln!(writer, "{},{:0.3}", metric.csv_name(), sample)?;
+            }
+        }
+        writer.flush()
+    }
+
+    fn record(&mut self, metric: Metric, value: Duration) {
+        let samples = &mut self.samples[metric as usize];
+        if samples.len() == Self::MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(value.as_secs_f64() * 1_000_000.0);
+    }
+}
+impl<Message> canvas::Program<Message> for Metrics {
+    type State = ();
+
+    /// A histogram of the selected metric, from zero to its largest value,
+    /// with faint lines at the 50th, 90th, and 99th percentiles.
+    fn draw(
+        &self,
+        _state: &Self::State,
+        theme: &Theme,
+        bounds: Rectangle,
+        _cursor: Cursor,
+    ) -> Vec<Geometry> {
+        let palette = theme.palette();
+        let mut frame = Frame::new(bounds.size());
+        let Some(summary) = self.summary(self.selected) else {
+            return vec![frame.into_geometry()];
+        };
+        let (width, height) = (frame.width(), frame.height());
+        let range = summary.max.max(f64::EPSILON);
+
+        let mut bins = [0usize; Self::HISTOGRAM_BINS];
+        for sample in self.samples[self.selected as usize].iter() {
+            let bin = (sample / range * Self::HISTOGRAM_BINS as f64) as usize;
+            bins[bin.min(Self::HISTOGRAM_BINS - 1)] += 1;
+        }
+        let tallest = bins.iter().copied().max().unwrap_or_default().max(1);
+        let bin_width = width / Self::HISTOGRAM_BINS as f32;
+        for (i, count) in bins.iter().enumerate() {
+            let bar_height = *count as f32 / tallest as f32 * height;
+            frame.fill_rectangle(
+                Point::new(i as f32 * bin_width, height - bar_height),
+                Size::new((bin_width - 1.0).max(1.0), bar_height),
+                palette.primary,
+            );
+        }
+
+        let percentile_color = Color {
+            a: 0.5,
+            ..palette.text
+        };
+        let percentiles = Path::new(|builder| {
+            for value in [summary.p50, summary.p90, summary.p99] {
+                let x = (value / range) as f32 * width;
+                builder.move_to(Point::new(x, 0.0));
+                builder.line_to(Point::new(x, height));
+            }
+        });
+        frame.stroke(&percentiles, Stroke::default().with_color(percentile_color));
+
+        vec![frame.into_geometry()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn micros(us: u64) -> Duration {
+        Duration::from_micros(us)
+    }
+
+    fn timing(interval: Option<u64>, jitter: Option<u64>, output_latency: u64) -> CallbackTiming {
+        CallbackTiming {
+            interval: interval.map(micros),
+            jitter: jitter.map(micros),
+            output_latency: micros(output_latency),
+        }
+    }
+
+    #[test]
+    fn summarizes_each_metric() {
+        let mut metrics = Metrics::default();
+        for metric in Metric::ALL {
+            assert!(metrics.summary(metric).is_none());
+        }
+
+        // Shuffled, to show that the order they arrive in doesn't matter.
+        let timings: Vec<_> = (1..=100)
+            .map(|i| (i * 37) % 100 + 1)
+            .map(|i| timing(Some(i), None, i * 10))
+            .collect();
+        metrics.add_callback_timings(&timings);
+
+        let summary = metrics.summary(Metric::CallbackInterval).unwrap();
+        assert_eq!(summary.count, 100);
+        assert!((summary.mean - 50.5).abs() < 1e-9);
+        assert_eq!(summary.p50, 51.0);
+        assert_eq!(summary.p90, 90.0);
+        assert_eq!(summary.p99, 99.0);
+        assert_eq!(summary.max, 100.0);
+        assert_eq!(metrics.summary(Metric::OutputLatency).unwrap().max, 1000.0);
+
+        // Timings without a jitter don't count as zero jitter.
+        assert!(metrics.summary(Metric::CallbackJitter).is_none());
+
+        let start = Instant::now();
+        metrics.add_queue_activity(&[QueueActivity {
+            requested_at: start,
+            requested: 512,
+            received_at: start + micros(200),
+            filled_at: start + micros(1200),
+            len_after: 512,
+            capacity: 1024,
+        }]);
+        assert_eq!(metrics.summary(Metric::EventLatency).unwrap().mean, 200.0);
+        assert_eq!(metrics.summary(Metric::SynthesisTime).unwrap().mean, 1000.0);
+
+        metrics.reset();
+        for metric in Metric::ALL {
+            assert!(metrics.summary(metric).is_none());
+        }
+    }
+
+    #[test]
+    fn keeps_only_recent_samples() {
+        let mut metrics = Metrics::default();
+        let timings: Vec<_> = (0..Metrics::MAX_SAMPLES as u64 + 10)
+            .map(|i| timing(None, Some(i), 0))
+            .collect();
+        metrics.add_callback_timings(&timings);
+        let summary = metrics.summary(Metric::CallbackJitter).unwrap();
+        assert_eq!(summary.count, Metrics::MAX_SAMPLES);
+        assert_eq!(metrics.samples[Metric::CallbackJitter as usize][0], 10.0);
+        assert_eq!(summary.max, (Metrics::MAX_SAMPLES + 9) as f64);
+    }
+
+    #[test]
+    fn saves_csv() {
+        let mut metrics = Metrics::default();
+        metrics.add_callback_timings(&[
+            timing(Some(5000), Some(250), 10000),
+            timing(Some(5500), Some(500), 12345),
+        ]);
+
+        let path = std::env::temp_dir().join(format!("metrics-test-{}.csv", std::process::id()));
+        metrics.save_csv(&path).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            csv,
+            "metric,microseconds\n\
+             callback_interval,5000.000\n\
+             callback_interval,5500.000\n\
+             callback_jitter,250.000\n\
+             callback_jitter,500.000\n\
+             output_latency,10000.000\n\
+             output_latency,12345.000\n"
+        );
+    }
+}
@@ -0,0 +1,610 @@
+//! MIDI input, either from a port that already exists, like a hardware
+//! keyboard's, or from a virtual port that we create and other programs can
+//! connect to. [MidiSubscription] delivers it to the app as an Iced
+//! subscription, and [MidiMessage::synthesizer_input] says what each message
+//! means to the synthesizer.
+
+use crate::{render::SynthesizerInput, voice::note_name};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use iced::{
+    futures::{channel::mpsc, StreamExt},
+    subscription, Subscription,
+};
+use midir::{MidiInput, MidiInputConnection};
+use std::{
+    fmt::{Debug, Display},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Where MIDI input comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MidiPort {
+    // A port of our own, which other programs (or `aconnect`) can connect to.
+    Virtual,
+
+    // A port that already exists, by name.
+    Named(String),
+}
+impl MidiPort {
+    /// Parses the value of the `--midi=` command-line option.
+    pub fn from_arg(arg: &str) -> Self {
+        if arg == "virtual" {
+            MidiPort::Virtual
+        } else {
+            MidiPort::Named(arg.to_string())
+        }
+    }
+}
+impl Display for MidiPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MidiPort::Virtual => write!(f, "Virtual port"),
+            MidiPort::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// The MIDI channel messages that we understand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+
+    // From -8192 to 8191, where 0 is no bend.
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+}
+impl MidiMessage {
+    const MOD_WHEEL: u8 = 1;
+    const ALL_SOUND_OFF: u8 = 120;
+    const ALL_NOTES_OFF: u8 = 123;
+
+    /// Makes a message from a channel message's status byte and all of its
+    /// data bytes. Returns None for anything we don't understand.
+    fn from_parts(status: u8, data: &[u8]) -> Option<Self> {
+        let channel = status & 0x0f;
+        match (status & 0xf0, data) {
+            (0x80, &[note, _]) => Some(MidiMessage::NoteOff { channel, note }),
+
+            // By convention, a note-on with zero velocity is a note-off.
+            (0x90, &[note, 0]) => Some(MidiMessage::NoteOff { channel, note }),
+            (0x90, &[note, velocity]) => Some(MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            }),
+            (0xb0, &[controller, value]) => Some(MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            }),
+            (0xe0, &[lsb, msb]) => Some(MidiMessage::PitchBend {
+                channel,
+                value: ((msb as i16) << 7 | lsb as i16) - 8192,
+            }),
+            _ => None,
+        }
+    }
+
+    /// What the message, which arrived `when`, asks the synthesizer to do, if
+    /// anything. We listen on every channel.
+    pub fn synthesizer_input(&self, when: Instant) -> Option<SynthesizerInput> {
+        match *self {
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                Some(SynthesizerInput::NoteOn(note, velocity, when))
+            }
+            MidiMessage::NoteOff { note, .. } => Some(SynthesizerInput::NoteOff(note, when)),
+            MidiMessage::ControlChange {
+                controller: Self::MOD_WHEEL,
+                value,
+                ..
+            } => Some(SynthesizerInput::SetModWheel(value as f32 / 127.0, when)),
+            MidiMessage::ControlChange {
+                controller: Self::ALL_SOUND_OFF | Self::ALL_NOTES_OFF,
+                ..
+            } => Some(SynthesizerInput::AllNotesOff(when)),
+            MidiMessage::ControlChange { .. } => None,
+            MidiMessage::PitchBend { value, .. } => {
+                Some(SynthesizerInput::SetPitchBend(value as f32 / 8192.0, when))
+            }
+        }
+    }
+}
+impl Display for MidiMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Channels are numbered from 1 everywhere but on the wire.
+        match self {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => write!(
+                f,
+                "Ch {} note on {} vel {}",
+                channel + 1,
+                note_name(*note),
+                velocity
+            ),
+            MidiMessage::NoteOff { channel, note } => {
+                write!(f, "Ch {} note off {}", channel + 1, note_name(*note))
+            }
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => write!(f, "Ch {} CC {} = {}", channel + 1, controller, value),
+            MidiMessage::PitchBend { channel, value } => {
+                write!(f, "Ch {} pitch bend {:+}", channel + 1, value)
+            }
+        }
+    }
+}
+
+/// Splits the bytes that the backend delivers into [MidiMessage]s. A status
+/// byte can be left out when it's the same as the previous message's, which
+/// is called running status, so we remember it from one delivery to the next.
+#[derive(Debug, Default)]
+struct MidiParser {
+    running_status: Option<u8>,
+}
+impl MidiParser {
+    /// Calls `on_message` with each message in `bytes` that we understand.
+    /// A message that's cut off at the end of `bytes` is dropped.
+    fn parse(&mut self, bytes: &[u8], mut on_message: impl FnMut(MidiMessage)) {
+        let mut data = [0; 2];
+        let mut data_len = 0;
+        for &byte in bytes {
+            match byte {
+                // Data, which belongs to the most recent status.
+                0x00..=0x7f => {
+                    let Some(status) = self.running_status else {
+                        continue;
+                    };
+                    data[data_len] = byte;
+                    data_len += 1;
+                    if data_len == Self::data_len(status) {
+                        if let Some(message) = MidiMessage::from_parts(status, &data[..data_len]) {
+                            on_message(message);
+                        }
+                        data_len = 0;
+                    }
+                }
+
+                // Channel messages.
+                0x80..=0xef => {
+                    self.running_status = Some(byte);
+                    data_len = 0;
+                }
+
+                // System common messages and SysEx, which we ignore along
+                // with their data, and which cancel running status.
+                0xf0..=0xf7 => {
+                    self.running_status = None;
+                    data_len = 0;
+                }
+
+                // Real-time messages can turn up anywhere, even in the middle
+                // of another message, and don't affect it.
+                0xf8..=0xff => {}
+            }
+        }
+    }
+
+    /// How many data bytes follow a channel message's status byte.
+    fn data_len(status: u8) -> usize {
+        match status & 0xf0 {
+            // Program change and channel pressure.
+            0xc0 | 0xd0 => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Turns the backend's timestamps, which count microseconds from a starting
+/// point that it doesn't tell us, into [Instant]s. A message can't arrive
+/// before it happened, so each one tells us that the starting point was no
+/// later than its arrival less its timestamp. We take the earliest of those,
+/// which assumes that the quickest message we've seen arrived right away.
+#[derive(Debug, Default)]
+struct MidiClock {
+    epoch: Option<Instant>,
+}
+impl MidiClock {
+    fn instant(&mut self, timestamp: u64, arrived: Instant) -> Instant {
+        let since_epoch = Duration::from_micros(timestamp);
+        let latest_epoch = arrived.checked_sub(since_epoch).unwrap_or(arrived);
+        let epoch = *self
+            .epoch
+            .insert(self.epoch.map_or(latest_epoch, |e| e.min(latest_epoch)));
+        epoch + since_epoch
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MidiError {
+    // The MIDI backend isn't available.
+    Init(String),
+
+    // There's no port by that name anymore.
+    PortNotFound(String),
+
+    // The port exists, but we couldn't listen to it.
+    Connect(String),
+}
+impl Display for MidiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MidiError::Init(e) => write!(f, "MIDI isn't available: {}", e),
+            MidiError::PortNotFound(name) => write!(f, "No MIDI port named {}", name),
+            MidiError::Connect(e) => write!(f, "Couldn't open MIDI port: {}", e),
+        }
+    }
+}
+impl std::error::Error for MidiError {}
+impl From<midir::InitError> for MidiError {
+    fn from(e: midir::InitError) -> Self {
+        MidiError::Init(e.to_string())
+    }
+}
+impl From<midir::ConnectError<MidiInput>> for MidiError {
+    fn from(e: midir::ConnectError<MidiInput>) -> Self {
+        MidiError::Connect(e.to_string())
+    }
+}
+
+pub enum MidiInterfaceInput {
+    Connect(MidiPort),
+    Disconnect,
+    RefreshPorts,
+    Quit,
+}
+
+#[derive(Clone, Debug)]
+pub enum MidiEvent {
+    Ready(Sender<MidiInterfaceInput>),
+
+    // The ports that exist right now, not counting our virtual one.
+    Ports(Vec<MidiPort>),
+
+    // The port we're now listening to, or None if we're not.
+    Connected(Option<MidiPort>),
+
+    // A message, and when it arrived, first in microseconds from an arbitrary
+    // starting point that the MIDI backend picks, and then as an Instant.
+    Message(u64, Instant, MidiMessage),
+
+    Error(MidiError),
+    Quit,
+}
+
+enum State {
+    Start(Option<MidiPort>), // The port to start on, if any
+    Ready(
+        JoinHandle<()>,                     // The MIDI thread
+        mpsc::UnboundedReceiver<MidiEvent>, // Events from the MIDI thread
+    ),
+    Ending(JoinHandle<()>),
+    Idle,
+}
+
+pub struct MidiSubscription {}
+impl MidiSubscription {
+    /// What other programs see us as.
+    const CLIENT_NAME: &str = "Audio Prototype";
+    const PORT_NAME: &str = "Audio Prototype input";
+
+    /// Starts listening to `port`, or to nothing until the app asks if that's
+    /// None.
+    ///
+    /// Unlike [crate::subscription::AudioInterfaceSubscription], this waits
+    /// for events without blocking an executor thread, because MIDI input can
+    /// stay quiet indefinitely.
+    pub fn subscription(port: Option<MidiPort>) -> Subscription<MidiEvent> {
+        subscription::unfold(
+            std::any::TypeId::of::<MidiSubscription>(),
+            State::Start(port),
+            |state| async move {
+                match state {
+                    State::Start(port) => {
+                        // Sends input from the app to the MIDI thread.
+                        let (input_sender, input_receiver) = unbounded();
+
+                        // Sends events from the MIDI thread, and from the
+                        // backend's own thread, to the subscription.
+                        let (event_sender, event_receiver) = mpsc::unbounded();
+                        let handler = std::thread::Builder::new()
+                            .name("midi".to_string())
+                            .spawn(move || Self::run_midi(port, input_receiver, event_sender))
+                            .expect("spawning the MIDI thread");
+                        (
+                            Some(MidiEvent::Ready(input_sender)),
+                            State::Ready(handler, event_receiver),
+                        )
+                    }
+                    State::Ready(handler, mut event_receiver) => {
+                        match event_receiver.next().await {
+                            Some(MidiEvent::Quit) | None => {
+                                (Some(MidiEvent::Quit), State::Ending(handler))
+                            }
+                            Some(event) => (Some(event), State::Ready(handler, event_receiver)),
+                        }
+                    }
+                    State::Ending(handler) => {
+                        let _ = handler.join();
+                        (None, State::Idle)
+                    }
+                    State::Idle => {
+                        // See AudioInterfaceSubscription.
+                        let _: () = iced::futures::future::pending().await;
+                        (None, State::Idle)
+                    }
+                }
+            },
+        )
+    }
+
+    /// The body of the MIDI thread. It owns the connection to the port we're
+    /// listening to, and opens and closes it as the app asks.
+    fn run_midi(
+        port: Option<MidiPort>,
+        input_receiver: Receiver<MidiInterfaceInput>,
+        event_sender: mpsc::UnboundedSender<MidiEvent>,
+    ) {
+        Self::send_ports(&event_sender);
+        let mut connection = port.and_then(|port| Self::connect(port, &event_sender));
+        while let Ok(input) = input_receiver.recv() {
+            match input {
+                MidiInterfaceInput::Connect(port) => {
+                    // Close the old connection first, in case it's to the
+                    // same port.
+                    drop(connection.take());
+                    connection = Self::connect(port, &event_sender);
+                }
+                MidiInterfaceInput::Disconnect => {
+                    connection = None;
+                    let _ = event_sender.unbounded_send(MidiEvent::Connected(None));
+                }
+                MidiInterfaceInput::RefreshPorts => Self::send_ports(&event_sender),
+                MidiInterfaceInput::Quit => break,
+            }
+        }
+        drop(connection);
+        let _ = event_sender.unbounded_send(MidiEvent::Quit);
+    }
+
+    fn send_ports(event_sender: &mpsc::UnboundedSender<MidiEvent>) {
+        let event = match MidiInput::new(Self::CLIENT_NAME) {
+            Ok(midi_input) => MidiEvent::Ports(
+                midi_input
+                    .ports()
+                    .iter()
+                    .filter_map(|port| midi_input.port_name(port).ok())
+                    .map(MidiPort::Named)
+                    .collect(),
+            ),
+            Err(e) => MidiEvent::Error(e.into()),
+        };
+        let _ = event_sender.unbounded_send(event);
+    }
+
+    /// Opens `port`, reporting how that went.
+    fn connect(
+        port: MidiPort,
+        event_sender: &mpsc::UnboundedSender<MidiEvent>,
+    ) -> Option<MidiInputConnection<()>> {
+        match Self::open(&port, event_sender.clone()) {
+            Ok(connection) => {
+                let _ = event_sender.unbounded_send(MidiEvent::Connected(Some(port)));
+                Some(connection)
+            }
+            Err(e) => {
+                let _ = event_sender.unbounded_send(MidiEvent::Error(e));
+                let _ = event_sender.unbounded_send(MidiEvent::Connected(None));
+                None
+            }
+        }
+    }
+
+    /// Opens `port`. The backend calls us back on a thread of its own for each
+    /// message, which we pass straight to the subscription.
+    fn open(
+        port: &MidiPort,
+        event_sender: mpsc::UnboundedSender<MidiEvent>,
+    ) -> Result<MidiInputConnection<()>, MidiError> {
+        let midi_input = MidiInput::new(Self::CLIENT_NAME)?;
+        let mut clock = MidiClock::default();
+        let mut parser = MidiParser::default();
+        let callback = move |timestamp: u64, bytes: &[u8], _: &mut ()| {
+            let when = clock.instant(timestamp, Instant::now());
+            parser.parse(bytes, |message| {
+                let _ = event_sender.unbounded_send(MidiEvent::Message(timestamp, when, message));
+            });
+        };
+        match port {
+            #[cfg(unix)]
+            MidiPort::Virtual => {
+                use midir::os::unix::VirtualInput;
+                Ok(midi_input.create_virtual(Self::PORT_NAME, callback, ())?)
+            }
+            #[cfg(not(unix))]
+            MidiPort::Virtual => Err(MidiError::Connect(
+                "virtual ports aren't supported on this platform".to_string(),
+            )),
+            MidiPort::Named(name) => {
+                let Some(found) = midi_input
+                    .ports()
+                    .into_iter()
+                    .find(|p| midi_input.port_name(p).is_ok_and(|n| n == *name))
+                else {
+                    return Err(MidiError::PortNotFound(name.clone()));
+                };
+                Ok(midi_input.connect(&found, Self::PORT_NAME, callback, ())?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parser: &mut MidiParser, bytes: &[u8]) -> Vec<MidiMessage> {
+        let mut messages = Vec::default();
+        parser.parse(bytes, |message| messages.push(message));
+        messages
+    }
+
+    fn note_on(channel: u8, note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        }
+    }
+
+    fn note_off(channel: u8, note: u8) -> MidiMessage {
+        MidiMessage::NoteOff { channel, note }
+    }
+
+    #[test]
+    fn parses_messages() {
+        let cases: &[(&[u8], &[MidiMessage])] = &[
+            (&[0x90, 60, 100], &[note_on(0, 60, 100)]),
+            (&[0x9f, 61, 1], &[note_on(15, 61, 1)]),
+            (&[0x80, 60, 64], &[note_off(0, 60)]),
+            // Zero velocity is a note-off.
+            (&[0x93, 60, 0], &[note_off(3, 60)]),
+            (
+                &[0xb2, 1, 127],
+                &[MidiMessage::ControlChange {
+                    channel: 2,
+                    controller: 1,
+                    value: 127,
+                }],
+            ),
+            (
+                &[0xe0, 0, 0x40],
+                &[MidiMessage::PitchBend {
+                    channel: 0,
+                    value: 0,
+                }],
+            ),
+            (
+                &[0xe0, 0, 0],
+                &[MidiMessage::PitchBend {
+                    channel: 0,
+                    value: -8192,
+                }],
+            ),
+            (
+                &[0xe0, 0x7f, 0x7f],
+                &[MidiMessage::PitchBend {
+                    channel: 0,
+                    value: 8191,
+                }],
+            ),
+            // Program change, which has one data byte, and which we ignore.
+            (&[0xc0, 5, 0x90, 60, 100], &[note_on(0, 60, 100)]),
+            // Several messages at once.
+            (
+                &[0x90, 60, 100, 0x80, 60, 0],
+                &[note_on(0, 60, 100), note_off(0, 60)],
+            ),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(
+                parse(&mut MidiParser::default(), bytes),
+                *expected,
+                "{:02x?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn follows_running_status() {
+        let mut parser = MidiParser::default();
+        assert_eq!(
+            parse(&mut parser, &[0x90, 60, 100, 64, 90, 60, 0]),
+            vec![note_on(0, 60, 100), note_on(0, 64, 90), note_off(0, 60)]
+        );
+
+        // Even across deliveries.
+        assert_eq!(parse(&mut parser, &[64, 0]), vec![note_off(0, 64)]);
+
+        // Real-time messages don't interrupt it.
+        assert_eq!(
+            parse(&mut parser, &[67, 0xf8, 80]),
+            vec![note_on(0, 67, 80)]
+        );
+
+        // But system messages end it.
+        assert!(parse(&mut parser, &[0xf0, 1, 2, 0xf7, 60, 100]).is_empty());
+
+        // And data with no status at all means nothing.
+        assert!(parse(&mut MidiParser::default(), &[60, 100]).is_empty());
+    }
+
+    #[test]
+    fn drops_truncated_messages() {
+        let mut parser = MidiParser::default();
+        assert!(parse(&mut parser, &[0x90]).is_empty());
+        assert!(parse(&mut parser, &[0x90, 60]).is_empty());
+        assert!(parse(&mut parser, &[0xe0, 0]).is_empty());
+
+        // A new status abandons a message that's cut short.
+        assert_eq!(
+            parse(&mut parser, &[0x90, 60, 0x80, 61, 0]),
+            vec![note_off(0, 61)]
+        );
+
+        // And the leftover half of a message doesn't carry over.
+        parse(&mut parser, &[0x90, 62]);
+        assert_eq!(parse(&mut parser, &[63, 1]), vec![note_on(0, 63, 1)]);
+    }
+
+    #[test]
+    fn clock_places_messages_in_time() {
+        let start = Instant::now();
+        let mut clock = MidiClock::default();
+
+        // The first message is taken to have arrived right away.
+        assert_eq!(clock.instant(1_000_000, start), start);
+
+        // One that arrives late lands when it happened.
+        assert_eq!(
+            clock.instant(1_500_000, start + Duration::from_millis(800)),
+            start + Duration::from_millis(500)
+        );
+
+        // One that arrives quicker than the first moves the epoch back, so
+        // it's on time.
+        let arrived = start + Duration::from_secs(1);
+        assert_eq!(clock.instant(2_100_000, arrived), arrived);
+        assert_eq!(
+            clock.instant(2_200_000, arrived + Duration::from_millis(300)),
+            arrived + Duration::from_millis(100)
+        );
+
+        // And messages before the new epoch are still placed by it.
+        assert_eq!(
+            clock.instant(1_000_000, arrived),
+            arrived - Duration::from_millis(1100)
+        );
+    }
+}
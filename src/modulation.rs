@@ -0,0 +1,466 @@
+//! Low-frequency oscillators, and the matrix that routes them and other
+//! control signals to the parameters of each voice.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+
+/// The shapes an [Lfo] can produce. Unlike the audio oscillators, these don't
+/// need band-limiting, because they run far below audible frequencies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LfoShape {
+    #[default]
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+
+    // A new random level at the start of each cycle.
+    SampleAndHold,
+}
+impl LfoShape {
+    pub const ALL: [LfoShape; 5] = [
+        LfoShape::Sine,
+        LfoShape::Triangle,
+        LfoShape::Saw,
+        LfoShape::Square,
+        LfoShape::SampleAndHold,
+    ];
+}
+impl Display for LfoShape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LfoShape::Sine => "Sine",
+            LfoShape::Triangle => "Triangle",
+            LfoShape::Saw => "Saw",
+            LfoShape::Square => "Square",
+            LfoShape::SampleAndHold => "Sample & hold",
+        })
+    }
+}
+
+/// Whether an [Lfo] runs at its own rate, or at a fraction of the tempo.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LfoSync {
+    // Runs at LfoSettings::rate.
+    #[default]
+    Free,
+
+    // One cycle per this much of a 4/4 bar.
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+}
+impl LfoSync {
+    pub const ALL: [LfoSync; 6] = [
+        LfoSync::Free,
+        LfoSync::Whole,
+        LfoSync::Half,
+        LfoSync::Quarter,
+        LfoSync::Eighth,
+        LfoSync::Sixteenth,
+    ];
+
+    /// How many beats one cycle lasts, or None if we're not synced.
+    fn beats(&self) -> Option<f32> {
+        match self {
+            LfoSync::Free => None,
+            LfoSync::Whole => Some(4.0),
+            LfoSync::Half => Some(2.0),
+            LfoSync::Quarter => Some(1.0),
+            LfoSync::Eighth => Some(0.5),
+            LfoSync::Sixteenth => Some(0.25),
+        }
+    }
+}
+impl Display for LfoSync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LfoSync::Free => "Free",
+            LfoSync::Whole => "1/1",
+            LfoSync::Half => "1/2",
+            LfoSync::Quarter => "1/4",
+            LfoSync::Eighth => "1/8",
+            LfoSync::Sixteenth => "1/16",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+
+    // In Hz, when not synced.
+    pub rate: f32,
+
+    pub sync: LfoSync,
+}
+impl LfoSettings {
+    pub const MIN_RATE: f32 = 0.01;
+    pub const MAX_RATE: f32 = 20.0;
+
+    /// The same settings, but with everything within range.
+    pub fn clamped(&self) -> Self {
+        Self {
+            rate: self.rate.clamp(Self::MIN_RATE, Self::MAX_RATE),
+            ..*self
+        }
+    }
+
+    /// The rate in Hz, taking sync into account.
+    pub fn frequency(&self, tempo: f32) -> f32 {
+        self.sync
+            .beats()
+            .map_or(self.rate, |beats| tempo / 60.0 / beats)
+    }
+}
+impl Default for LfoSettings {
+    fn default() -> Self {
+        Self {
+            shape: LfoShape::default(),
+            rate: 5.0,
+            sync: LfoSync::default(),
+        }
+    }
+}
+
+/// A free-running low-frequency oscillator. The synthesizer's LFOs are shared
+/// by all its voices, so that, for example, vibrato stays in step across a
+/// chord.
+#[derive(Clone, Debug)]
+pub struct Lfo {
+    // Where we are in the current cycle, in [0, 1).
+    phase: f64,
+
+    // The current sample-and-hold level.
+    held: f32,
+
+    noise_state: u32,
+}
+impl Default for Lfo {
+    fn default() -> Self {
+        Self {
+            phase: 0.0,
+            held: 0.0,
+            noise_state: 0x2545_f491,
+        }
+    }
+}
+impl Lfo {
+    /// Fills `block` with the LFO's next values, each from -1.0 to 1.0.
+    pub fn render(
+        &mut self,
+        block: &mut [f32],
+        settings: &LfoSettings,
+        tempo: f32,
+        sample_rate: usize,
+    ) {
+        let phase_increment = settings.frequency(tempo) as f64 / sample_rate as f64;
+        for value in block.iter_mut() {
+            let t = self.phase as f32;
+            *value = match settings.shape {
+                LfoShape::Sine => (t * 2.0 * std::f32::consts::PI).sin(),
+                LfoShape::Triangle => 1.0 - 4.0 * (t - 0.5).abs(),
+                LfoShape::Saw => 2.0 * t - 1.0,
+                LfoShape::Square => {
+                    if t < 0.5 {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                }
+                LfoShape::SampleAndHold => self.held,
+            };
+            self.phase += phase_increment;
+            if self.phase >= 1.0 {
+                self.phase = self.phase.fract();
+                self.held = self.next_random();
+            }
+        }
+    }
+
+    /// Xorshift32, scaled to [-1, 1).
+    fn next_random(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        (x as f64 / u32::MAX as f64 * 2.0 - 1.0) as f32
+    }
+}
+
+/// Where a modulation route gets its signal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModSource {
+    // From -1.0 to 1.0.
+    #[default]
+    Lfo1,
+    Lfo2,
+
+    // The voice's own envelopes, from 0.0 to 1.0.
+    AmpEnvelope,
+    FilterEnvelope,
+
+    // How hard the voice's note was played, from 0.0 to 1.0.
+    Velocity,
+
+    // From 0.0 to 1.0.
+    ModWheel,
+}
+impl ModSource {
+    pub const ALL: [ModSource; 6] = [
+        ModSource::Lfo1,
+        ModSource::Lfo2,
+        ModSource::AmpEnvelope,
+        ModSource::FilterEnvelope,
+        ModSource::Velocity,
+        ModSource::ModWheel,
+    ];
+}
+impl Display for ModSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ModSource::Lfo1 => "LFO 1",
+            ModSource::Lfo2 => "LFO 2",
+            ModSource::AmpEnvelope => "Amp envelope",
+            ModSource::FilterEnvelope => "Filter envelope",
+            ModSource::Velocity => "Velocity",
+            ModSource::ModWheel => "Mod wheel",
+        })
+    }
+}
+
+/// What a modulation route changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModDestination {
+    // Up to an octave either way at full depth.
+    #[default]
+    Pitch,
+
+    // Up to five octaves either way at full depth.
+    Cutoff,
+
+    // Up to double, or down to silence, at full depth.
+    Amplitude,
+
+    // All the way left or right at full depth.
+    Pan,
+
+    // Across nearly the whole range at full depth.
+    PulseWidth,
+}
+impl ModDestination {
+    pub const ALL: [ModDestination; 5] = [
+        ModDestination::Pitch,
+        ModDestination::Cutoff,
+        ModDestination::Amplitude,
+        ModDestination::Pan,
+        ModDestination::PulseWidth,
+    ];
+    const COUNT: usize = Self::ALL.len();
+
+    /// How far full depth moves the destination, in its own units.
+    fn range(&self) -> f32 {
+        match self {
+            ModDestination::Pitch => 12.0,
+            ModDestination::Cutoff => 5.0,
+            ModDestination::Amplitude => 1.0,
+            ModDestination::Pan => 1.0,
+            ModDestination::PulseWidth => 0.45,
+        }
+    }
+}
+impl Display for ModDestination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ModDestination::Pitch => "Pitch",
+            ModDestination::Cutoff => "Cutoff",
+            ModDestination::Amplitude => "Amplitude",
+            ModDestination::Pan => "Pan",
+            ModDestination::PulseWidth => "Pulse width",
+        })
+    }
+}
+
+/// One connection in the [ModMatrix].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModRoute {
+    pub source: ModSource,
+    pub destination: ModDestination,
+
+    // From -1.0 to 1.0. Zero turns the route off.
+    pub depth: f32,
+}
+
+/// A fixed number of [ModRoute]s, all of which apply at once.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModMatrix {
+    pub routes: [ModRoute; ModMatrix::ROUTE_COUNT],
+}
+impl ModMatrix {
+    pub const ROUTE_COUNT: usize = 4;
+
+    /// The same matrix, but with every depth within range.
+    pub fn clamped(&self) -> Self {
+        Self {
+            routes: self.routes.map(|route| ModRoute {
+                depth: route.depth.clamp(-1.0, 1.0),
+                ..route
+            }),
+        }
+    }
+
+    /// Sums every route into its destination, given the current value of
+    /// each source.
+    pub fn apply(&self, sources: &ModSourceValues) -> ModAmounts {
+        let mut amounts = [0.0; ModDestination::COUNT];
+        for route in self.routes.iter().filter(|route| route.depth != 0.0) {
+            let value = match route.source {
+                ModSource::Lfo1 => sources.lfo1,
+                ModSource::Lfo2 => sources.lfo2,
+                ModSource::AmpEnvelope => sources.amp_envelope,
+                ModSource::FilterEnvelope => sources.filter_envelope,
+                ModSource::Velocity => sources.velocity,
+                ModSource::ModWheel => sources.mod_wheel,
+            };
+            amounts[route.destination as usize] += value * route.depth * route.destination.range();
+        }
+        ModAmounts { amounts }
+    }
+}
+
+/// The value of every [ModSource] for one voice at one moment.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModSourceValues {
+    pub lfo1: f32,
+    pub lfo2: f32,
+    pub amp_envelope: f32,
+    pub filter_envelope: f32,
+    pub velocity: f32,
+    pub mod_wheel: f32,
+}
+
+/// How far the [ModMatrix] moves each [ModDestination], in its own units:
+/// semitones, octaves, and so on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModAmounts {
+    amounts: [f32; ModDestination::COUNT],
+}
+impl ModAmounts {
+    pub fn get(&self, destination: ModDestination) -> f32 {
+        self.amounts[destination as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(source: ModSource, destination: ModDestination, depth: f32) -> ModRoute {
+        ModRoute {
+            source,
+            destination,
+            depth,
+        }
+    }
+
+    #[test]
+    fn routes_sources_to_destinations() {
+        let matrix = ModMatrix {
+            routes: [
+                route(ModSource::Lfo1, ModDestination::Pitch, 0.5),
+                route(ModSource::ModWheel, ModDestination::Pitch, -0.25),
+                route(ModSource::Velocity, ModDestination::Cutoff, 1.0),
+                // Off, so it changes nothing.
+                route(ModSource::AmpEnvelope, ModDestination::Pan, 0.0),
+            ],
+        };
+        let amounts = matrix.apply(&ModSourceValues {
+            lfo1: 1.0,
+            lfo2: 1.0,
+            amp_envelope: 1.0,
+            filter_envelope: 1.0,
+            velocity: 0.5,
+            mod_wheel: 1.0,
+        });
+
+        // Routes to the same destination add up, each scaled to its range.
+        assert_eq!(amounts.get(ModDestination::Pitch), 12.0 * 0.5 - 12.0 * 0.25);
+        assert_eq!(amounts.get(ModDestination::Cutoff), 5.0 * 0.5);
+        assert_eq!(amounts.get(ModDestination::Pan), 0.0);
+        assert_eq!(amounts.get(ModDestination::Amplitude), 0.0);
+        assert_eq!(amounts.get(ModDestination::PulseWidth), 0.0);
+    }
+
+    #[test]
+    fn every_source_reaches_its_route() {
+        for (i, source) in ModSource::ALL.into_iter().enumerate() {
+            let mut values = [0.0; 6];
+            values[i] = 1.0;
+            let sources = ModSourceValues {
+                lfo1: values[0],
+                lfo2: values[1],
+                amp_envelope: values[2],
+                filter_envelope: values[3],
+                velocity: values[4],
+                mod_wheel: values[5],
+            };
+            let matrix = ModMatrix {
+                routes: [route(source, ModDestination::Amplitude, 1.0); ModMatrix::ROUTE_COUNT],
+            };
+            assert_eq!(
+                matrix.apply(&sources).get(ModDestination::Amplitude),
+                ModMatrix::ROUTE_COUNT as f32,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn clamps_depths() {
+        let matrix = ModMatrix {
+            routes: [
+                route(ModSource::Lfo1, ModDestination::Pitch, 3.0),
+                route(ModSource::Lfo2, ModDestination::Pitch, -3.0),
+                route(ModSource::Lfo1, ModDestination::Pitch, 0.5),
+                ModRoute::default(),
+            ],
+        }
+        .clamped();
+        let depths: Vec<_> = matrix.routes.iter().map(|r| r.depth).collect();
+        assert_eq!(depths, vec![1.0, -1.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn synced_lfo_follows_tempo() {
+        let mut settings = LfoSettings {
+            rate: 3.0,
+            ..Default::default()
+        };
+        assert_eq!(settings.frequency(120.0), 3.0);
+        settings.sync = LfoSync::Quarter;
+        assert_eq!(settings.frequency(120.0), 2.0);
+        settings.sync = LfoSync::Whole;
+        assert_eq!(settings.frequency(120.0), 0.5);
+    }
+
+    #[test]
+    fn lfo_stays_in_range() {
+        for shape in LfoShape::ALL {
+            let settings = LfoSettings {
+                shape,
+                rate: LfoSettings::MAX_RATE,
+                ..Default::default()
+            };
+            let mut block = vec![0.0; 4800];
+            Lfo::default().render(&mut block, &settings, 120.0, 48000);
+            assert!(block.iter().all(|v| (-1.0..=1.0).contains(v)), "{}", shape);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! A backend that plays to nowhere. It exists so that the audio path, and the
+//! app around it, can run on machines without a sound card, like CI servers.
+//!
+//! [NullStream] runs the same [StreamCallback] that a cpal stream would, from a
+//! timer thread that wakes up once per buffer. It therefore consumes the queue
+//! and sends [crate::subscription::AudioInterfaceEvent::NeedsAudio] at the same
+//! rate as a real device would.
+
+use crate::{
+    devices::{OutputDeviceId, OutputDeviceInfo, StreamConfigRequest, StreamDescription},
+    stream::{AudioStreamError, CallbackTimestamp, OutputStream, StreamCallback},
+};
+use cpal::{SupportedBufferSize, SupportedStreamConfigRange};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// The null backend's one device. Clones share their state, so whoever finds
+/// the device can still reach it once a stream is using it. That's how tests
+/// get it to misbehave the way real devices sometimes do.
+#[derive(Clone, Debug, Default)]
+pub struct NullDevice {
+    // How many more streams to refuse to build.
+    refusals: Arc<AtomicUsize>,
+
+    // Set to make the stream that's playing on the device act as if the
+    // device had been unplugged.
+    is_unplugged: Arc<AtomicBool>,
+
+    // If set, streams call back once per message on this instead of once per
+    // buffer's worth of time.
+    clock: Option<Receiver<()>>,
+}
+impl NullDevice {
+    /// Makes the next `count` attempts to build a stream on this device fail,
+    /// as a real device does when it doesn't like the config after all.
+    #[cfg(test)]
+    pub fn refuse_builds(&self, count: usize) {
+        self.refusals.store(count, Ordering::Relaxed);
+    }
+
+    /// Makes the stream that's playing on this device report
+    /// [AudioStreamError::DeviceNotAvailable] and stop calling back, as a
+    /// cpal stream does when its device is unplugged. Streams built after
+    /// that play as usual, as if the device had been plugged back in.
+    #[cfg(test)]
+    pub fn unplug(&self) {
+        self.is_unplugged.store(true, Ordering::Relaxed);
+    }
+
+    /// A device whose streams call back only when told to, by a send on the
+    /// returned sender, so that tests can step through callbacks one at a
+    /// time rather than waiting for them.
+    #[cfg(test)]
+    pub fn with_manual_clock() -> (Self, Sender<()>) {
+        let (ticks, clock) = crossbeam_channel::unbounded();
+        let device = Self {
+            clock: Some(clock),
+            ..Default::default()
+        };
+        (device, ticks)
+    }
+
+    /// Uses up one refusal, if there are any left.
+    fn refuses_build(&self) -> bool {
+        self.refusals
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| r.checked_sub(1))
+            .is_ok()
+    }
+}
+
+/// A stream on the null backend.
+#[derive(Debug)]
+pub struct NullStream {
+    // Whether the timer thread should be calling the callback.
+    is_playing: Arc<AtomicBool>,
+
+    // Tells the timer thread to exit.
+    should_quit: Arc<AtomicBool>,
+
+    timer_thread: Option<JoinHandle<()>>,
+}
+impl NullStream {
+    pub const DEVICE_NAME: &'static str = "Null output";
+
+    /// Used when the [StreamConfigRequest] doesn't specify.
+    pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+    pub const DEFAULT_CHANNELS: u16 = 2;
+    pub const DEFAULT_BUFFER_FRAMES: u32 = 512;
+
+    /// The null backend will run at any rate within reason.
+    const MIN_SAMPLE_RATE: u32 = 8000;
+    const MAX_SAMPLE_RATE: u32 = 192000;
+    const MAX_CHANNELS: u16 = 32;
+    const MIN_BUFFER_FRAMES: u32 = 16;
+    const MAX_BUFFER_FRAMES: u32 = 16384;
+
+    /// Starts the timer thread. Like a cpal stream, the new stream starts out
+    /// playing, and reports errors to `error_sender`.
+    pub fn new_with(
+        device: &NullDevice,
+        description: &StreamDescription,
+        mut callback: StreamCallback,
+        error_sender: Sender<AudioStreamError>,
+    ) -> Result<Self, AudioStreamError> {
+        if device.refuses_build() {
+            return Err(AudioStreamError::BuildFailed(
+                "the null device refused".to_string(),
+            ));
+        }
+
+        let is_playing = Arc::new(AtomicBool::new(true));
+        let should_quit = Arc::new(AtomicBool::new(false));
+
+        let frames = match description.buffer_size {
+            cpal::BufferSize::Fixed(frames) => frames,
+            cpal::BufferSize::Default => Self::DEFAULT_BUFFER_FRAMES,
+        } as usize;
+        let period = Duration::from_secs_f64(frames as f64 / description.sample_rate as f64);
+        let mut buffer = vec![0.0f32; frames * description.channels as usize];
+
+        let timer_thread = {
+            let is_playing = Arc::clone(&is_playing);
+            let should_quit = Arc::clone(&should_quit);
+            let is_unplugged = Arc::clone(&device.is_unplugged);
+            let clock = device.clock.clone();
+            std::thread::Builder::new()
+                .name("null output".to_string())
+                .spawn(move || {
+                    let start = Instant::now();
+                    let mut deadline = start;
+                    while !should_quit.load(Ordering::Relaxed) {
+                        if is_unplugged.swap(false, Ordering::Relaxed) {
+                            let _ = error_sender.send(AudioStreamError::DeviceNotAvailable);
+                            break;
+                        }
+                        if let Some(clock) = &clock {
+                            // Look up every so often to see whether we
+                            // should quit.
+                            match clock.recv_timeout(period) {
+                                Ok(()) => {}
+                                Err(RecvTimeoutError::Timeout) => continue,
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            }
+                        }
+                        deadline += period;
+                        if is_playing.load(Ordering::Relaxed) {
+                            // Our pretend device plays each buffer once it's
+                            // time for the next callback.
+                            callback.on_window(
+                                &mut buffer,
+                                CallbackTimestamp {
+                                    callback: start.elapsed(),
+                                    playback: deadline - start,
+                                },
+                            );
+                        }
+                        if clock.is_some() {
+                            // The next tick is the next deadline.
+                            continue;
+                        }
+                        let now = Instant::now();
+                        if deadline > now {
+                            std::thread::sleep(deadline - now);
+                        } else {
+                            // We fell behind. A real device would have glitched
+                            // rather than play faster to catch up, so neither do
+                            // we.
+                            deadline = now;
+                        }
+                    }
+                })
+                .map_err(|e| AudioStreamError::BuildFailed(e.to_string()))?
+        };
+
+        Ok(Self {
+            is_playing,
+            should_quit,
+            timer_thread: Some(timer_thread),
+        })
+    }
+
+    /// Describes the null device for the device list.
+    pub fn device_info() -> OutputDeviceInfo {
+        OutputDeviceInfo {
+            id: OutputDeviceId::null(),
+            is_default: false,
+            configs: vec![SupportedStreamConfigRange::new(
+                Self::DEFAULT_CHANNELS,
+                cpal::SampleRate(Self::MIN_SAMPLE_RATE),
+                cpal::SampleRate(Self::MAX_SAMPLE_RATE),
+                SupportedBufferSize::Range {
+                    min: Self::MIN_BUFFER_FRAMES,
+                    max: Self::MAX_BUFFER_FRAMES,
+                },
+                cpal::SampleFormat::F32,
+            )],
+        }
+    }
+
+    /// The null device can give the request almost anything it wants, except
+    /// that it always produces f32 samples, because there's nobody to convert
+    /// them for.
+    pub fn negotiate(id: &OutputDeviceId, request: &StreamConfigRequest) -> StreamDescription {
+        StreamDescription {
+            device: id.clone(),
+            sample_rate: request
+                .sample_rate
+                .unwrap_or(Self::DEFAULT_SAMPLE_RATE)
+                .clamp(Self::MIN_SAMPLE_RATE, Self::MAX_SAMPLE_RATE),
+            channels: request
+                .channels
+                .unwrap_or(Self::DEFAULT_CHANNELS)
+                .clamp(1, Self::MAX_CHANNELS),
+            sample_format: cpal::SampleFormat::F32,
+            buffer_size: cpal::BufferSize::Fixed(match request.buffer_size {
+                cpal::BufferSize::Fixed(frames) => {
+                    frames.clamp(Self::MIN_BUFFER_FRAMES, Self::MAX_BUFFER_FRAMES)
+                }
+                cpal::BufferSize::Default => Self::DEFAULT_BUFFER_FRAMES,
+            }),
+        }
+    }
+}
+impl OutputStream for NullStream {
+    fn play(&self) -> Result<(), AudioStreamError> {
+        self.is_playing.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<(), AudioStreamError> {
+        self.is_playing.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+impl Drop for NullStream {
+    fn drop(&mut self) {
+        self.should_quit.store(true, Ordering::Relaxed);
+        if let Some(timer_thread) = self.timer_thread.take() {
+            let _ = timer_thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        devices::OutputDevice,
+        oscilloscope::ScopeTap,
+        stream::{AudioQueue, AudioStream, StereoSample},
+        subscription::AudioInterfaceEvent,
+    };
+    use crossbeam_channel::{unbounded, Receiver};
+
+    pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn open_null_stream(
+        buffer_size: usize,
+        request: StreamConfigRequest,
+    ) -> (AudioStream, Receiver<AudioInterfaceEvent>) {
+        open_null_stream_on(&NullDevice::default(), buffer_size, request)
+    }
+
+    /// Opens a stream on `device`, which the caller can go on using to make
+    /// the stream misbehave.
+    pub fn open_null_stream_on(
+        device: &NullDevice,
+        buffer_size: usize,
+        request: StreamConfigRequest,
+    ) -> (AudioStream, Receiver<AudioInterfaceEvent>) {
+        let (sender, events) = unbounded();
+        let description = NullStream::negotiate(&OutputDeviceId::null(), &request);
+        let audio_stream = AudioStream::create_stream_with(
+            OutputDevice::Null(device.clone()),
+            request,
+            description,
+            buffer_size,
+            &Arc::new(ScopeTap::default()),
+            sender,
+        )
+        .expect("the null device should open");
+        (audio_stream, events)
+    }
+
+    /// Waits for the next Reset, skipping over everything else.
+    pub fn next_reset(events: &Receiver<AudioInterfaceEvent>) -> (StreamDescription, AudioQueue) {
+        loop {
+            if let AudioInterfaceEvent::Reset(description, queue) =
+                events.recv_timeout(TIMEOUT).expect("no Reset")
+            {
+                return (description, queue);
+            }
+        }
+    }
+
+    /// Waits for the next NeedsAudio, skipping over everything else, and
+    /// returns how many samples it asked for.
+    pub fn next_needs_audio(events: &Receiver<AudioInterfaceEvent>) -> usize {
+        loop {
+            if let AudioInterfaceEvent::NeedsAudio(_, count) =
+                events.recv_timeout(TIMEOUT).expect("no NeedsAudio")
+            {
+                return count;
+            }
+        }
+    }
+
+    fn request(sample_rate: u32, buffer_frames: u32) -> StreamConfigRequest {
+        StreamConfigRequest {
+            sample_rate: Some(sample_rate),
+            buffer_size: cpal::BufferSize::Fixed(buffer_frames),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reset_describes_negotiated_config() {
+        let (_audio_stream, events) = open_null_stream(1024, request(48000, 256));
+        let (description, queue) = next_reset(&events);
+        assert_eq!(description.device, OutputDeviceId::null());
+        assert_eq!(description.sample_rate, 48000);
+        assert_eq!(description.channels, NullStream::DEFAULT_CHANNELS);
+        assert_eq!(description.sample_format, cpal::SampleFormat::F32);
+        assert_eq!(description.buffer_size, cpal::BufferSize::Fixed(256));
+        assert_eq!(queue.capacity(), 1024);
+    }
+
+    #[test]
+    fn reset_clamps_what_the_device_cannot_do() {
+        let (_audio_stream, events) = open_null_stream(1024, request(1, 1));
+        let (description, _) = next_reset(&events);
+        assert_eq!(description.sample_rate, NullStream::MIN_SAMPLE_RATE);
+        assert_eq!(
+            description.buffer_size,
+            cpal::BufferSize::Fixed(NullStream::MIN_BUFFER_FRAMES)
+        );
+    }
+
+    #[test]
+    fn needs_audio_asks_to_fill_empty_queue() {
+        let (_audio_stream, events) = open_null_stream(1024, request(48000, 256));
+        let (_, queue) = next_reset(&events);
+
+        // Nobody's filling the queue, so every callback finds it empty.
+        for _ in 0..3 {
+            assert_eq!(next_needs_audio(&events), queue.capacity());
+        }
+    }
+
+    #[test]
+    fn filled_queue_is_drained() {
+        let (device, clock) = NullDevice::with_manual_clock();
+        let (_audio_stream, events) = open_null_stream_on(&device, 1024, request(48000, 256));
+        let (_, queue) = next_reset(&events);
+
+        // No callback runs until we say, so the first sees the queue full.
+        while queue.push(StereoSample::default()).is_ok() {}
+
+        // Each callback takes one buffer's worth, and asks for it back.
+        for asked_for in [256, 512, 768, 1024] {
+            clock.send(()).unwrap();
+            assert_eq!(next_needs_audio(&events), asked_for);
+        }
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+
+/// The shapes an [Oscillator] can produce.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Saw,
+
+    // A pulse wave, which is a square wave when its pulse width is 0.5.
+    Square,
+
+    Triangle,
+
+    // White noise. It has no pitch, so frequency doesn't affect it.
+    Noise,
+}
+impl Waveform {
+    pub const ALL: [Waveform; 5] = [
+        Waveform::Sine,
+        Waveform::Saw,
+        Waveform::Square,
+        Waveform::Triangle,
+        Waveform::Noise,
+    ];
+}
+impl Display for Waveform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Waveform::Sine => "Sine",
+            Waveform::Saw => "Saw",
+            Waveform::Square => "Square",
+            Waveform::Triangle => "Triangle",
+            Waveform::Noise => "Noise",
+        })
+    }
+}
+
+/// Produces one voice's worth of a [Waveform].
+///
+/// The naive saw, pulse, and triangle waves have corners and jumps that
+/// produce harmonics far above Nyquist, which fold back down as inharmonic
+/// aliasing that gets worse the higher the note. We smooth each discontinuity
+/// with a polynomial approximation of a band-limited step (PolyBLEP), or for
+/// the triangle's corners, of a band-limited ramp (PolyBLAMP). It's cheap and
+/// needs no tables, and while it doesn't remove aliasing entirely, it removes
+/// most of the audible part.
+#[derive(Clone, Debug)]
+pub struct Oscillator {
+    waveform: Waveform,
+
+    // The fraction of each cycle that a pulse wave spends high.
+    pulse_width: f32,
+
+    // Where we are in the current cycle, in [0, 1). Keeping it here, rather
+    // than working it out from a sample count, means that it stays accurate
+    // however long we play, and that a change in frequency changes only how
+    // fast it moves, not where it is.
+    phase: f64,
+
+    // The state of the noise generator. Each oscillator has its own, so that
+    // what it produces doesn't depend on what else is running, or where.
+    noise_state: u32,
+}
+impl Oscillator {
+    pub const MIN_PULSE_WIDTH: f32 = 0.05;
+    pub const MAX_PULSE_WIDTH: f32 = 0.95;
+
+    /// `seed` starts the noise generator. Give each oscillator a different
+    /// one, or their noise will be identical.
+    pub fn new_with(seed: u32) -> Self {
+        Self {
+            waveform: Waveform::default(),
+            pulse_width: 0.5,
+            phase: 0.0,
+
+            // Xorshift gets stuck at zero.
+            noise_state: seed.max(1),
+        }
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.pulse_width = pulse_width.clamp(Self::MIN_PULSE_WIDTH, Self::MAX_PULSE_WIDTH);
+    }
+
+    /// Returns the oscillator's output at its current phase, then advances the
+    /// phase by `phase_increment`, which is the oscillator's frequency divided
+    /// by the sample rate.
+    pub fn next_sample(&mut self, phase_increment: f64) -> f32 {
+        let sample = self.sample(self.phase, phase_increment);
+        self.phase += phase_increment;
+        if self.phase >= 1.0 {
+            // Not just 1.0, in case the frequency is above the sample rate.
+            self.phase = self.phase.fract();
+        }
+        sample
+    }
+
+    /// The oscillator's output at `phase`, which is in [0, 1), when the phase
+    /// advances by `phase_increment` each sample.
+    fn sample(&mut self, phase: f64, phase_increment: f64) -> f32 {
+        let t = phase;
+        let dt = phase_increment;
+        (match self.waveform {
+            Waveform::Sine => (t * 2.0 * std::f64::consts::PI).sin(),
+            Waveform::Saw => 2.0 * t - 1.0 - Self::poly_blep(t, dt),
+            Waveform::Square => {
+                let width = self.pulse_width as f64;
+
+                // The phase relative to the falling edge. Wrapping it by the
+                // same comparison that picks the naive value, rather than
+                // with fract(), keeps a phase that rounds up to the edge
+                // from getting the correction for the far side of it.
+                let (naive, fall) = if t < width {
+                    (1.0, t + 1.0 - width)
+                } else {
+                    (-1.0, t - width)
+                };
+                naive + Self::poly_blep(t, dt) - Self::poly_blep(fall, dt)
+            }
+            Waveform::Triangle => {
+                let naive = 1.0 - 4.0 * (t - 0.5).abs();
+                naive
+                    + 4.0 * dt * (Self::poly_blamp(t, dt) - Self::poly_blamp((t + 0.5).fract(), dt))
+            }
+            Waveform::Noise => self.next_noise() as f64,
+        }) as f32
+    }
+
+    /// The difference between a naive and a band-limited upward step of 2 at
+    /// phase 0, near phase `t`.
+    fn poly_blep(t: f64, dt: f64) -> f64 {
+        if t < dt {
+            let t = t / dt;
+            2.0 * t - t * t - 1.0
+        } else if t > 1.0 - dt {
+            let t = (t - 1.0) / dt;
+            t * t + 2.0 * t + 1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// The difference between a naive and a band-limited corner at phase 0,
+    /// near phase `t`. This is the integral of [Self::poly_blep].
+    fn poly_blamp(t: f64, dt: f64) -> f64 {
+        if t < dt {
+            let t = t / dt - 1.0;
+            -t * t * t / 3.0
+        } else if t > 1.0 - dt {
+            let t = (t - 1.0) / dt + 1.0;
+            t * t * t / 3.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Xorshift32, scaled to [-1, 1).
+    fn next_noise(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        (x as f64 / u32::MAX as f64 * 2.0 - 1.0) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn oscillator(waveform: Waveform) -> Oscillator {
+        let mut oscillator = Oscillator::new_with(1);
+        oscillator.set_waveform(waveform);
+        oscillator
+    }
+
+    /// A second of `oscillator` at `frequency`.
+    fn play(oscillator: &mut Oscillator, frequency: f64) -> Vec<f32> {
+        (0..SAMPLE_RATE as usize)
+            .map(|_| oscillator.next_sample(frequency / SAMPLE_RATE))
+            .collect()
+    }
+
+    fn mean(samples: &[f32]) -> f32 {
+        samples.iter().sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn output_is_bounded_without_dc() {
+        for waveform in Waveform::ALL {
+            // Each a whole number of cycles per second, so that the mean of a
+            // second is the mean of a cycle.
+            for frequency in [40.0, 440.0, 4800.0, 12000.0] {
+                let samples = play(&mut oscillator(waveform), frequency);
+                assert!(
+                    samples.iter().all(|s| s.abs() <= 1.0),
+                    "{} at {} Hz",
+                    waveform,
+                    frequency
+                );
+                assert!(
+                    mean(&samples).abs() < 0.01,
+                    "{} at {} Hz has DC of {}",
+                    waveform,
+                    frequency,
+                    mean(&samples)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn correction_is_applied_at_discontinuities() {
+        let dt = 0.01;
+
+        // Jumps land halfway, where the naive wave would be at one extreme.
+        let mut saw = oscillator(Waveform::Saw);
+        assert_eq!(saw.sample(0.0, dt), 0.0);
+        let mut square = oscillator(Waveform::Square);
+        assert_eq!(square.sample(0.0, dt), 0.0);
+        assert_eq!(square.sample(0.5, dt), 0.0);
+
+        // Corners are rounded off.
+        let mut triangle = oscillator(Waveform::Triangle);
+        assert!(triangle.sample(0.0, dt) > -1.0);
+        assert!(triangle.sample(0.5, dt) < 1.0);
+
+        // And away from them, the waves are the naive ones.
+        assert_eq!(saw.sample(0.25, dt), -0.5);
+        assert_eq!(square.sample(0.25, dt), 1.0);
+        assert_eq!(square.sample(0.75, dt), -1.0);
+        assert_eq!(triangle.sample(0.25, dt), 0.0);
+    }
+
+    #[test]
+    fn frequency_change_keeps_phase() {
+        let mut sine = oscillator(Waveform::Sine);
+        let mut previous = (0.0, 0.0);
+        for frequency in [440.0, 3000.0, 55.0, 12000.0] {
+            let dt = frequency / SAMPLE_RATE;
+            for _ in 0..1000 {
+                let (previous_sample, previous_dt) = previous;
+                let sample = sine.next_sample(dt);
+
+                // No step bigger than the slope allows, even on the first
+                // sample at a new frequency, which is the first that the
+                // phase reaches after moving at the old one.
+                let max_step = 2.0 * std::f64::consts::PI * previous_dt;
+                assert!(
+                    ((sample - previous_sample) as f64).abs() <= max_step + 1e-6,
+                    "jumped at {} Hz",
+                    frequency
+                );
+                previous = (sample, dt);
+            }
+        }
+    }
+
+    #[test]
+    fn phase_stays_accurate() {
+        // An hour at 440 Hz takes too long to test, but the error in a sum of
+        // increments grows steadily, so ten minutes shows it well enough.
+        let dt = 440.0 / SAMPLE_RATE;
+        let count = SAMPLE_RATE as usize * 600;
+        let mut sine = oscillator(Waveform::Sine);
+        for _ in 0..count {
+            sine.next_sample(dt);
+        }
+        let expected = (count as f64 * 440.0 / SAMPLE_RATE).fract();
+
+        // Either way around the wrap.
+        let error = (sine.phase - expected).abs();
+        assert!(error.min(1.0 - error) < 1e-6, "{}", sine.phase);
+        assert!((0.0..1.0).contains(&sine.phase));
+
+        // A frequency above the sample rate still leaves the phase in range.
+        sine.next_sample(2.5);
+        assert!((0.0..1.0).contains(&sine.phase));
+    }
+
+    #[test]
+    fn pulse_width_sets_duty_cycle() {
+        for width in [0.1, 0.25, 0.5, 0.75] {
+            let mut pulse = oscillator(Waveform::Square);
+            pulse.set_pulse_width(width);
+            let samples = play(&mut pulse, 480.0);
+            let high = samples.iter().filter(|s| **s > 0.0).count() as f32 / samples.len() as f32;
+            assert!((high - width).abs() < 0.02, "{} for width {}", high, width);
+
+            // High for width and low for the rest, so the mean moves with it.
+            assert!((mean(&samples) - (2.0 * width - 1.0)).abs() < 0.02);
+        }
+
+        let mut pulse = oscillator(Waveform::Square);
+        pulse.set_pulse_width(0.0);
+        assert_eq!(pulse.pulse_width, Oscillator::MIN_PULSE_WIDTH);
+        pulse.set_pulse_width(1.0);
+        assert_eq!(pulse.pulse_width, Oscillator::MAX_PULSE_WIDTH);
+    }
+}
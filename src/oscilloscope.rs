@@ -0,0 +1,270 @@
+//! An oscilloscope that shows what the audio stream is actually playing, as
+//! opposed to what's waiting in the [crate::stream::AudioQueue], and the tap
+//! that it and [crate::spectrum::SpectrumAnalyzer] get their samples from.
+
+use crate::stream::StereoSample;
+use crossbeam::queue::ArrayQueue;
+use iced::{
+    widget::canvas::{self, Cursor, Frame, Geometry, Path, Stroke},
+    Color, Point, Rectangle, Theme,
+};
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Where the stream callback leaves copies of the samples it plays, for the
+/// app's displays to pick up. Like [crate::wav::RecordingTap], the callback
+/// copies samples here only while someone is looking.
+#[derive(Debug)]
+pub struct ScopeTap {
+    is_enabled: AtomicBool,
+    queue: ArrayQueue<StereoSample>,
+}
+impl Default for ScopeTap {
+    fn default() -> Self {
+        Self {
+            is_enabled: AtomicBool::new(false),
+
+            // Several frames' worth at typical rates and refresh rates, so
+            // that a slow frame doesn't leave a gap in the trace.
+            queue: ArrayQueue::new(16384),
+        }
+    }
+}
+impl ScopeTap {
+    /// Called from the stream callback, so it must never block. If the app
+    /// hasn't kept up, the oldest samples are dropped, which is what the
+    /// displays would have done with them anyway.
+    pub fn push(&self, sample: StereoSample) {
+        if self.is_enabled.load(Ordering::Relaxed) {
+            let _ = self.queue.force_push(sample);
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Starts or stops copying samples from the stream. Anything left over
+    /// from before a change is stale either way, but if nothing changes, the
+    /// samples are still waiting for whichever display is already showing.
+    pub fn set_enabled(&self, is_enabled: bool) {
+        if self.is_enabled.swap(is_enabled, Ordering::Relaxed) != is_enabled {
+            while self.queue.pop().is_some() {}
+        }
+    }
+
+    /// Everything the stream has played since the last time we asked, oldest
+    /// first.
+    pub fn take(&self) -> Vec<StereoSample> {
+        std::iter::from_fn(|| self.queue.pop()).collect()
+    }
+}
+
+/// How the [Oscilloscope] draws the two channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScopeMode {
+    // Each channel against time, left above right.
+    #[default]
+    LeftRight,
+
+    // Left against right, which shows the stereo image.
+    Xy,
+}
+impl ScopeMode {
+    pub const ALL: [ScopeMode; 2] = [ScopeMode::LeftRight, ScopeMode::Xy];
+}
+impl Display for ScopeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ScopeMode::LeftRight => "Left/right",
+            ScopeMode::Xy => "X/Y",
+        })
+    }
+}
+
+/// Keeps the most recent samples from a [ScopeTap], and draws them on a
+/// canvas.
+#[derive(Debug)]
+pub struct Oscilloscope {
+    is_enabled: bool,
+    mode: ScopeMode,
+
+    // Newest at the back. Longer than what we display, so that there's room
+    // to look back for a trigger.
+    history: VecDeque<StereoSample>,
+}
+impl Default for Oscilloscope {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            mode: ScopeMode::default(),
+            history: VecDeque::with_capacity(Self::HISTORY_LEN),
+        }
+    }
+}
+impl Oscilloscope {
+    /// How many samples we display. About 23 milliseconds at 44.1KHz, which
+    /// is a full cycle of anything above the bottom octave of a piano.
+    const DISPLAY_LEN: usize = 1024;
+    const HISTORY_LEN: usize = Self::DISPLAY_LEN * 4;
+
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled
+    }
+
+    /// Shows or hides the oscilloscope. Whatever we had is stale either way.
+    pub fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+        self.history.clear();
+    }
+
+    pub fn mode(&self) -> ScopeMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ScopeMode) {
+        self.mode = mode;
+    }
+
+    /// Adds what the stream has played since last time, from
+    /// [ScopeTap::take].
+    pub fn update(&mut self, samples: &[StereoSample]) {
+        for sample in samples {
+            if self.history.len() == Self::HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(*sample);
+        }
+    }
+
+    /// Where the samples to display begin: the latest place where the left
+    /// channel rises through zero and there's still a full display's worth
+    /// after it, so that a steady tone holds still from one frame to the
+    /// next. If there's no such place, we just show the newest samples.
+    fn trigger(&self) -> usize {
+        let newest_start = self.history.len().saturating_sub(Self::DISPLAY_LEN);
+        (1..=newest_start)
+            .rev()
+            .find(|&i| self.history[i - 1].left < 0.0 && self.history[i].left >= 0.0)
+            .unwrap_or(newest_start)
+    }
+
+    fn draw_left_right(&self, frame: &mut Frame, stroke: Stroke) {
+        let start = self.trigger();
+        let samples = self.history.range(start..).take(Self::DISPLAY_LEN);
+        let x_scale = frame.width() / (Self::DISPLAY_LEN - 1) as f32;
+
+        // Each channel gets half the height, with full scale reaching the
+        // edge of its half.
+        let half_height = frame.height() / 2.0;
+        for (center, is_right) in [(half_height / 2.0, false), (half_height * 1.5, true)] {
+            let path = Path::new(|builder| {
+                for (i, sample) in samples.clone().enumerate() {
+                    let value = if is_right { sample.right } else { sample.left };
+                    let point = Point::new(
+                        i as f32 * x_scale,
+                        center - value.clamp(-1.0, 1.0) * half_height / 2.0,
+                    );
+                    if i == 0 {
+                        builder.move_to(point);
+                    } else {
+                        builder.line_to(point);
+                    }
+                }
+            });
+            frame.stroke(&path, stroke.clone());
+        }
+    }
+
+    fn draw_xy(&self, frame: &mut Frame, stroke: Stroke) {
+        let start = self.history.len().saturating_sub(Self::DISPLAY_LEN);
+        let center = frame.center();
+        let scale = frame.width().min(frame.height()) / 2.0;
+        let path = Path::new(|builder| {
+            for (i, sample) in self.history.range(start..).enumerate() {
+                let point = Point::new(
+                    center.x + sample.left.clamp(-1.0, 1.0) * scale,
+                    center.y - sample.right.clamp(-1.0, 1.0) * scale,
+                );
+                if i == 0 {
+                    builder.move_to(point);
+                } else {
+                    builder.line_to(point);
+                }
+            }
+        });
+        frame.stroke(&path, stroke);
+    }
+}
+impl<Message> canvas::Program<Message> for Oscilloscope {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &Self::State,
+        theme: &Theme,
+        bounds: Rectangle,
+        _cursor: Cursor,
+    ) -> Vec<Geometry> {
+        let palette = theme.palette();
+        let mut frame = Frame::new(bounds.size());
+
+        // The axes, faintly.
+        let axis_color = Color {
+            a: 0.25,
+            ..palette.text
+        };
+        let axes = Path::new(|builder| match self.mode {
+            ScopeMode::LeftRight => {
+                for y in [frame.height() / 4.0, frame.height() * 0.75] {
+                    builder.move_to(Point::new(0.0, y));
+                    builder.line_to(Point::new(frame.width(), y));
+                }
+            }
+            ScopeMode::Xy => {
+                let center = frame.center();
+                builder.move_to(Point::new(0.0, center.y));
+                builder.line_to(Point::new(frame.width(), center.y));
+                builder.move_to(Point::new(center.x, 0.0));
+                builder.line_to(Point::new(center.x, frame.height()));
+            }
+        });
+        frame.stroke(&axes, Stroke::default().with_color(axis_color));
+
+        let stroke = Stroke::default()
+            .with_color(palette.primary)
+            .with_width(1.5);
+        match self.mode {
+            ScopeMode::LeftRight => self.draw_left_right(&mut frame, stroke),
+            ScopeMode::Xy => self.draw_xy(&mut frame, stroke),
+        }
+        vec![frame.into_geometry()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enabling_again_keeps_waiting_samples() {
+        let tap = ScopeTap::default();
+        tap.push(StereoSample::default());
+        assert!(tap.take().is_empty());
+
+        tap.set_enabled(true);
+        tap.push(StereoSample::default());
+
+        // e.g., the scope turning on while the spectrum is already showing.
+        tap.set_enabled(true);
+        assert_eq!(tap.take().len(), 1);
+
+        tap.push(StereoSample::default());
+        tap.set_enabled(false);
+        tap.set_enabled(true);
+        assert!(tap.take().is_empty());
+    }
+}
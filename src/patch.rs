@@ -0,0 +1,233 @@
+use crate::{
+    envelope::EnvelopeSettings,
+    filter::FilterSettings,
+    modulation::{LfoSettings, ModMatrix},
+    oscillator::{Oscillator, Waveform},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Display},
+    path::Path,
+};
+
+/// Everything about how the synthesizer sounds, as opposed to how it runs.
+/// Patches can be saved to and loaded from JSON files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Patch {
+    pub waveform: Waveform,
+
+    // The fraction of each cycle that a pulse wave spends high.
+    pub pulse_width: f32,
+
+    // The amplitude envelope.
+    pub envelope: EnvelopeSettings,
+
+    pub filter: FilterSettings,
+    pub lfos: [LfoSettings; Patch::LFO_COUNT],
+    pub mod_matrix: ModMatrix,
+}
+impl Patch {
+    pub const LFO_COUNT: usize = 2;
+
+    /// The same patch, but with everything within range. Patches from files
+    /// might have anything in them.
+    pub fn clamped(&self) -> Self {
+        Self {
+            waveform: self.waveform,
+            pulse_width: self
+                .pulse_width
+                .clamp(Oscillator::MIN_PULSE_WIDTH, Oscillator::MAX_PULSE_WIDTH),
+            envelope: self.envelope.clamped(),
+            filter: self.filter.clamped(),
+            lfos: self.lfos.map(|lfo| lfo.clamped()),
+            mod_matrix: self.mod_matrix.clamped(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), PatchError> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, PatchError> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str::<Self>(&json)?.clamped())
+    }
+}
+impl Default for Patch {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::default(),
+            pulse_width: 0.5,
+            envelope: EnvelopeSettings::default(),
+            filter: FilterSettings::default(),
+            lfos: Default::default(),
+            mod_matrix: ModMatrix::default(),
+        }
+    }
+}
+
+/// Why a [Patch] couldn't be saved or loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    // Reading or writing the file failed.
+    Io(String),
+
+    // The file isn't a patch.
+    Format(String),
+}
+impl Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::Io(e) => write!(f, "Couldn't access patch file: {}", e),
+            PatchError::Format(e) => write!(f, "Not a valid patch: {}", e),
+        }
+    }
+}
+impl std::error::Error for PatchError {}
+impl From<std::io::Error> for PatchError {
+    fn from(e: std::io::Error) -> Self {
+        PatchError::Io(e.to_string())
+    }
+}
+impl From<serde_json::Error> for PatchError {
+    fn from(e: serde_json::Error) -> Self {
+        PatchError::Format(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        envelope::EnvelopeCurve,
+        filter::FilterMode,
+        modulation::{LfoShape, LfoSync, ModDestination, ModRoute, ModSource},
+    };
+    use std::path::PathBuf;
+
+    /// A file of our own in the temp directory, removed when dropped.
+    struct TempFile(PathBuf);
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!(
+                "patch-test-{}-{}.json",
+                std::process::id(),
+                name
+            )))
+        }
+
+        fn with_contents(name: &str, contents: &str) -> Self {
+            let file = Self::new(name);
+            std::fs::write(&file.0, contents).unwrap();
+            file
+        }
+    }
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_file() {
+        let mut patch = Patch {
+            waveform: Waveform::Square,
+            pulse_width: 0.3,
+            ..Default::default()
+        };
+        patch.envelope.attack = 0.25;
+        patch.envelope.curve = EnvelopeCurve::ALL[EnvelopeCurve::ALL.len() - 1];
+        patch.filter.mode = FilterMode::ALL[FilterMode::ALL.len() - 1];
+        patch.lfos[1] = LfoSettings {
+            shape: LfoShape::SampleAndHold,
+            rate: 2.0,
+            sync: LfoSync::Eighth,
+        };
+        patch.mod_matrix.routes[2] = ModRoute {
+            source: ModSource::Velocity,
+            destination: ModDestination::Cutoff,
+            depth: -0.5,
+        };
+
+        let file = TempFile::new("round-trip");
+        patch.save(&file.0).unwrap();
+        assert_eq!(Patch::load(&file.0), Ok(patch));
+    }
+
+    #[test]
+    fn missing_fields_get_defaults() {
+        let file = TempFile::with_contents(
+            "missing",
+            r#"{
+                "waveform": "Saw",
+                "envelope": { "attack": 0.5 },
+                "lfos": [{ "rate": 1.0 }, {}],
+                "mod_matrix": { "routes": [{ "depth": 0.5 }, {}, {}, {}] }
+            }"#,
+        );
+        let patch = Patch::load(&file.0).unwrap();
+
+        let mut expected = Patch {
+            waveform: Waveform::Saw,
+            ..Default::default()
+        };
+        expected.envelope.attack = 0.5;
+        expected.lfos[0].rate = 1.0;
+        expected.mod_matrix.routes[0].depth = 0.5;
+        assert_eq!(patch, expected);
+
+        let file = TempFile::with_contents("empty", "{}");
+        assert_eq!(Patch::load(&file.0), Ok(Patch::default()));
+    }
+
+    #[test]
+    fn extra_fields_are_ignored() {
+        let file = TempFile::with_contents(
+            "extra",
+            r#"{
+                "waveform": "Triangle",
+                "from_the_future": [1, 2, 3],
+                "filter": { "drive": 11 }
+            }"#,
+        );
+        assert_eq!(
+            Patch::load(&file.0),
+            Ok(Patch {
+                waveform: Waveform::Triangle,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn loaded_values_are_clamped() {
+        let file = TempFile::with_contents(
+            "clamped",
+            r#"{ "pulse_width": 2.0, "mod_matrix": { "routes": [{ "depth": -7.0 }, {}, {}, {}] } }"#,
+        );
+        let patch = Patch::load(&file.0).unwrap();
+        assert_eq!(patch.pulse_width, Oscillator::MAX_PULSE_WIDTH);
+        assert_eq!(patch.mod_matrix.routes[0].depth, -1.0);
+    }
+
+    #[test]
+    fn reports_why_loading_failed() {
+        let missing = TempFile::new("missing-file");
+        assert!(matches!(Patch::load(&missing.0), Err(PatchError::Io(_))));
+
+        let garbage = TempFile::with_contents("garbage", "not json");
+        assert!(matches!(
+            Patch::load(&garbage.0),
+            Err(PatchError::Format(_))
+        ));
+
+        let wrong_type = TempFile::with_contents("wrong-type", r#"{ "waveform": "Kazoo" }"#);
+        assert!(matches!(
+            Patch::load(&wrong_type.0),
+            Err(PatchError::Format(_))
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+//! A rolling graph of how full the [crate::stream::AudioQueue] has been, how
+//! much the stream asked for each time, and when it ran dry. This is where
+//! the effects of [crate::synthesizer::Synthesizer::fake_delay] and the
+//! buffer size show up.
+
+use crate::render::QueueActivity;
+use iced::{
+    widget::canvas::{self, Cursor, Frame, Geometry, Path, Stroke},
+    Color, Point, Rectangle, Size, Theme,
+};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// How far back the [QueueHistory] graph looks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistorySpan(u64);
+impl HistorySpan {
+    pub const ALL: [HistorySpan; 4] = [
+        HistorySpan(1),
+        HistorySpan(2),
+        HistorySpan(5),
+        HistorySpan(10),
+    ];
+
+    fn duration(&self) -> Duration {
+        Duration::from_secs(self.0)
+    }
+}
+impl Default for HistorySpan {
+    fn default() -> Self {
+        Self(5)
+    }
+}
+impl Display for HistorySpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} sec", self.0)
+    }
+}
+
+/// Keeps the last several seconds of [QueueActivity] and underruns, and
+/// draws them on a canvas.
+#[derive(Debug, Default)]
+pub struct QueueHistory {
+    span: HistorySpan,
+
+    // Oldest first. We keep enough for the longest span, so that switching
+    // to a longer one doesn't start from nothing.
+    activity: VecDeque<QueueActivity>,
+    underruns: VecDeque<Instant>,
+
+    // The newest thing we've heard about, which is the graph's right edge.
+    // Using that instead of the current time keeps the graph still when
+    // nothing's happening, as when the stream is paused.
+    latest: Option<Instant>,
+}
+impl QueueHistory {
+    pub fn span(&self) -> HistorySpan {
+        self.span
+    }
+
+    pub fn set_span(&mut self, span: HistorySpan) {
+        self.span = span;
+    }
+
+    /// Adds a batch of [QueueActivity] from the render thread.
+    pub fn add_activity(&mut self, activity: Vec<QueueActivity>) {
+        for entry in activity {
+            self.note_time(entry.filled_at);
+            self.activity.push_back(entry);
+        }
+        self.forget_old();
+    }
+
+    /// Adds an underrun that the stream callback reported at `when`.
+    pub fn add_underrun(&mut self, when: Instant) {
+        self.note_time(when);
+        self.underruns.push_back(when);
+        self.forget_old();
+    }
+
+    fn note_time(&mut self, when: Instant) {
+        self.latest = Some(self.latest.map_or(when, |latest| latest.max(when)));
+    }
+
+    fn forget_old(&mut self) {
+        let Some(latest) = self.latest else {
+            return;
+        };
+        // The spans are in order, so the last is the longest.
+        let longest = HistorySpan::ALL[HistorySpan::ALL.len() - 1].duration();
+        let Some(cutoff) = latest.checked_sub(longest) else {
+            return;
+        };
+        while self
+            .activity
+            .front()
+            .is_some_and(|entry| entry.filled_at < cutoff)
+        {
+            self.activity.pop_front();
+        }
+        while self.underruns.front().is_some_and(|when| *when < cutoff) {
+            self.underruns.pop_front();
+        }
+    }
+}
+impl<Message> canvas::Program<Message> for QueueHistory {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &Self::State,
+        theme: &Theme,
+        bounds: Rectangle,
+        _cursor: Cursor,
+    ) -> Vec<Geometry> {
+        let palette = theme.palette();
+        let mut frame = Frame::new(bounds.size());
+        let Some(latest) = self.latest else {
+            return vec![frame.into_geometry()];
+        };
+        let span = self.span.duration();
+        let Some(start) = latest.checked_sub(span) else {
+            return vec![frame.into_geometry()];
+        };
+        let (width, height) = (frame.width(), frame.height());
+        let x = |when: Instant| {
+            when.saturating_duration_since(start).as_secs_f32() / span.as_secs_f32() * width
+        };
+
+        // Everything is a fraction of the queue's capacity at the time, so
+        // that changing the buffer size doesn't throw the scale off.
+        let y = |len: usize, capacity: usize| height - len as f32 / capacity.max(1) as f32 * height;
+        let visible = self
+            .activity
+            .iter()
+            .filter(|entry| entry.filled_at >= start);
+
+        // The request sizes, as faint bars up from the bottom.
+        let request_color = Color {
+            a: 0.25,
+            ..palette.text
+        };
+        for entry in visible.clone() {
+            let top = y(entry.requested, entry.capacity);
+            frame.fill_rectangle(
+                Point::new(x(entry.requested_at), top),
+                Size::new(1.0, height - top),
+                request_color,
+            );
+        }
+
+        // The fill level, which drops to what was left when the stream asked
+        // for more, and climbs back once the render thread has filled it.
+        // The time in between is how long rendering took.
+        let fill = Path::new(|builder| {
+            for (i, entry) in visible.enumerate() {
+                let asked = Point::new(
+                    x(entry.requested_at),
+                    y(entry.capacity - entry.requested, entry.capacity),
+                );
+                if i == 0 {
+                    builder.move_to(asked);
+                } else {
+                    builder.line_to(asked);
+                }
+                builder.line_to(Point::new(
+                    x(entry.filled_at),
+                    y(entry.len_after, entry.capacity),
+                ));
+            }
+        });
+        frame.stroke(
+            &fill,
+            Stroke::default()
+                .with_color(palette.primary)
+                .with_width(1.5),
+        );
+
+        // The underruns, as lines all the way up.
+        let underruns = Path::new(|builder| {
+            for when in self.underruns.iter().filter(|when| **when >= start) {
+                builder.move_to(Point::new(x(*when), 0.0));
+                builder.line_to(Point::new(x(*when), height));
+            }
+        });
+        frame.stroke(&underruns, Stroke::default().with_color(palette.danger));
+
+        vec![frame.into_geometry()]
+    }
+}
@@ -0,0 +1,266 @@
+//! The render thread owns the [Synthesizer] and the producing end of the
+//! [AudioQueue]. It sits between the audio stream and the app: it answers
+//! [AudioInterfaceEvent::NeedsAudio] itself, and passes every other event
+//! through. The app changes the synthesizer by sending [SynthesizerInput],
+//! and learns how it's doing from [AudioInterfaceEvent::Synthesizer] and
+//! [AudioInterfaceEvent::QueueActivity].
+//!
+//! None of this involves the app's update loop, so a slow `view()` or a
+//! window being dragged around can't starve the queue.
+
+use crate::{
+    envelope::EnvelopeSettings,
+    filter::FilterSettings,
+    modulation::{LfoSettings, ModRoute},
+    oscillator::Waveform,
+    patch::Patch,
+    stream::AudioQueue,
+    subscription::AudioInterfaceEvent,
+    synthesizer::{Synthesizer, TimedEvent, VoiceRendering},
+    voice::VoiceStealing,
+};
+use crossbeam_channel::{select, Receiver, Sender};
+use std::{
+    fmt::Debug,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Changes the app can make to the [Synthesizer] on the render thread. The
+/// ones that a player performs carry when they happened, so that the
+/// synthesizer can place them within a block. See [Synthesizer::schedule].
+#[derive(Clone, Debug)]
+pub enum SynthesizerInput {
+    Play,
+    Pause,
+    NoteOn(u8, u8, Instant),
+    NoteOff(u8, Instant),
+    AllNotesOff(Instant),
+    SetVoiceCount(usize),
+    SetVoiceStealing(VoiceStealing),
+    SetFakeDelay(u64),
+    SetVoiceRendering(VoiceRendering),
+    SetWaveform(Waveform),
+    SetPulseWidth(f32),
+    SetEnvelope(EnvelopeSettings),
+    SetFilter(FilterSettings),
+    SetLfo(usize, LfoSettings),
+    SetModRoute(usize, ModRoute),
+    SetPatch(Patch),
+    SetTempo(f32),
+    SetModWheel(f32, Instant),
+    SetPitchBend(f32, Instant),
+}
+
+/// A snapshot of the [Synthesizer]'s parameters, for the app to display.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SynthesizerStatus {
+    pub sample_rate: usize,
+    pub active_notes: Vec<u8>,
+    pub voice_count: usize,
+    pub voice_stealing: VoiceStealing,
+    pub is_playing: bool,
+    pub fake_delay: u64,
+    pub voice_rendering: VoiceRendering,
+    pub patch: Patch,
+    pub tempo: f32,
+    pub mod_wheel: f32,
+    pub pitch_bend: f32,
+
+    // See [Synthesizer::frames_per_second].
+    pub frames_per_second: Option<f64>,
+}
+impl SynthesizerStatus {
+    fn new_from(synthesizer: &Synthesizer) -> Self {
+        Self {
+            sample_rate: synthesizer.sample_rate,
+            active_notes: synthesizer.active_notes(),
+            voice_count: synthesizer.voice_count(),
+            voice_stealing: synthesizer.voice_stealing(),
+            is_playing: synthesizer.is_playing(),
+            fake_delay: synthesizer.fake_delay(),
+            voice_rendering: synthesizer.voice_rendering(),
+            patch: synthesizer.patch().clone(),
+            tempo: synthesizer.tempo(),
+            mod_wheel: synthesizer.mod_wheel(),
+            pitch_bend: synthesizer.pitch_bend(),
+            frames_per_second: synthesizer.frames_per_second(),
+        }
+    }
+}
+
+/// What happened to the [AudioQueue] when the render thread answered one
+/// [AudioInterfaceEvent::NeedsAudio].
+#[derive(Clone, Copy, Debug)]
+pub struct QueueActivity {
+    // When the stream callback asked, and how many samples it asked for.
+    pub requested_at: Instant,
+    pub requested: usize,
+
+    // When the render thread got the request.
+    pub received_at: Instant,
+
+    // When the render thread finished pushing them, and how full the queue
+    // was then.
+    pub filled_at: Instant,
+    pub len_after: usize,
+
+    // The queue's capacity, which changes with the buffer size.
+    pub capacity: usize,
+}
+
+#[derive(Debug)]
+pub struct RenderThread {}
+impl RenderThread {
+    /// How often the render thread tells the app how the synthesizer is doing
+    /// while it's working, so that the throughput numbers stay current.
+    const STATUS_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// How often the render thread sends the app what it's collected for
+    /// [AudioInterfaceEvent::QueueActivity]. Sending each one as it happens
+    /// would mean an app update for every stream callback.
+    const ACTIVITY_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Starts the render thread. It reads the audio stream's events from
+    /// `stream_events` and passes the ones it doesn't consume to
+    /// `event_sender`. It exits after passing along
+    /// [AudioInterfaceEvent::Quit], or when the audio stream goes away.
+    pub fn start(
+        input_receiver: Receiver<SynthesizerInput>,
+        stream_events: Receiver<AudioInterfaceEvent>,
+        event_sender: Sender<AudioInterfaceEvent>,
+    ) -> JoinHandle<()> {
+        std::thread::Builder::new()
+            .name("render".to_string())
+            .spawn(move || Self::run(input_receiver, stream_events, event_sender))
+            .expect("spawning the render thread")
+    }
+
+    fn run(
+        input_receiver: Receiver<SynthesizerInput>,
+        stream_events: Receiver<AudioInterfaceEvent>,
+        event_sender: Sender<AudioInterfaceEvent>,
+    ) {
+        // We don't know the sample rate until the first Reset.
+        let mut synthesizer = Synthesizer::new_with(0);
+        let mut queue: Option<AudioQueue> = None;
+        let mut last_status = Instant::now();
+        let mut activity = Vec::default();
+        let mut last_activity = Instant::now();
+        loop {
+            select! {
+                recv(stream_events) -> event => {
+                    let Ok(event) = event else {
+                        break;
+                    };
+                    match event {
+                        AudioInterfaceEvent::NeedsAudio(when, count) => {
+                            if let Some(queue) = &queue {
+                                let received_at = Instant::now();
+                                if let Err(e) = synthesizer.generate_audio(count, queue.clone(), received_at) {
+                                    let _ = event_sender.send(AudioInterfaceEvent::Error(e.into()));
+                                }
+                                activity.push(QueueActivity {
+                                    requested_at: when,
+                                    requested: count,
+                                    received_at,
+                                    filled_at: Instant::now(),
+                                    len_after: queue.len(),
+                                    capacity: queue.capacity(),
+                                });
+                            }
+                            if last_activity.elapsed() >= Self::ACTIVITY_INTERVAL {
+                                last_activity = Instant::now();
+                                let _ = event_sender.send(AudioInterfaceEvent::QueueActivity(
+                                    std::mem::take(&mut activity),
+                                ));
+                            }
+                            if last_status.elapsed() >= Self::STATUS_INTERVAL {
+                                last_status = Instant::now();
+                                let _ = event_sender.send(AudioInterfaceEvent::Synthesizer(
+                                    SynthesizerStatus::new_from(&synthesizer),
+                                ));
+                            }
+                        }
+                        AudioInterfaceEvent::Reset(ref description, ref new_queue) => {
+                            // A Reset might just mean that the queue was
+                            // replaced. Either way, the synthesizer can keep
+                            // going as it was.
+                            let sample_rate = description.sample_rate as usize;
+                            if synthesizer.sample_rate != sample_rate {
+                                synthesizer.set_sample_rate(sample_rate);
+                                let _ = event_sender.send(AudioInterfaceEvent::Synthesizer(
+                                    SynthesizerStatus::new_from(&synthesizer),
+                                ));
+                            }
+                            queue = Some(new_queue.clone());
+                            let _ = event_sender.send(event);
+                        }
+                        AudioInterfaceEvent::Quit => {
+                            let _ = event_sender.send(event);
+                            break;
+                        }
+                        _ => {
+                            let _ = event_sender.send(event);
+                        }
+                    }
+                }
+                recv(input_receiver) -> input => {
+                    let Ok(input) = input else {
+                        break;
+                    };
+                    match input {
+                        SynthesizerInput::Play => synthesizer.play(),
+                        SynthesizerInput::Pause => synthesizer.pause(),
+                        SynthesizerInput::NoteOn(note, velocity, when) => {
+                            synthesizer.schedule(when, TimedEvent::NoteOn(note, velocity))
+                        }
+                        SynthesizerInput::NoteOff(note, when) => {
+                            synthesizer.schedule(when, TimedEvent::NoteOff(note))
+                        }
+                        SynthesizerInput::AllNotesOff(when) => {
+                            synthesizer.schedule(when, TimedEvent::AllNotesOff)
+                        }
+                        SynthesizerInput::SetVoiceCount(voice_count) => {
+                            synthesizer.set_voice_count(voice_count)
+                        }
+                        SynthesizerInput::SetVoiceStealing(voice_stealing) => {
+                            synthesizer.set_voice_stealing(voice_stealing)
+                        }
+                        SynthesizerInput::SetEnvelope(envelope) => {
+                            synthesizer.set_envelope(envelope)
+                        }
+                        SynthesizerInput::SetFilter(filter) => synthesizer.set_filter(filter),
+                        SynthesizerInput::SetFakeDelay(fake_delay) => {
+                            synthesizer.set_fake_delay(fake_delay)
+                        }
+                        SynthesizerInput::SetVoiceRendering(voice_rendering) => {
+                            synthesizer.set_voice_rendering(voice_rendering)
+                        }
+                        SynthesizerInput::SetWaveform(waveform) => {
+                            synthesizer.set_waveform(waveform)
+                        }
+                        SynthesizerInput::SetPulseWidth(pulse_width) => {
+                            synthesizer.set_pulse_width(pulse_width)
+                        }
+                        SynthesizerInput::SetLfo(index, lfo) => synthesizer.set_lfo(index, lfo),
+                        SynthesizerInput::SetModRoute(index, route) => {
+                            synthesizer.set_mod_route(index, route)
+                        }
+                        SynthesizerInput::SetPatch(patch) => synthesizer.set_patch(&patch),
+                        SynthesizerInput::SetTempo(tempo) => synthesizer.set_tempo(tempo),
+                        SynthesizerInput::SetModWheel(mod_wheel, when) => {
+                            synthesizer.schedule(when, TimedEvent::SetModWheel(mod_wheel))
+                        }
+                        SynthesizerInput::SetPitchBend(pitch_bend, when) => {
+                            synthesizer.schedule(when, TimedEvent::SetPitchBend(pitch_bend))
+                        }
+                    }
+                    let _ = event_sender.send(AudioInterfaceEvent::Synthesizer(
+                        SynthesizerStatus::new_from(&synthesizer),
+                    ));
+                }
+            }
+        }
+    }
+}
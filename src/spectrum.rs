@@ -0,0 +1,486 @@
+//! A spectrum analyzer that shows what the audio stream is actually playing,
+//! for checking what the synthesizer's waveforms and filters do to the sound.
+
+use crate::stream::StereoSample;
+use iced::{
+    widget::canvas::{self, Cursor, Frame, Geometry, Path, Stroke, Text},
+    Color, Point, Rectangle, Theme,
+};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    sync::Arc,
+};
+
+/// The window function that [SpectrumAnalyzer] applies before each
+/// transform, trading how sharp peaks look against how far they leak into
+/// their neighbors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpectrumWindow {
+    // No window at all. The sharpest peaks, but anything that isn't exactly
+    // on a bin leaks across the whole spectrum.
+    Rectangular,
+
+    // A good all-round choice.
+    #[default]
+    Hann,
+
+    // Like Hann, but with a lower first sidelobe and a slower falloff after
+    // it.
+    Hamming,
+
+    // Wider peaks, but with leakage down below 90 dB, for spotting quiet
+    // aliasing among loud harmonics.
+    BlackmanHarris,
+}
+impl SpectrumWindow {
+    pub const ALL: [SpectrumWindow; 4] = [
+        SpectrumWindow::Rectangular,
+        SpectrumWindow::Hann,
+        SpectrumWindow::Hamming,
+        SpectrumWindow::BlackmanHarris,
+    ];
+
+    /// The window's value at sample `n` of `len`.
+    fn coefficient(&self, n: usize, len: usize) -> f32 {
+        let x = 2.0 * std::f32::consts::PI * n as f32 / (len - 1) as f32;
+        match self {
+            SpectrumWindow::Rectangular => 1.0,
+            SpectrumWindow::Hann => 0.5 - 0.5 * x.cos(),
+            SpectrumWindow::Hamming => 0.54 - 0.46 * x.cos(),
+            SpectrumWindow::BlackmanHarris => {
+                0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() - 0.01168 * (3.0 * x).cos()
+            }
+        }
+    }
+}
+impl Display for SpectrumWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SpectrumWindow::Rectangular => "Rectangular",
+            SpectrumWindow::Hann => "Hann",
+            SpectrumWindow::Hamming => "Hamming",
+            SpectrumWindow::BlackmanHarris => "Blackman-Harris",
+        })
+    }
+}
+
+/// Transforms the most recent samples from a
+/// [crate::oscilloscope::ScopeTap] whenever new ones arrive, and draws the
+/// result on a canvas with a logarithmic frequency axis. The transforms run
+/// on the app's thread, never the audio thread.
+pub struct SpectrumAnalyzer {
+    is_enabled: bool,
+    window: SpectrumWindow,
+    is_holding_peaks: bool,
+
+    // Of the samples we last transformed. Zero until we've seen a stream.
+    sample_rate: usize,
+
+    // The most recent samples, mixed to mono. Newest at the back.
+    history: VecDeque<f32>,
+
+    fft: Arc<dyn Fft<f32>>,
+
+    // The window's coefficients, and their sum, which is what it takes to
+    // bring a full-scale sine wave to 0 dB whatever the window.
+    coefficients: Vec<f32>,
+    coefficient_sum: f32,
+
+    // Reused by every transform.
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+
+    // The level of each bin up to Nyquist, in dB relative to full scale.
+    levels: Vec<f32>,
+
+    // The highest level each bin has reached since the peaks were reset.
+    peaks: Vec<f32>,
+}
+impl Debug for SpectrumAnalyzer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpectrumAnalyzer")
+            .field("is_enabled", &self.is_enabled)
+            .field("window", &self.window)
+            .field("is_holding_peaks", &self.is_holding_peaks)
+            .field("sample_rate", &self.sample_rate)
+            .field("history", &"(skipped)")
+            .field("fft", &"(skipped)")
+            .field("coefficients", &"(skipped)")
+            .field("coefficient_sum", &self.coefficient_sum)
+            .field("buffer", &"(skipped)")
+            .field("scratch", &"(skipped)")
+            .field("levels", &"(skipped)")
+            .field("peaks", &"(skipped)")
+            .finish()
+    }
+}
+impl Default for SpectrumAnalyzer {
+    fn default() -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(Self::FFT_SIZE);
+        let scratch = vec![Complex::default(); fft.get_inplace_scratch_len()];
+        let mut r = Self {
+            is_enabled: false,
+            window: SpectrumWindow::default(),
+            is_holding_peaks: false,
+            sample_rate: 0,
+            history: VecDeque::with_capacity(Self::FFT_SIZE),
+            fft,
+            coefficients: Vec::default(),
+            coefficient_sum: 0.0,
+            buffer: vec![Complex::default(); Self::FFT_SIZE],
+            scratch,
+            levels: vec![Self::FLOOR_DB; Self::BIN_COUNT],
+            peaks: vec![Self::FLOOR_DB; Self::BIN_COUNT],
+        };
+        r.set_window(r.window);
+        r
+    }
+}
+impl SpectrumAnalyzer {
+    /// About 93 milliseconds at 44.1KHz, for bins about 11Hz wide, which is
+    /// enough to separate neighboring semitones down to around 200Hz.
+    const FFT_SIZE: usize = 4096;
+    const BIN_COUNT: usize = Self::FFT_SIZE / 2;
+
+    /// The range we display.
+    const MIN_FREQUENCY: f32 = 20.0;
+    const MAX_FREQUENCY: f32 = 20000.0;
+    const FLOOR_DB: f32 = -120.0;
+
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled
+    }
+
+    /// Shows or hides the analyzer. Whatever we had is stale either way.
+    pub fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+        self.history.clear();
+        self.levels.fill(Self::FLOOR_DB);
+        self.reset_peaks();
+    }
+
+    pub fn window(&self) -> SpectrumWindow {
+        self.window
+    }
+
+    /// Changes the window. Peaks held under the old one would be misleading,
+    /// so they're reset.
+    pub fn set_window(&mut self, window: SpectrumWindow) {
+        self.window = window;
+        self.coefficients = (0..Self::FFT_SIZE)
+            .map(|n| window.coefficient(n, Self::FFT_SIZE))
+            .collect();
+        self.coefficient_sum = self.coefficients.iter().sum();
+        self.reset_peaks();
+    }
+
+    pub fn is_holding_peaks(&self) -> bool {
+        self.is_holding_peaks
+    }
+
+    pub fn set_holding_peaks(&mut self, is_holding_peaks: bool) {
+        self.is_holding_peaks = is_holding_peaks;
+        self.reset_peaks();
+    }
+
+    pub fn reset_peaks(&mut self) {
+        self.peaks.fill(Self::FLOOR_DB);
+    }
+
+    /// Adds what the stream has played at `sample_rate` since last time, from
+    /// [crate::oscilloscope::ScopeTap::take], and transforms the newest
+    /// samples once there are enough of them.
+    pub fn update(&mut self, samples: &[StereoSample], sample_rate: usize) {
+        if samples.is_empty() || sample_rate == 0 {
+            return;
+        }
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.history.clear();
+            self.reset_peaks();
+        }
+        for sample in samples {
+            if self.history.len() == Self::FFT_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back((sample.left + sample.right) / 2.0);
+        }
+        if self.history.len() < Self::FFT_SIZE {
+            return;
+        }
+
+        for (slot, (sample, coefficient)) in self
+            .buffer
+            .iter_mut()
+            .zip(self.history.iter().zip(&self.coefficients))
+        {
+            *slot = Complex::new(sample * coefficient, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        // Each real sine shows up half in its positive-frequency bin and half
+        // in its mirror image, hence the 2.
+        let scale = 2.0 / self.coefficient_sum;
+        for (level, bin) in self.levels.iter_mut().zip(&self.buffer) {
+            *level = 20.0 * (bin.norm() * scale).max(f32::MIN_POSITIVE).log10();
+        }
+        if self.is_holding_peaks {
+            for (peak, level) in self.peaks.iter_mut().zip(&self.levels) {
+                *peak = peak.max(*level);
+            }
+        }
+    }
+
+    /// The frequency at the center of `bin`.
+    fn bin_frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate as f32 / Self::FFT_SIZE as f32
+    }
+
+    /// The highest frequency we can show.
+    fn max_frequency(&self) -> f32 {
+        (self.sample_rate as f32 / 2.0).min(Self::MAX_FREQUENCY)
+    }
+
+    /// Where `frequency` goes across a frame `width` wide.
+    fn x(&self, frequency: f32, width: f32) -> f32 {
+        width * (frequency / Self::MIN_FREQUENCY).ln()
+            / (self.max_frequency() / Self::MIN_FREQUENCY).ln()
+    }
+
+    /// Where `db` goes down a frame `height` high.
+    fn y(db: f32, height: f32) -> f32 {
+        height * (db / Self::FLOOR_DB).clamp(0.0, 1.0)
+    }
+
+    /// A line through the level of every bin that's within the range we
+    /// display.
+    fn trace(&self, levels: &[f32], frame: &Frame) -> Path {
+        let max_frequency = self.max_frequency();
+        Path::new(|builder| {
+            let mut is_first = true;
+            for (i, level) in levels.iter().enumerate().skip(1) {
+                let frequency = self.bin_frequency(i);
+                if frequency < Self::MIN_FREQUENCY {
+                    continue;
+                }
+                if frequency > max_frequency {
+                    break;
+                }
+                let point = Point::new(
+                    self.x(frequency, frame.width()),
+                    Self::y(*level, frame.height()),
+                );
+                if is_first {
+                    builder.move_to(point);
+                    is_first = false;
+                } else {
+                    builder.line_to(point);
+                }
+            }
+        })
+    }
+}
+impl<Message> canvas::Program<Message> for SpectrumAnalyzer {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &Self::State,
+        theme: &Theme,
+        bounds: Rectangle,
+        _cursor: Cursor,
+    ) -> Vec<Geometry> {
+        let palette = theme.palette();
+        let mut frame = Frame::new(bounds.size());
+        if self.sample_rate == 0 {
+            return vec![frame.into_geometry()];
+        }
+
+        // A faint line for each decade and every 20 dB, with labels.
+        let grid_color = Color {
+            a: 0.25,
+            ..palette.text
+        };
+        let (width, height) = (frame.width(), frame.height());
+        let decades: Vec<f32> = [100.0, 1000.0, 10000.0]
+            .into_iter()
+            .filter(|frequency| *frequency < self.max_frequency())
+            .collect();
+        let grid = Path::new(|builder| {
+            for frequency in &decades {
+                let x = self.x(*frequency, width);
+                builder.move_to(Point::new(x, 0.0));
+                builder.line_to(Point::new(x, height));
+            }
+            for db in (1..6).map(|i| i as f32 * -20.0) {
+                let y = Self::y(db, height);
+                builder.move_to(Point::new(0.0, y));
+                builder.line_to(Point::new(width, y));
+            }
+        });
+        frame.stroke(&grid, Stroke::default().with_color(grid_color));
+        for frequency in &decades {
+            frame.fill_text(Text {
+                content: if *frequency >= 1000.0 {
+                    format!("{}k", frequency / 1000.0)
+                } else {
+                    format!("{}", frequency)
+                },
+                position: Point::new(self.x(*frequency, width) + 2.0, height - 14.0),
+                color: grid_color,
+                size: 12.0,
+                ..Text::default()
+            });
+        }
+        for db in (1..6).map(|i| i as f32 * -20.0) {
+            frame.fill_text(Text {
+                content: format!("{} dB", db),
+                position: Point::new(2.0, Self::y(db, height) + 2.0),
+                color: grid_color,
+                size: 12.0,
+                ..Text::default()
+            });
+        }
+
+        if self.is_holding_peaks {
+            frame.stroke(
+                &self.trace(&self.peaks, &frame),
+                Stroke::default().with_color(Color {
+                    a: 0.6,
+                    ..palette.danger
+                }),
+            );
+        }
+        frame.stroke(
+            &self.trace(&self.levels, &frame),
+            Stroke::default()
+                .with_color(palette.primary)
+                .with_width(1.5),
+        );
+        vec![frame.into_geometry()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough of a full-scale sine at `frequency` to fill the history.
+    fn sine(frequency: f32, sample_rate: usize) -> Vec<StereoSample> {
+        (0..SpectrumAnalyzer::FFT_SIZE)
+            .map(|n| {
+                let value =
+                    (2.0 * std::f32::consts::PI * frequency * n as f32 / sample_rate as f32).sin();
+                StereoSample {
+                    left: value,
+                    right: value,
+                }
+            })
+            .collect()
+    }
+
+    /// The bin with the highest level.
+    fn loudest_bin(levels: &[f32]) -> usize {
+        (0..levels.len())
+            .max_by(|a, b| levels[*a].total_cmp(&levels[*b]))
+            .unwrap()
+    }
+
+    #[test]
+    fn bins_map_to_frequencies() {
+        let mut analyzer = SpectrumAnalyzer::default();
+        for sample_rate in [44100, 48000, 96000] {
+            analyzer.update(&sine(1000.0, sample_rate), sample_rate);
+            assert_eq!(analyzer.bin_frequency(0), 0.0);
+            assert_eq!(
+                analyzer.bin_frequency(SpectrumAnalyzer::BIN_COUNT),
+                sample_rate as f32 / 2.0
+            );
+
+            // A sine lands in the bin nearest its frequency.
+            for frequency in [100.0, 1000.0, 10000.0] {
+                analyzer.update(&sine(frequency, sample_rate), sample_rate);
+                let bin = loudest_bin(&analyzer.levels);
+                let bin_width = analyzer.bin_frequency(1);
+                assert!(
+                    (analyzer.bin_frequency(bin) - frequency).abs() <= bin_width / 2.0,
+                    "{} Hz at {} Hz went to bin {}",
+                    frequency,
+                    sample_rate,
+                    bin
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn full_scale_sine_reads_zero_db() {
+        let sample_rate = 48000;
+        for window in SpectrumWindow::ALL {
+            let mut analyzer = SpectrumAnalyzer::default();
+            analyzer.set_window(window);
+
+            // Right on a bin, so that nothing leaks.
+            let bin = 100;
+            let frequency = bin as f32 * sample_rate as f32 / SpectrumAnalyzer::FFT_SIZE as f32;
+            analyzer.update(&sine(frequency, sample_rate), sample_rate);
+            assert_eq!(loudest_bin(&analyzer.levels), bin, "{}", window);
+            assert!(
+                analyzer.levels[bin].abs() < 0.1,
+                "{}: {} dB",
+                window,
+                analyzer.levels[bin]
+            );
+        }
+    }
+
+    #[test]
+    fn waits_for_a_full_history() {
+        let mut analyzer = SpectrumAnalyzer::default();
+        let samples = sine(1000.0, 48000);
+        analyzer.update(&samples[..100], 48000);
+        assert!(analyzer
+            .levels
+            .iter()
+            .all(|l| *l == SpectrumAnalyzer::FLOOR_DB));
+
+        // A new sample rate starts the history over.
+        analyzer.update(&samples[100..], 44100);
+        assert!(analyzer
+            .levels
+            .iter()
+            .all(|l| *l == SpectrumAnalyzer::FLOOR_DB));
+    }
+
+    #[test]
+    fn holds_peaks() {
+        let sample_rate = 48000;
+        let mut analyzer = SpectrumAnalyzer::default();
+        analyzer.set_holding_peaks(true);
+        analyzer.update(&sine(1000.0, sample_rate), sample_rate);
+        let bin = loudest_bin(&analyzer.levels);
+        let level = analyzer.levels[bin];
+
+        analyzer.update(&sine(5000.0, sample_rate), sample_rate);
+        assert!(analyzer.levels[bin] < level - 40.0);
+        assert_eq!(analyzer.peaks[bin], level);
+
+        analyzer.reset_peaks();
+        assert_eq!(analyzer.peaks[bin], SpectrumAnalyzer::FLOOR_DB);
+    }
+
+    #[test]
+    fn axis_spans_displayed_range() {
+        let mut analyzer = SpectrumAnalyzer::default();
+        analyzer.update(&sine(1000.0, 48000), 48000);
+        assert_eq!(analyzer.x(SpectrumAnalyzer::MIN_FREQUENCY, 500.0), 0.0);
+        assert!((analyzer.x(20000.0, 500.0) - 500.0).abs() < 1e-3);
+
+        // Nyquist is the limit when it's lower.
+        analyzer.update(&sine(1000.0, 22050), 22050);
+        assert!((analyzer.x(11025.0, 500.0) - 500.0).abs() < 1e-3);
+    }
+}
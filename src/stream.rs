@@ -1,28 +1,306 @@
-use crate::subscription::AudioInterfaceEvent;
+use crate::{
+    channels::{ChannelMap, ExtraChannels},
+    devices::{
+        find_output_device, negotiate_output_config, output_device_id, OutputDevice,
+        OutputDeviceId, StreamConfigRequest, StreamDescription,
+    },
+    null_backend::NullStream,
+    oscilloscope::ScopeTap,
+    subscription::AudioInterfaceEvent,
+    voice_pool::VoicePanic,
+    wav::{Recorder, RecordingTap, WavFormat},
+};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    FromSample, Sample, SizedSample, Stream, SupportedStreamConfig,
+    FromSample, Sample, SizedSample, Stream,
 };
 use crossbeam::queue::ArrayQueue;
-use crossbeam_channel::Sender;
-use std::{fmt::Debug, result::Result::Ok, sync::Arc, time::Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::{
+    fmt::{Debug, Display},
+    path::Path,
+    result::Result::Ok,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Everything that can go wrong while setting up or running an
+/// [AudioStream]. cpal's own error types aren't Clone, which Iced needs for
+/// anything that travels in a message, so we keep only their descriptions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioStreamError {
+    // There's no output device to play on. The string says which device we
+    // were looking for.
+    NoDevice(String),
+
+    // The device can't be configured the way we need.
+    UnsupportedConfig(String),
+
+    // cpal couldn't build a stream with the config we negotiated.
+    BuildFailed(String),
+
+    PlayFailed(String),
+    PauseFailed(String),
+
+    // Something went wrong writing a recording to disk.
+    RecordingFailed(String),
 
-#[derive(Debug, Default)]
+    // A running stream's device went away, e.g., because it was unplugged.
+    DeviceNotAvailable,
+
+    // Any other error that the backend reported on a running stream.
+    Backend(String),
+
+    // A voice worker panicked while rendering, so every note was cut off.
+    VoicePanicked,
+}
+impl Display for AudioStreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioStreamError::NoDevice(which) => write!(f, "No output device: {}", which),
+            AudioStreamError::UnsupportedConfig(e) => write!(f, "Unsupported config: {}", e),
+            AudioStreamError::BuildFailed(e) => write!(f, "Couldn't build stream: {}", e),
+            AudioStreamError::PlayFailed(e) => write!(f, "Couldn't play stream: {}", e),
+            AudioStreamError::PauseFailed(e) => write!(f, "Couldn't pause stream: {}", e),
+            AudioStreamError::RecordingFailed(e) => write!(f, "Couldn't record: {}", e),
+            AudioStreamError::DeviceNotAvailable => {
+                write!(f, "Output device is no longer available")
+            }
+            AudioStreamError::Backend(e) => write!(f, "Audio backend error: {}", e),
+            AudioStreamError::VoicePanicked => {
+                write!(f, "A voice worker crashed, so every note was stopped")
+            }
+        }
+    }
+}
+impl std::error::Error for AudioStreamError {}
+impl From<cpal::HostUnavailable> for AudioStreamError {
+    fn from(e: cpal::HostUnavailable) -> Self {
+        AudioStreamError::NoDevice(e.to_string())
+    }
+}
+impl From<cpal::DevicesError> for AudioStreamError {
+    fn from(e: cpal::DevicesError) -> Self {
+        AudioStreamError::NoDevice(e.to_string())
+    }
+}
+impl From<cpal::DeviceNameError> for AudioStreamError {
+    fn from(e: cpal::DeviceNameError) -> Self {
+        AudioStreamError::NoDevice(e.to_string())
+    }
+}
+impl From<cpal::DefaultStreamConfigError> for AudioStreamError {
+    fn from(e: cpal::DefaultStreamConfigError) -> Self {
+        AudioStreamError::UnsupportedConfig(e.to_string())
+    }
+}
+impl From<cpal::BuildStreamError> for AudioStreamError {
+    fn from(e: cpal::BuildStreamError) -> Self {
+        AudioStreamError::BuildFailed(e.to_string())
+    }
+}
+impl From<cpal::PlayStreamError> for AudioStreamError {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        AudioStreamError::PlayFailed(e.to_string())
+    }
+}
+impl From<cpal::PauseStreamError> for AudioStreamError {
+    fn from(e: cpal::PauseStreamError) -> Self {
+        AudioStreamError::PauseFailed(e.to_string())
+    }
+}
+impl From<hound::Error> for AudioStreamError {
+    fn from(e: hound::Error) -> Self {
+        AudioStreamError::RecordingFailed(e.to_string())
+    }
+}
+impl From<VoicePanic> for AudioStreamError {
+    fn from(_: VoicePanic) -> Self {
+        AudioStreamError::VoicePanicked
+    }
+}
+impl From<cpal::StreamError> for AudioStreamError {
+    fn from(e: cpal::StreamError) -> Self {
+        match e {
+            cpal::StreamError::DeviceNotAvailable => AudioStreamError::DeviceNotAvailable,
+            cpal::StreamError::BackendSpecific { err } => {
+                AudioStreamError::Backend(err.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
 pub struct StereoSample {
     pub left: f32,
     pub right: f32,
 }
 
+/// Describes a cpal callback that found the queue empty before it had filled
+/// its buffer.
+#[derive(Clone, Debug)]
+pub struct UnderrunReport {
+    // When the callback noticed.
+    pub when: Instant,
+
+    // How many frames the callback had to fill with silence.
+    pub frames_zero_filled: usize,
+}
+
+/// How loud the stream played over a stretch of callbacks, for each of the
+/// left and right channels, measured before conversion to the device's
+/// sample format.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LevelReport {
+    // How many frames this covers.
+    pub frames: usize,
+
+    // The largest absolute sample value.
+    pub peak: [f32; 2],
+
+    // The sum of every sample squared, for working out the RMS level.
+    pub sum_of_squares: [f32; 2],
+
+    // How many samples went beyond ±1.0. Integer formats clamp these, and
+    // float formats leave it to the device, which is usually no better.
+    pub clipped_samples: [usize; 2],
+}
+impl LevelReport {
+    fn add(&mut self, sample: &StereoSample) {
+        for (channel, value) in [sample.left, sample.right].into_iter().enumerate() {
+            let magnitude = value.abs();
+            self.peak[channel] = self.peak[channel].max(magnitude);
+            self.sum_of_squares[channel] += value * value;
+            if magnitude > 1.0 {
+                self.clipped_samples[channel] += 1;
+            }
+        }
+        self.frames += 1;
+    }
+}
+
+/// Where the stream callback leaves its [LevelReport] for the audio-stream
+/// thread to pick up, so that the callback never has to send it anywhere
+/// itself. Like [ScopeTap], it never blocks the callback.
+#[derive(Debug)]
+pub struct LevelSlot {
+    // Holds at most one report.
+    report: ArrayQueue<LevelReport>,
+}
+impl Default for LevelSlot {
+    fn default() -> Self {
+        Self {
+            report: ArrayQueue::new(1),
+        }
+    }
+}
+impl LevelSlot {
+    /// Called from the stream callback. If the last report hasn't been picked
+    /// up yet, `report` comes back, so that the callback can keep adding to
+    /// it and nothing goes unmeasured.
+    fn offer(&self, report: LevelReport) -> Result<(), LevelReport> {
+        self.report.push(report)
+    }
+
+    /// Everything the stream has played since the last time we asked, if
+    /// it's played anything.
+    pub fn take(&self) -> Option<LevelReport> {
+        self.report.pop()
+    }
+}
+
+/// When a stream callback ran, and when the device expects to play what it
+/// wrote, both measured from the same arbitrary point on the stream's clock.
+#[derive(Clone, Copy, Debug)]
+pub struct CallbackTimestamp {
+    pub callback: Duration,
+    pub playback: Duration,
+}
+impl CallbackTimestamp {
+    /// Converts cpal's timestamps, which can only be compared with each
+    /// other, to durations since `origin`.
+    fn new_from(timestamp: &cpal::OutputStreamTimestamp, origin: &cpal::StreamInstant) -> Self {
+        Self {
+            callback: timestamp
+                .callback
+                .duration_since(origin)
+                .unwrap_or_default(),
+            playback: timestamp
+                .playback
+                .duration_since(origin)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// How one stream callback's timing looked, according to the stream's
+/// [CallbackTimestamp]s.
+#[derive(Clone, Copy, Debug)]
+pub struct CallbackTiming {
+    // The time since the previous callback, and how far that was from the
+    // time it took to play what the previous callback wrote. None for the
+    // first callback.
+    pub interval: Option<Duration>,
+    pub jitter: Option<Duration>,
+
+    // How long a sample pushed onto the queue right after this callback
+    // would wait to be heard: everything still in the queue has to play
+    // first, and then it has to get through the device.
+    pub output_latency: Duration,
+}
+
+/// A stream that's running on one of our backends. This is what lets
+/// [AudioStream] treat cpal streams and [NullStream]s alike.
+pub trait OutputStream {
+    fn play(&self) -> Result<(), AudioStreamError>;
+    fn pause(&self) -> Result<(), AudioStreamError>;
+}
+impl OutputStream for Stream {
+    fn play(&self) -> Result<(), AudioStreamError> {
+        Ok(StreamTrait::play(self)?)
+    }
+
+    fn pause(&self) -> Result<(), AudioStreamError> {
+        Ok(StreamTrait::pause(self)?)
+    }
+}
+
 /// The producer-consumer queue of stereo samples that the audio stream consumes.
 pub type AudioQueue = Arc<ArrayQueue<StereoSample>>;
 
+/// The parts of an [AudioStream] that its stream is built from, saved before
+/// a change so that we can go back to them if the change doesn't work out.
+struct StreamSettings {
+    device: OutputDevice,
+    request: StreamConfigRequest,
+    description: StreamDescription,
+    extra_channels: ExtraChannels,
+    queue: AudioQueue,
+}
+
 /// Encapsulates the connection to the audio interface.
 pub struct AudioStream {
-    // cpal config describing the current audio stream.
-    config: SupportedStreamConfig,
+    // The device that the stream plays on. We hang onto it so that we can
+    // rebuild the stream later.
+    device: OutputDevice,
+
+    // What the app asked for the last time it told us how the stream should
+    // be configured.
+    request: StreamConfigRequest,
+
+    // What we actually got.
+    description: StreamDescription,
 
-    // The cpal audio stream.
-    stream: Stream,
+    // What to play on channels past the first two.
+    extra_channels: ExtraChannels,
+
+    // The audio stream. This is None only briefly while we're rebuilding it,
+    // if rebuilding it failed, or if its device went away.
+    stream: Option<Box<dyn OutputStream>>,
+
+    // Whether the app last asked us to pause. Remembered so that a rebuilt
+    // stream ends up in the same state as the one it replaced.
+    is_paused: bool,
 
     // The queue of samples that the stream consumes.
     queue: AudioQueue,
@@ -30,14 +308,46 @@ pub struct AudioStream {
     // The sending half of the channel that the audio stream uses to send
     // updates to the subscription.
     sender: Sender<AudioInterfaceEvent>,
+
+    // Errors reported by the stream while it's running. We hold onto the
+    // sending half, too, so that the channel stays connected even when there's
+    // no stream.
+    error_sender: Sender<AudioStreamError>,
+    stream_errors: Receiver<AudioStreamError>,
+
+    // The stream callback leaves a copy of everything it plays here. It's
+    // shared by every stream we build, so a recording can outlive a rebuild.
+    recording_tap: Arc<RecordingTap>,
+
+    // Set while we're recording.
+    recorder: Option<Recorder>,
+
+    // The stream callback leaves another copy here for the app's oscilloscope.
+    scope_tap: Arc<ScopeTap>,
+
+    // And leaves its level reports and timings here. Shared by every stream
+    // we build, like recording_tap.
+    level_slot: Arc<LevelSlot>,
+    callback_timings: Arc<ArrayQueue<CallbackTiming>>,
 }
 impl Debug for AudioStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AudioStream")
-            .field("config", &"(skipped)")
+            .field("device", &"(skipped)")
+            .field("request", &self.request)
+            .field("description", &self.description)
+            .field("extra_channels", &self.extra_channels)
             .field("stream", &"(skipped)")
+            .field("is_paused", &self.is_paused)
             .field("queue", &self.queue)
             .field("sender", &self.sender)
+            .field("error_sender", &self.error_sender)
+            .field("stream_errors", &self.stream_errors)
+            .field("recording_tap", &self.recording_tap)
+            .field("recorder", &self.recorder)
+            .field("scope_tap", &self.scope_tap)
+            .field("level_slot", &self.level_slot)
+            .field("callback_timings", &self.callback_timings)
             .finish()
     }
 }
@@ -53,95 +363,449 @@ impl AudioStream {
     /// samples.
     pub const REASONABLE_BUFFER_SIZE: usize = 2048;
 
+    /// The largest queue we'll make. At 44.1KHz, this is over 20 seconds of
+    /// audio, which is far past being useful for anything but showing what
+    /// too much latency sounds like.
+    pub const MAX_BUFFER_SIZE: usize = 1 << 20;
+
+    /// How many [CallbackTiming]s the stream callback can leave before
+    /// someone picks them up. Past that, it drops new ones rather than make
+    /// room, which would mean allocating. This is a few seconds' worth even
+    /// at small device buffer sizes.
+    const CALLBACK_TIMINGS_CAPACITY: usize = 4096;
+
+    /// Opens a stream on the default host's default output device.
     pub fn create_default_stream(
         buffer_size: usize,
+        request: StreamConfigRequest,
+        scope_tap: &Arc<ScopeTap>,
+        audio_stream_event_sender: Sender<AudioInterfaceEvent>,
+    ) -> Result<Self, AudioStreamError> {
+        let (device, description) = Self::host_device_setup(&request)?;
+        Self::create_stream_with(
+            device,
+            request,
+            description,
+            buffer_size,
+            scope_tap,
+            audio_stream_event_sender,
+        )
+    }
+
+    /// Opens a stream on the given output device.
+    pub fn create_stream_on(
+        id: &OutputDeviceId,
+        buffer_size: usize,
+        request: StreamConfigRequest,
+        scope_tap: &Arc<ScopeTap>,
         audio_stream_event_sender: Sender<AudioInterfaceEvent>,
-    ) -> Result<Self, ()> {
-        if let Ok((_host, device, config)) = Self::host_device_setup() {
-            let queue = Arc::new(ArrayQueue::new(buffer_size));
-            if let Ok(stream) = Self::stream_setup_for(
-                &device,
-                &config,
-                &Arc::clone(&queue),
-                audio_stream_event_sender.clone(),
-            ) {
-                let r = Self {
-                    config,
-                    stream,
-                    queue,
-                    sender: audio_stream_event_sender,
-                };
-                r.send_reset();
-                Ok(r)
-            } else {
-                Err(())
+    ) -> Result<Self, AudioStreamError> {
+        let device = find_output_device(id)?;
+        let description = negotiate_output_config(id, &device, &request)?;
+        Self::create_stream_with(
+            device,
+            request,
+            description,
+            buffer_size,
+            scope_tap,
+            audio_stream_event_sender,
+        )
+    }
+
+    /// Opens a stream on a device that we've already found, with a config
+    /// that we've already negotiated for it.
+    pub fn create_stream_with(
+        device: OutputDevice,
+        request: StreamConfigRequest,
+        description: StreamDescription,
+        buffer_size: usize,
+        scope_tap: &Arc<ScopeTap>,
+        audio_stream_event_sender: Sender<AudioInterfaceEvent>,
+    ) -> Result<Self, AudioStreamError> {
+        let (error_sender, stream_errors) = unbounded();
+        let mut r = Self {
+            device,
+            request,
+            description,
+            extra_channels: ExtraChannels::default(),
+            stream: None,
+            is_paused: false,
+            queue: Self::new_queue(buffer_size),
+            sender: audio_stream_event_sender,
+            error_sender,
+            stream_errors,
+            recording_tap: Arc::new(RecordingTap::default()),
+            recorder: None,
+            scope_tap: Arc::clone(scope_tap),
+            level_slot: Arc::new(LevelSlot::default()),
+            callback_timings: Arc::new(ArrayQueue::new(Self::CALLBACK_TIMINGS_CAPACITY)),
+        };
+        r.stream = Some(r.stream_setup()?);
+        r.send_reset();
+        Ok(r)
+    }
+
+    /// Whether there's a stream playing, or ready to play. This is false after
+    /// a failed rebuild, or once [Self::drop_lost_stream] has given up on a
+    /// stream whose device went away.
+    pub fn has_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Drops a stream whose device has gone away. It will never play again,
+    /// and keeping it around would make [Self::has_stream] say otherwise.
+    pub fn drop_lost_stream(&mut self) {
+        self.stream = None;
+    }
+
+    /// Tells the audio stream to resume playing audio (and consuming samples
+    /// from the queue).
+    pub fn play(&mut self) -> Result<(), AudioStreamError> {
+        self.is_paused = false;
+        if let Some(stream) = &self.stream {
+            stream.play()?;
+        }
+        Ok(())
+    }
+
+    /// Tells the audio stream to stop playing audio (which means it will also
+    /// stop consuming samples from the queue).
+    pub fn pause(&mut self) -> Result<(), AudioStreamError> {
+        self.is_paused = true;
+        if let Some(stream) = &self.stream {
+            stream.pause()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the queue with a new one of the given capacity, and rebuilds
+    /// the stream to consume it. Anything still in the old queue is discarded.
+    /// Sends [AudioInterfaceEvent::Reset] with the new queue so that the
+    /// producer knows where to put samples from now on.
+    ///
+    /// If the new stream can't be built, the old queue and stream are put
+    /// back.
+    pub fn set_buffer_size(&mut self, buffer_size: usize) -> Result<(), AudioStreamError> {
+        let previous = self.settings();
+
+        // Drop the old stream first. Some backends (ALSA hw devices, for
+        // example) won't open a device a second time while it's still in use.
+        self.stream = None;
+
+        self.queue = Self::new_queue(buffer_size);
+        self.rebuild_or_restore(previous)
+    }
+
+    /// Switches playback to the given output device, negotiating a config for
+    /// it from the most recent [StreamConfigRequest]. The queue is replaced
+    /// with a new one of the same capacity because the new device might run at
+    /// a different sample rate, which would make any queued samples wrong.
+    /// Sends [AudioInterfaceEvent::Reset].
+    ///
+    /// If the new device won't play, we go back to the old one, and the
+    /// Reset describes that instead.
+    pub fn set_output_device(&mut self, id: &OutputDeviceId) -> Result<(), AudioStreamError> {
+        let device = find_output_device(id)?;
+        let description = negotiate_output_config(id, &device, &self.request)?;
+
+        let previous = self.settings();
+        self.stream = None;
+        self.device = device;
+        self.description = description;
+        self.queue = Self::new_queue(self.queue.capacity());
+        self.rebuild_or_restore(previous)
+    }
+
+    /// Renegotiates the current device's config according to `request`, and
+    /// rebuilds the stream and queue to match. Sends
+    /// [AudioInterfaceEvent::Reset] describing what we ended up with, which
+    /// might not be exactly what was requested.
+    ///
+    /// If the device won't play the new config, we go back to the old one.
+    pub fn set_stream_config(
+        &mut self,
+        request: StreamConfigRequest,
+    ) -> Result<(), AudioStreamError> {
+        let description =
+            negotiate_output_config(&self.description.device, &self.device, &request)?;
+
+        let previous = self.settings();
+        self.stream = None;
+        self.request = request;
+        self.description = description;
+        self.queue = Self::new_queue(self.queue.capacity());
+        self.rebuild_or_restore(previous)
+    }
+
+    /// Changes what plays on output channels past the first two. This
+    /// rebuilds the stream but keeps the queue. If the rebuild fails, the old
+    /// channel mapping is put back.
+    pub fn set_extra_channels(
+        &mut self,
+        extra_channels: ExtraChannels,
+    ) -> Result<(), AudioStreamError> {
+        let previous = self.settings();
+        self.stream = None;
+        self.extra_channels = extra_channels;
+        self.rebuild_or_restore(previous)
+    }
+
+    fn settings(&self) -> StreamSettings {
+        StreamSettings {
+            device: self.device.clone(),
+            request: self.request.clone(),
+            description: self.description.clone(),
+            extra_channels: self.extra_channels,
+            queue: Arc::clone(&self.queue),
+        }
+    }
+
+    /// Rebuilds the stream after a change to the settings it's built from.
+    /// If that fails, goes back to `previous`, and rebuilds the stream we had
+    /// before, so that a setting the device won't accept doesn't leave us
+    /// without a stream and the render thread filling a queue that nobody
+    /// reads. Either way, a successful rebuild sends
+    /// [AudioInterfaceEvent::Reset] describing the stream we ended up with.
+    fn rebuild_or_restore(&mut self, previous: StreamSettings) -> Result<(), AudioStreamError> {
+        let Err(e) = self.rebuild_stream() else {
+            return Ok(());
+        };
+        self.stream = None;
+        self.device = previous.device;
+        self.request = previous.request;
+        self.description = previous.description;
+        self.extra_channels = previous.extra_channels;
+        self.queue = previous.queue;
+
+        // If even this fails, the caller reports the first error, which is
+        // the one that explains how we got here. The stream stays None, and
+        // has_stream() says so.
+        let _ = self.rebuild_stream();
+        Err(e)
+    }
+
+    /// Builds a new stream from the current device, config, and queue, puts it
+    /// in the same play/pause state as the old one, and announces the change.
+    ///
+    /// If the device turns down a fixed buffer size, we try once more with its
+    /// default buffer size before giving up.
+    fn rebuild_stream(&mut self) -> Result<(), AudioStreamError> {
+        // Whatever the old stream had to say no longer matters.
+        while self.stream_errors.try_recv().is_ok() {}
+
+        let stream = match self.stream_setup() {
+            Ok(stream) => stream,
+            Err(e) => {
+                if self.description.buffer_size == cpal::BufferSize::Default {
+                    return Err(e);
+                }
+                self.description.buffer_size = cpal::BufferSize::Default;
+                self.stream_setup()?
             }
+        };
+        if self.is_paused {
+            stream.pause()?;
         } else {
-            Err(())
+            stream.play()?;
         }
+        self.stream = Some(stream);
+        self.send_reset();
+
+        // A WAV file has only one sample rate.
+        if self
+            .recorder
+            .as_ref()
+            .is_some_and(|r| r.sample_rate() != self.description.sample_rate)
+        {
+            self.stop_recording()?;
+        }
+        Ok(())
     }
 
-    /// Returns the sample rate of the current audio stream.
-    pub fn sample_rate(&self) -> usize {
-        let config: &cpal::StreamConfig = &self.config.clone().into();
-        config.sample_rate.0 as usize
+    /// Starts copying everything the stream plays into a new WAV file at
+    /// `path`, stopping any recording that's already in progress. Sends
+    /// [AudioInterfaceEvent::Recording].
+    pub fn start_recording(
+        &mut self,
+        path: &Path,
+        format: WavFormat,
+    ) -> Result<(), AudioStreamError> {
+        self.stop_recording()?;
+        let recorder = Recorder::start(
+            &self.recording_tap,
+            path,
+            self.description.sample_rate,
+            format,
+        )?;
+        let _ = self.sender.send(AudioInterfaceEvent::Recording(Some(
+            recorder.path().to_path_buf(),
+        )));
+        self.recorder = Some(recorder);
+        Ok(())
     }
 
-    /// Tells the audio stream to stop playing audio (which means it will also
-    /// stop consuming samples from the queue).
-    pub fn play(&self) {
-        let _ = self.stream.play();
+    /// Finishes the current recording, if any. Sends
+    /// [AudioInterfaceEvent::Recording].
+    pub fn stop_recording(&mut self) -> Result<(), AudioStreamError> {
+        if let Some(recorder) = self.recorder.take() {
+            let _ = self.sender.send(AudioInterfaceEvent::Recording(None));
+            recorder.stop()?;
+        }
+        Ok(())
     }
 
-    /// Tells the audio stream to resume playing audio (and consuming samples
-    /// from the queue).
-    pub fn pause(&self) {
-        let _ = self.stream.pause();
+    /// Finishes the current recording if writing it has failed, returning
+    /// why. Sends [AudioInterfaceEvent::Recording] if so.
+    pub fn check_recording(&mut self) -> Result<(), AudioStreamError> {
+        if self.recorder.as_ref().is_some_and(Recorder::has_failed) {
+            self.stop_recording()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Builds a stream from the current device, config, and queue.
+    fn stream_setup(&self) -> Result<Box<dyn OutputStream>, AudioStreamError> {
+        Self::build_output_stream(
+            &self.device,
+            &self.description,
+            StreamCallback {
+                channel_map: ChannelMap::new_with(
+                    self.description.channels as usize,
+                    self.extra_channels,
+                ),
+                queue: Arc::clone(&self.queue),
+                sender: self.sender.clone(),
+                recording_tap: Arc::clone(&self.recording_tap),
+                scope_tap: Arc::clone(&self.scope_tap),
+                sample_rate: self.description.sample_rate,
+                level_slot: Arc::clone(&self.level_slot),
+                levels: LevelReport::default(),
+                last_callback: None,
+                timings: Arc::clone(&self.callback_timings),
+            },
+            self.error_sender.clone(),
+        )
+    }
+
+    /// How loud the stream has played since the last time we asked, for
+    /// [AudioInterfaceEvent::Levels].
+    pub fn take_levels(&self) -> Option<LevelReport> {
+        self.level_slot.take()
+    }
+
+    /// The stream callback's timings since the last time we asked, oldest
+    /// first, for [AudioInterfaceEvent::CallbackTimings].
+    pub fn take_callback_timings(&self) -> Vec<CallbackTiming> {
+        std::iter::from_fn(|| self.callback_timings.pop()).collect()
+    }
+
+    /// Returns the channel on which the current stream reports errors that
+    /// happen while it's running. Errors from streams that have since been
+    /// replaced are discarded.
+    pub fn stream_errors(&self) -> &Receiver<AudioStreamError> {
+        &self.stream_errors
+    }
+
+    /// Moves playback to `device`, e.g., after the current device was
+    /// unplugged. `description` should be negotiated from the most recent
+    /// [StreamConfigRequest], as [Self::host_device_setup] does. The queue is
+    /// replaced because the sample rate may have changed. Sends
+    /// [AudioInterfaceEvent::Reset].
+    pub fn fall_back_to(
+        &mut self,
+        device: OutputDevice,
+        description: StreamDescription,
+    ) -> Result<(), AudioStreamError> {
+        self.stream = None;
+        self.device = device;
+        self.description = description;
+        self.queue = Self::new_queue(self.queue.capacity());
+        self.rebuild_stream()
     }
 
     /// Gives the audio stream a chance to clean up before the thread exits.
     pub fn quit(&mut self) {
+        let _ = self.stop_recording();
         let _ = self.sender.send(AudioInterfaceEvent::Quit);
     }
 
-    /// Returns the default host, device, and stream config (all of which are
-    /// cpal concepts).
-    fn host_device_setup(
-    ) -> anyhow::Result<(cpal::Host, cpal::Device, cpal::SupportedStreamConfig), anyhow::Error>
-    {
+    /// ArrayQueue panics if asked for zero capacity, so we always ask for at
+    /// least one slot, and no more than [Self::MAX_BUFFER_SIZE].
+    fn new_queue(buffer_size: usize) -> AudioQueue {
+        Arc::new(ArrayQueue::new(buffer_size.clamp(1, Self::MAX_BUFFER_SIZE)))
+    }
+
+    /// Returns the default host's default device, along with the stream
+    /// config we negotiated for it.
+    pub fn host_device_setup(
+        request: &StreamConfigRequest,
+    ) -> Result<(OutputDevice, StreamDescription), AudioStreamError> {
         let host = cpal::default_host();
         let device = host
             .default_output_device()
-            .ok_or_else(|| anyhow::Error::msg("Default output device is not available"))?;
-        let config = device.default_output_config()?;
-        Ok((host, device, config))
+            .ok_or_else(|| AudioStreamError::NoDevice("default".to_string()))?;
+        let id = output_device_id(host.id(), &device)?;
+        let device = OutputDevice::Cpal(device);
+        let description = negotiate_output_config(&id, &device, request)?;
+        Ok((device, description))
+    }
+
+    /// Creates a stream on whichever backend the device belongs to.
+    fn build_output_stream(
+        device: &OutputDevice,
+        description: &StreamDescription,
+        callback: StreamCallback,
+        error_sender: Sender<AudioStreamError>,
+    ) -> Result<Box<dyn OutputStream>, AudioStreamError> {
+        Ok(match device {
+            OutputDevice::Cpal(device) => Box::new(Self::stream_setup_for(
+                device,
+                description,
+                callback,
+                error_sender,
+            )?),
+            OutputDevice::Null(device) => Box::new(NullStream::new_with(
+                device,
+                description,
+                callback,
+                error_sender,
+            )?),
+        })
     }
 
     /// Creates and returns a Stream for the given device and config. The Stream
-    /// will consume the supplied ArrayQueue<f32>. This function is actually a
-    /// wrapper around the generic stream_make<T>().
+    /// will run the supplied [StreamCallback], and report errors to
+    /// `error_sender`. This function is actually a wrapper around the generic
+    /// stream_make<T>(), instantiated for whichever sample format the device
+    /// wants.
     fn stream_setup_for(
         device: &cpal::Device,
-        config: &SupportedStreamConfig,
-        queue: &AudioQueue,
-        audio_stream_event_sender: Sender<AudioInterfaceEvent>,
-    ) -> anyhow::Result<Stream, anyhow::Error> {
-        let config = config.clone();
-
-        match config.sample_format() {
-            cpal::SampleFormat::I8 => todo!(),
-            cpal::SampleFormat::I16 => todo!(),
-            cpal::SampleFormat::I32 => todo!(),
-            cpal::SampleFormat::I64 => todo!(),
-            cpal::SampleFormat::U8 => todo!(),
-            cpal::SampleFormat::U16 => todo!(),
-            cpal::SampleFormat::U32 => todo!(),
-            cpal::SampleFormat::U64 => todo!(),
-            cpal::SampleFormat::F32 => {
-                Self::stream_make::<f32>(&config.into(), &device, queue, audio_stream_event_sender)
-            }
-            cpal::SampleFormat::F64 => todo!(),
-            _ => todo!(),
+        description: &StreamDescription,
+        callback: StreamCallback,
+        error_sender: Sender<AudioStreamError>,
+    ) -> Result<Stream, AudioStreamError> {
+        let sample_format = description.sample_format;
+        let config = description.stream_config();
+        let (c, e) = (callback, error_sender);
+
+        match sample_format {
+            cpal::SampleFormat::I8 => Self::stream_make::<i8>(&config, device, c, e),
+            cpal::SampleFormat::I16 => Self::stream_make::<i16>(&config, device, c, e),
+            cpal::SampleFormat::I32 => Self::stream_make::<i32>(&config, device, c, e),
+            cpal::SampleFormat::I64 => Self::stream_make::<i64>(&config, device, c, e),
+            cpal::SampleFormat::U8 => Self::stream_make::<u8>(&config, device, c, e),
+            cpal::SampleFormat::U16 => Self::stream_make::<u16>(&config, device, c, e),
+            cpal::SampleFormat::U32 => Self::stream_make::<u32>(&config, device, c, e),
+            cpal::SampleFormat::U64 => Self::stream_make::<u64>(&config, device, c, e),
+            cpal::SampleFormat::F32 => Self::stream_make::<f32>(&config, device, c, e),
+            cpal::SampleFormat::F64 => Self::stream_make::<f64>(&config, device, c, e),
+            // cpal's SampleFormat is non_exhaustive, so a future version might
+            // hand us something we don't know how to produce.
+            sample_format => Err(AudioStreamError::UnsupportedConfig(format!(
+                "sample format {}",
+                sample_format
+            ))),
         }
     }
 
@@ -149,25 +813,25 @@ impl AudioStream {
     fn stream_make<T>(
         config: &cpal::StreamConfig,
         device: &cpal::Device,
-        queue: &AudioQueue,
-        audio_stream_event_sender: Sender<AudioInterfaceEvent>,
-    ) -> Result<Stream, anyhow::Error>
+        mut callback: StreamCallback,
+        error_sender: Sender<AudioStreamError>,
+    ) -> Result<Stream, AudioStreamError>
     where
         T: SizedSample + FromSample<f32>,
     {
-        let err_fn = |err| eprintln!("Error building output sound stream: {}", err);
+        let err_fn = move |err: cpal::StreamError| {
+            let _ = error_sender.send(err.into());
+        };
 
-        let queue = Arc::clone(&queue);
-        let channel_count = config.channels as usize;
+        // cpal's timestamps are only meaningful relative to each other, so we
+        // measure everything from the first callback.
+        let mut origin = None;
         let stream = device.build_output_stream(
             config,
-            move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
-                Self::on_window(
-                    output,
-                    channel_count,
-                    &queue,
-                    audio_stream_event_sender.clone(),
-                )
+            move |output: &mut [T], info: &cpal::OutputCallbackInfo| {
+                let timestamp = info.timestamp();
+                let origin = origin.get_or_insert(timestamp.callback);
+                callback.on_window(output, CallbackTimestamp::new_from(&timestamp, origin))
             },
             err_fn,
             None,
@@ -175,37 +839,195 @@ impl AudioStream {
         Ok(stream)
     }
 
+    fn send_reset(&self) {
+        let _ = self.sender.send(AudioInterfaceEvent::Reset(
+            self.description.clone(),
+            Arc::clone(&self.queue),
+        ));
+    }
+}
+
+/// Everything the stream's callback needs, bundled up so that it can be moved
+/// into the callback closure.
+pub struct StreamCallback {
+    // How each StereoSample fills a frame.
+    channel_map: ChannelMap,
+
+    // The queue of samples that the stream consumes.
+    queue: AudioQueue,
+
+    // Where to send NeedsAudio and Underrun events.
+    sender: Sender<AudioInterfaceEvent>,
+
+    // Where to leave a copy of every sample we play, in case we're recording.
+    recording_tap: Arc<RecordingTap>,
+
+    // And another, in case the app is showing it.
+    scope_tap: Arc<ScopeTap>,
+
+    sample_rate: u32,
+
+    // Where we leave how loud we've played, and how loud we've played since
+    // we last left a report there.
+    level_slot: Arc<LevelSlot>,
+    levels: LevelReport,
+
+    // When the previous callback ran and how many frames it wrote, and
+    // where we leave the timings we measure.
+    last_callback: Option<(Duration, usize)>,
+    timings: Arc<ArrayQueue<CallbackTiming>>,
+}
+impl StreamCallback {
     /// cpal callback that supplies samples from the ArrayQueue<f32>, converting
-    /// them if needed to the stream's expected data type.
-    fn on_window<T>(
-        output: &mut [T],
-        channel_count: usize,
-        queue: &AudioQueue,
-        audio_stream_event_sender: Sender<AudioInterfaceEvent>,
-    ) where
+    /// them if needed to the stream's expected data type, and spreading them
+    /// across the device's channels according to the [ChannelMap].
+    /// `timestamp` says when the stream called us, and when it will play
+    /// what we write.
+    pub fn on_window<T>(&mut self, output: &mut [T], timestamp: CallbackTimestamp)
+    where
         T: Sample + FromSample<f32>,
     {
-        for frame in output.chunks_exact_mut(channel_count) {
-            let sample = queue.pop().unwrap_or_default();
-            frame[0] = T::from_sample(sample.left);
-            if channel_count > 0 {
-                frame[1] = T::from_sample(sample.right);
+        let mut frames_zero_filled = 0;
+
+        // chunks_exact_mut() panics on zero, and a device with no channels has
+        // nothing for us to do anyway.
+        let channel_count = self.channel_map.channel_count();
+        let frames = output.len().checked_div(channel_count).unwrap_or_default();
+        if channel_count > 0 {
+            for frame in output.chunks_exact_mut(channel_count) {
+                let sample = self.queue.pop().unwrap_or_else(|| {
+                    frames_zero_filled += 1;
+                    StereoSample::default()
+                });
+                self.recording_tap.push(sample);
+                self.scope_tap.push(sample);
+                self.levels.add(&sample);
+                for (slot, source) in frame.iter_mut().zip(self.channel_map.sources()) {
+                    *slot = Self::convert_sample(source.sample_from(&sample));
+                }
             }
         }
-        let capacity = queue.capacity();
-        let len = queue.len();
+        if frames_zero_filled > 0 {
+            let _ = self
+                .sender
+                .send(AudioInterfaceEvent::Underrun(UnderrunReport {
+                    when: Instant::now(),
+                    frames_zero_filled,
+                }));
+        }
+        let capacity = self.queue.capacity();
+        let len = self.queue.len();
         if len < capacity {
-            let _ = audio_stream_event_sender.send(AudioInterfaceEvent::NeedsAudio(
+            let _ = self.sender.send(AudioInterfaceEvent::NeedsAudio(
                 Instant::now(),
                 capacity - len,
             ));
         }
+        if let Err(levels) = self.level_slot.offer(std::mem::take(&mut self.levels)) {
+            self.levels = levels;
+        }
+        self.record_timing(timestamp, frames);
     }
 
-    fn send_reset(&self) {
-        let _ = self.sender.send(AudioInterfaceEvent::Reset(
-            self.sample_rate(),
-            Arc::clone(&self.queue),
-        ));
+    fn record_timing(&mut self, timestamp: CallbackTimestamp, frames: usize) {
+        let sample_rate = self.sample_rate.max(1) as f64;
+        let (interval, jitter) = match self.last_callback {
+            Some((last_callback, last_frames)) => {
+                let interval = timestamp.callback.saturating_sub(last_callback);
+                let expected = Duration::from_secs_f64(last_frames as f64 / sample_rate);
+                (
+                    Some(interval),
+                    Some(interval.max(expected) - interval.min(expected)),
+                )
+            }
+            None => (None, None),
+        };
+        self.last_callback = Some((timestamp.callback, frames));
+        // If nobody's picking these up, the newest ones are dropped.
+        let _ = self.timings.push(CallbackTiming {
+            interval,
+            jitter,
+            output_latency: timestamp.playback.saturating_sub(timestamp.callback)
+                + Duration::from_secs_f64(self.queue.len() as f64 / sample_rate),
+        });
+    }
+
+    /// Converts one of our f32 samples to the stream's sample type. cpal's
+    /// integer conversions assume the input is in [-1.0, 1.0], so we clamp
+    /// first rather than let an overly loud mix wrap around.
+    fn convert_sample<T>(sample: f32) -> T
+    where
+        T: Sample + FromSample<f32>,
+    {
+        T::from_sample(sample.clamp(-1.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::null_backend::{
+        tests::{next_needs_audio, next_reset, open_null_stream_on},
+        NullDevice,
+    };
+
+    #[test]
+    fn failed_rebuild_keeps_previous_stream() {
+        let device = NullDevice::default();
+        let (mut audio_stream, events) =
+            open_null_stream_on(&device, 1024, StreamConfigRequest::default());
+        let (description, queue) = next_reset(&events);
+
+        // Refuse both the requested buffer size and the retry at the device's
+        // default.
+        device.refuse_builds(2);
+        assert!(audio_stream.set_buffer_size(256).is_err());
+
+        assert!(audio_stream.has_stream());
+        let (restored_description, restored_queue) = next_reset(&events);
+        assert_eq!(restored_description, description);
+        assert!(Arc::ptr_eq(&restored_queue, &queue));
+
+        // And the restored stream is still asking for audio from that queue.
+        assert!(next_needs_audio(&events) > 0);
+    }
+
+    #[test]
+    fn extra_channels_survive_rebuilds() {
+        let device = NullDevice::default();
+        let request = StreamConfigRequest {
+            channels: Some(6),
+            ..Default::default()
+        };
+        let (mut audio_stream, events) = open_null_stream_on(&device, 1024, request);
+        next_reset(&events);
+
+        audio_stream.set_extra_channels(ExtraChannels::Mid).unwrap();
+        next_reset(&events);
+        assert_eq!(audio_stream.extra_channels, ExtraChannels::Mid);
+
+        // Other changes rebuild the stream with the setting we already had.
+        audio_stream.set_buffer_size(2048).unwrap();
+        next_reset(&events);
+        assert_eq!(audio_stream.extra_channels, ExtraChannels::Mid);
+
+        // And a change the device refuses leaves it as it was.
+        device.refuse_builds(2);
+        assert!(audio_stream
+            .set_extra_channels(ExtraChannels::RepeatStereo)
+            .is_err());
+        next_reset(&events);
+        assert_eq!(audio_stream.extra_channels, ExtraChannels::Mid);
+        assert!(audio_stream.has_stream());
+    }
+
+    #[test]
+    fn buffer_size_is_capped() {
+        let (mut audio_stream, events) =
+            open_null_stream_on(&NullDevice::default(), 1024, StreamConfigRequest::default());
+        next_reset(&events);
+        audio_stream.set_buffer_size(usize::MAX).unwrap();
+        let (_, queue) = next_reset(&events);
+        assert_eq!(queue.capacity(), AudioStream::MAX_BUFFER_SIZE);
     }
 }
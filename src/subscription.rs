@@ -1,12 +1,35 @@
-use crate::stream::{AudioQueue, AudioStream};
-use crossbeam_channel::{unbounded, Receiver, Select, Sender};
+use crate::{
+    channels::ExtraChannels,
+    devices::{
+        enumerate_output_devices, OutputDevice, OutputDeviceId, OutputDeviceInfo,
+        StreamConfigRequest, StreamDescription,
+    },
+    oscilloscope::ScopeTap,
+    render::{QueueActivity, RenderThread, SynthesizerInput, SynthesizerStatus},
+    stream::{
+        AudioQueue, AudioStream, AudioStreamError, CallbackTiming, LevelReport, UnderrunReport,
+    },
+    wav::WavFormat,
+};
+use crossbeam_channel::{
+    at, never, select, tick, unbounded, Receiver, Select, Sender, TryRecvError,
+};
 use iced::{subscription, Subscription};
-use std::fmt::Debug;
-use std::time::Instant;
+use std::{fmt::Debug, path::PathBuf};
 use std::{result::Result::Ok, thread::JoinHandle};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub enum AudioInterfaceInput {
     SetBufferSize(usize),
+    SetOutputDevice(OutputDeviceId),
+    SetStreamConfig(StreamConfigRequest),
+    SetExtraChannels(ExtraChannels),
+    StartRecording(PathBuf, WavFormat),
+    StopRecording,
+    Synthesizer(SynthesizerInput),
     Play,
     Pause,
     Quit,
@@ -15,109 +38,171 @@ pub enum AudioInterfaceInput {
 #[derive(Clone, Debug)]
 pub enum AudioInterfaceEvent {
     Ready(Sender<AudioInterfaceInput>),
-    Reset(usize, AudioQueue),
+    Reset(StreamDescription, AudioQueue),
+    Devices(Vec<OutputDeviceInfo>),
     NeedsAudio(Instant, usize),
+    Underrun(UnderrunReport),
+
+    // Sent by the audio-stream thread every so often, with what the stream
+    // callback left in its LevelSlot, whether or not anything's playing.
+    Levels(LevelReport),
+
+    // Sent by the audio-stream thread along with Levels, with the timing of
+    // each stream callback since the last one.
+    CallbackTimings(Vec<CallbackTiming>),
+    Error(AudioStreamError),
+
+    // Where we're now recording to, or None if we just stopped.
+    Recording(Option<PathBuf>),
+
+    // Sent by the render thread every so often, with what it did for each
+    // NeedsAudio since the last one.
+    QueueActivity(Vec<QueueActivity>),
+
+    // Sent by the render thread whenever the synthesizer's parameters change.
+    Synthesizer(SynthesizerStatus),
     Quit,
 }
 
 enum State {
-    Start,
+    Start(
+        Option<OutputDeviceId>, // The device to start on, if not the default
+        StreamConfigRequest,    // How to configure it
+        Arc<ScopeTap>,          // Where to copy what it plays for the oscilloscope
+    ),
     Ready(
         JoinHandle<()>,                // The AudioStream thread
+        JoinHandle<()>,                // The render thread
         Receiver<AudioInterfaceInput>, // App input
         Sender<AudioInterfaceInput>,   // App input forward
-        Receiver<AudioInterfaceEvent>, // AudioStream events
+        Sender<SynthesizerInput>,      // App input forward to the render thread
+        Receiver<AudioInterfaceEvent>, // Events passed on by the render thread
     ),
-    Ending(JoinHandle<()>),
+    Ending(JoinHandle<()>, JoinHandle<()>),
     Idle,
 }
 
 pub struct AudioInterfaceSubscription {}
 impl AudioInterfaceSubscription {
-    pub fn subscription() -> Subscription<AudioInterfaceEvent> {
+    /// Starts the audio stream on `output_device`, or on the default device if
+    /// that's None, configured according to `request`. Every stream copies
+    /// what it plays to `scope_tap`.
+    pub fn subscription(
+        output_device: Option<OutputDeviceId>,
+        request: StreamConfigRequest,
+        scope_tap: Arc<ScopeTap>,
+    ) -> Subscription<AudioInterfaceEvent> {
         subscription::unfold(
             std::any::TypeId::of::<AudioInterfaceSubscription>(),
-            State::Start,
+            State::Start(output_device, request, scope_tap),
             |state| async move {
                 match state {
-                    State::Start => {
+                    State::Start(output_device, request, scope_tap) => {
                         // Sends input from the app to the subscription.
                         let (app_input_sender, app_input_receiver) = unbounded();
 
-                        // Sends events from the audio stream to the subscription.
+                        // Sends events from the audio stream to the render thread.
                         let (audio_stream_event_sender, audio_stream_event_receiver) = unbounded();
 
+                        // Sends the events that the render thread doesn't
+                        // consume on to the subscription.
+                        let (render_event_sender, render_event_receiver) = unbounded();
+
+                        // Forwards synthesizer input to the render thread.
+                        let (render_input_sender, render_input_receiver) = unbounded();
+                        let render_handler = RenderThread::start(
+                            render_input_receiver,
+                            audio_stream_event_receiver,
+                            render_event_sender,
+                        );
+
                         // Forwards input sent from the app and received by the subscription to the audio-stream thread.
                         let (app_input_forward_sender, app_input_forward_receiver) = unbounded();
                         let handler = std::thread::spawn(move || {
-                            if let Ok(mut audio_stream) = AudioStream::create_default_stream(
-                                AudioStream::REASONABLE_BUFFER_SIZE,
-                                audio_stream_event_sender.clone(),
-                            ) {
-                                loop {
-                                    if let Ok(input) = app_input_forward_receiver.recv() {
-                                        match input {
-                                            AudioInterfaceInput::SetBufferSize(_) => todo!(),
-                                            AudioInterfaceInput::Play => audio_stream.play(),
-                                            AudioInterfaceInput::Pause => audio_stream.pause(),
-                                            AudioInterfaceInput::Quit => {
-                                                audio_stream.quit();
-                                                break;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                            Self::run_audio_stream(
+                                output_device,
+                                request,
+                                scope_tap,
+                                app_input_forward_receiver,
+                                audio_stream_event_sender,
+                            )
                         });
                         (
                             Some(AudioInterfaceEvent::Ready(app_input_sender)),
                             State::Ready(
                                 handler,
+                                render_handler,
                                 app_input_receiver,
                                 app_input_forward_sender,
-                                audio_stream_event_receiver,
+                                render_input_sender,
+                                render_event_receiver,
                             ),
                         )
                     }
                     State::Ready(
                         handler,
+                        render_handler,
                         app_input_receiver,
                         app_input_forward_sender,
-                        audio_stream_event_receiver,
+                        render_input_sender,
+                        render_event_receiver,
                     ) => {
                         let mut sel = Select::new();
                         sel.recv(&app_input_receiver);
-                        sel.recv(&audio_stream_event_receiver);
+                        sel.recv(&render_event_receiver);
                         loop {
                             let index = sel.ready();
                             match index {
                                 0 => {
                                     let res = app_input_receiver.try_recv();
-                                    if let Ok(input) = res {
-                                        let _ = app_input_forward_sender.send(input);
+                                    match res {
+                                        Ok(AudioInterfaceInput::Synthesizer(input)) => {
+                                            let _ = render_input_sender.send(input);
+                                        }
+                                        Ok(input) => {
+                                            let _ = app_input_forward_sender.send(input);
+                                        }
+                                        Err(TryRecvError::Empty) => {}
+
+                                        // The app is gone. Dropping our senders
+                                        // tells the threads to wind down.
+                                        Err(TryRecvError::Disconnected) => {
+                                            return (None, State::Ending(handler, render_handler))
+                                        }
                                     }
                                 }
                                 1 => {
-                                    let res = audio_stream_event_receiver.try_recv();
-                                    if let Ok(event) = res {
-                                        match event {
-                                            AudioInterfaceEvent::Quit => {
-                                                return (
-                                                    Some(AudioInterfaceEvent::Quit),
-                                                    State::Ending(handler),
-                                                )
-                                            }
-                                            _ => {
-                                                return (
-                                                    Some(event),
-                                                    State::Ready(
-                                                        handler,
-                                                        app_input_receiver,
-                                                        app_input_forward_sender,
-                                                        audio_stream_event_receiver,
-                                                    ),
-                                                );
-                                            }
+                                    let res = render_event_receiver.try_recv();
+                                    match res {
+                                        Ok(AudioInterfaceEvent::Quit) => {
+                                            return (
+                                                Some(AudioInterfaceEvent::Quit),
+                                                State::Ending(handler, render_handler),
+                                            )
+                                        }
+                                        Ok(event) => {
+                                            return (
+                                                Some(event),
+                                                State::Ready(
+                                                    handler,
+                                                    render_handler,
+                                                    app_input_receiver,
+                                                    app_input_forward_sender,
+                                                    render_input_sender,
+                                                    render_event_receiver,
+                                                ),
+                                            );
+                                        }
+                                        Err(TryRecvError::Empty) => {}
+
+                                        // The render thread ended without
+                                        // passing along a Quit, so nothing
+                                        // more is coming.
+                                        Err(TryRecvError::Disconnected) => {
+                                            return (
+                                                Some(AudioInterfaceEvent::Quit),
+                                                State::Ending(handler, render_handler),
+                                            )
                                         }
                                     }
                                 }
@@ -125,8 +210,9 @@ impl AudioInterfaceSubscription {
                             }
                         }
                     }
-                    State::Ending(handler) => {
+                    State::Ending(handler, render_handler) => {
                         let _ = handler.join();
+                        let _ = render_handler.join();
                         // See https://github.com/iced-rs/iced/issues/1348
                         (None, State::Idle)
                     }
@@ -143,4 +229,307 @@ impl AudioInterfaceSubscription {
             },
         )
     }
+    /// How long the audio-stream thread waits between attempts to find a new
+    /// output device after losing the old one.
+    const RECOVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// How often the audio-stream thread picks up the stream callback's level
+    /// reports and timings and sends them on. Often enough for meters to move
+    /// smoothly, but not so often that the app spends all its time redrawing
+    /// them.
+    const REPORT_INTERVAL: Duration = Duration::from_millis(30);
+
+    /// The body of the audio-stream thread. It owns the [AudioStream] and
+    /// applies the app's input to it, reporting any failures as
+    /// [AudioInterfaceEvent::Error].
+    ///
+    /// If the stream can't be created, or its device goes away while it's
+    /// playing, the thread falls back to the default output device, retrying
+    /// every [Self::RECOVERY_INTERVAL] until one shows up. Meanwhile the app
+    /// can still pick a different device itself.
+    fn run_audio_stream(
+        output_device: Option<OutputDeviceId>,
+        request: StreamConfigRequest,
+        scope_tap: Arc<ScopeTap>,
+        input_receiver: Receiver<AudioInterfaceInput>,
+        event_sender: Sender<AudioInterfaceEvent>,
+    ) {
+        let _ = event_sender.send(AudioInterfaceEvent::Devices(enumerate_output_devices()));
+
+        // Remembered in case we have to create a stream from scratch.
+        let buffer_size = AudioStream::REASONABLE_BUFFER_SIZE;
+
+        let created = if let Some(id) = output_device {
+            AudioStream::create_stream_on(
+                &id,
+                buffer_size,
+                request.clone(),
+                &scope_tap,
+                event_sender.clone(),
+            )
+        } else {
+            AudioStream::create_default_stream(
+                buffer_size,
+                request.clone(),
+                &scope_tap,
+                event_sender.clone(),
+            )
+        };
+        let audio_stream = match created {
+            Ok(audio_stream) => Some(audio_stream),
+            Err(e) => {
+                let _ = event_sender.send(AudioInterfaceEvent::Error(e));
+                None
+            }
+        };
+        Self::serve_audio_stream(
+            audio_stream,
+            buffer_size,
+            request,
+            scope_tap,
+            AudioStream::host_device_setup,
+            input_receiver,
+            event_sender,
+        );
+    }
+
+    /// The audio-stream thread's main loop, once it has tried to create a
+    /// stream. `find_fallback` says which device to fall back to, and how to
+    /// configure it.
+    fn serve_audio_stream(
+        mut audio_stream: Option<AudioStream>,
+        mut buffer_size: usize,
+        mut request: StreamConfigRequest,
+        scope_tap: Arc<ScopeTap>,
+        find_fallback: impl Fn(
+            &StreamConfigRequest,
+        ) -> Result<(OutputDevice, StreamDescription), AudioStreamError>,
+        input_receiver: Receiver<AudioInterfaceInput>,
+        event_sender: Sender<AudioInterfaceEvent>,
+    ) {
+        let mut needs_recovery = audio_stream.is_none();
+
+        // When to try recovering next. Worked out once, rather than on every
+        // pass through the loop, because the report ticker comes around far
+        // more often than this.
+        let mut retry_at = None;
+        let report_ticker = tick(Self::REPORT_INTERVAL);
+        loop {
+            let stream_errors = audio_stream
+                .as_ref()
+                .map_or_else(never, |s| s.stream_errors().clone());
+            let retry = if needs_recovery {
+                at(*retry_at.get_or_insert_with(|| Instant::now() + Self::RECOVERY_INTERVAL))
+            } else {
+                never()
+            };
+            select! {
+                recv(input_receiver) -> input => {
+                    let Ok(input) = input else {
+                        break;
+                    };
+                    match &input {
+                        AudioInterfaceInput::SetBufferSize(new_buffer_size) => {
+                            buffer_size = *new_buffer_size;
+                        }
+                        AudioInterfaceInput::SetStreamConfig(new_request) => {
+                            request = new_request.clone();
+                        }
+                        _ => {}
+                    }
+                    let is_device_switch = matches!(input, AudioInterfaceInput::SetOutputDevice(_));
+                    let result = match (audio_stream.as_mut(), input) {
+                        (Some(audio_stream), AudioInterfaceInput::Quit) => {
+                            audio_stream.quit();
+                            break;
+                        }
+                        (None, AudioInterfaceInput::Quit) => {
+                            let _ = event_sender.send(AudioInterfaceEvent::Quit);
+                            break;
+                        }
+                        (Some(audio_stream), AudioInterfaceInput::SetBufferSize(buffer_size)) => {
+                            audio_stream.set_buffer_size(buffer_size)
+                        }
+                        (Some(audio_stream), AudioInterfaceInput::SetOutputDevice(id)) => {
+                            audio_stream.set_output_device(&id)
+                        }
+                        (None, AudioInterfaceInput::SetOutputDevice(id)) => {
+                            AudioStream::create_stream_on(
+                                &id,
+                                buffer_size,
+                                request.clone(),
+                                &scope_tap,
+                                event_sender.clone(),
+                            )
+                            .map(|new_stream| audio_stream = Some(new_stream))
+                        }
+                        (Some(audio_stream), AudioInterfaceInput::SetStreamConfig(request)) => {
+                            audio_stream.set_stream_config(request)
+                        }
+                        (
+                            Some(audio_stream),
+                            AudioInterfaceInput::SetExtraChannels(extra_channels),
+                        ) => audio_stream.set_extra_channels(extra_channels),
+                        (
+                            Some(audio_stream),
+                            AudioInterfaceInput::StartRecording(path, format),
+                        ) => audio_stream.start_recording(&path, format),
+                        (Some(audio_stream), AudioInterfaceInput::StopRecording) => {
+                            audio_stream.stop_recording()
+                        }
+                        // The subscription sends these to the render thread.
+                        (_, AudioInterfaceInput::Synthesizer(_)) => Ok(()),
+                        (Some(audio_stream), AudioInterfaceInput::Play) => audio_stream.play(),
+                        (Some(audio_stream), AudioInterfaceInput::Pause) => audio_stream.pause(),
+
+                        // There's no stream to apply the rest to.
+                        (None, _) => Ok(()),
+                    };
+                    // The app picking a device that works is as good as us
+                    // finding one. Anything else that succeeds, like Play on
+                    // a stream that lost its device, doesn't help.
+                    let has_stream = audio_stream.as_ref().is_some_and(AudioStream::has_stream);
+                    match result {
+                        Ok(()) => needs_recovery &= !has_stream,
+                        Err(e) => {
+                            let _ = event_sender.send(AudioInterfaceEvent::Error(e));
+
+                            // The device might have failed because it's gone.
+                            if is_device_switch {
+                                let _ = event_sender.send(AudioInterfaceEvent::Devices(
+                                    enumerate_output_devices(),
+                                ));
+                            }
+                        }
+                    }
+                }
+                recv(stream_errors) -> e => {
+                    if let Ok(e) = e {
+                        if e == AudioStreamError::DeviceNotAvailable {
+                            if let Some(audio_stream) = audio_stream.as_mut() {
+                                audio_stream.drop_lost_stream();
+                            }
+                            needs_recovery = true;
+                        }
+                        let _ = event_sender.send(AudioInterfaceEvent::Error(e));
+                    }
+                }
+                recv(report_ticker) -> _ => {
+                    if let Some(audio_stream) = audio_stream.as_mut() {
+                        if let Some(report) = audio_stream.take_levels() {
+                            let _ = event_sender.send(AudioInterfaceEvent::Levels(report));
+                        }
+                        let timings = audio_stream.take_callback_timings();
+                        if !timings.is_empty() {
+                            let _ = event_sender.send(AudioInterfaceEvent::CallbackTimings(timings));
+                        }
+                        if let Err(e) = audio_stream.check_recording() {
+                            let _ = event_sender.send(AudioInterfaceEvent::Error(e));
+                        }
+                    }
+
+                    // Not a reason to try recovering any sooner.
+                    continue;
+                }
+                recv(retry) -> _ => {}
+            }
+
+            if needs_recovery {
+                let result = find_fallback(&request).and_then(|(device, description)| {
+                    if let Some(audio_stream) = audio_stream.as_mut() {
+                        audio_stream.fall_back_to(device, description)
+                    } else {
+                        AudioStream::create_stream_with(
+                            device,
+                            request.clone(),
+                            description,
+                            buffer_size,
+                            &scope_tap,
+                            event_sender.clone(),
+                        )
+                        .map(|new_stream| audio_stream = Some(new_stream))
+                    }
+                });
+
+                // Whether or not that worked, the next try is a whole
+                // interval away.
+                retry_at = None;
+
+                // On failure we stay quiet, having already reported the error
+                // that got us here, and try again later.
+                if result.is_ok() && audio_stream.as_ref().is_some_and(AudioStream::has_stream) {
+                    needs_recovery = false;
+                    let _ =
+                        event_sender.send(AudioInterfaceEvent::Devices(enumerate_output_devices()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::null_backend::{
+        tests::{next_reset, TIMEOUT},
+        NullDevice, NullStream,
+    };
+
+    #[test]
+    fn recovers_lost_device_while_reporting() {
+        let device = NullDevice::default();
+        let request = StreamConfigRequest::default();
+        let scope_tap = Arc::new(ScopeTap::default());
+        let (event_sender, events) = unbounded();
+        let (input_sender, input_receiver) = unbounded();
+        let null_setup = {
+            let device = device.clone();
+            move |request: &StreamConfigRequest| {
+                Ok((
+                    OutputDevice::Null(device.clone()),
+                    NullStream::negotiate(&OutputDeviceId::null(), request),
+                ))
+            }
+        };
+        let handler = std::thread::spawn(move || {
+            let (output_device, description) = null_setup(&request).unwrap();
+            let audio_stream = AudioStream::create_stream_with(
+                output_device,
+                request.clone(),
+                description,
+                1024,
+                &scope_tap,
+                event_sender.clone(),
+            )
+            .unwrap();
+            AudioInterfaceSubscription::serve_audio_stream(
+                Some(audio_stream),
+                1024,
+                request,
+                scope_tap,
+                null_setup,
+                input_receiver,
+                event_sender,
+            )
+        });
+        next_reset(&events);
+
+        // The attempt to recover right away fails, both at the requested
+        // buffer size and at the default, so it's up to the retry. The report
+        // ticker keeps going all the while.
+        device.refuse_builds(2);
+        device.unplug();
+        loop {
+            if let AudioInterfaceEvent::Error(e) = events.recv_timeout(TIMEOUT).unwrap() {
+                assert_eq!(e, AudioStreamError::DeviceNotAvailable);
+                break;
+            }
+        }
+        let lost_at = Instant::now();
+        next_reset(&events);
+        assert!(lost_at.elapsed() < AudioInterfaceSubscription::RECOVERY_INTERVAL * 3);
+
+        let _ = input_sender.send(AudioInterfaceInput::Quit);
+        handler.join().unwrap();
+    }
 }
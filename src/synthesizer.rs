@@ -1,27 +1,161 @@
-use crate::stream::{AudioQueue, StereoSample};
-use std::{fmt::Debug, time::Duration};
+use crate::{
+    envelope::EnvelopeSettings,
+    filter::FilterSettings,
+    modulation::{Lfo, LfoSettings, ModRoute},
+    oscillator::{Oscillator, Waveform},
+    patch::Patch,
+    stream::{AudioQueue, StereoSample},
+    voice::{SharedModulation, Voice, VoiceStealing},
+    voice_pool::{VoicePanic, VoicePool},
+};
+use std::{
+    fmt::{Debug, Display},
+    time::{Duration, Instant},
+};
 
+/// How [Synthesizer] divides up the work of rendering its voices.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VoiceRendering {
+    // One voice after another on the calling thread.
+    #[default]
+    Serial,
+
+    // Spread across this many worker threads.
+    Parallel(usize),
+}
+impl VoiceRendering {
+    pub const ALL: [VoiceRendering; 4] = [
+        VoiceRendering::Serial,
+        VoiceRendering::Parallel(2),
+        VoiceRendering::Parallel(4),
+        VoiceRendering::Parallel(8),
+    ];
+}
+impl Display for VoiceRendering {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoiceRendering::Serial => write!(f, "Serial"),
+            VoiceRendering::Parallel(worker_count) => write!(f, "{} workers", worker_count),
+        }
+    }
+}
+
+/// Something a player does that should land at the moment it happened, rather
+/// than at the start of whichever block the synthesizer renders next. See
+/// [Synthesizer::schedule].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimedEvent {
+    NoteOn(u8, u8),
+    NoteOff(u8),
+    AllNotesOff,
+    SetModWheel(f32),
+    SetPitchBend(f32),
+}
+
+/// A polyphonic synthesizer. Each note played with [Synthesizer::note_on] gets
+/// a [Voice] of its own, until there are more notes than voices, at which point
+/// new notes take over existing voices according to [VoiceStealing].
 #[derive(Debug)]
 pub struct Synthesizer {
     pub sample_rate: usize,
-    pub sample_clock: usize,
 
-    pub frequency: f32,
     is_playing: bool,
 
+    // Ramps between 0.0 and 1.0 on pause and play, so that they don't click.
+    output_gain: f32,
+
     fake_delay: u64,
+
+    voice_rendering: VoiceRendering,
+    voice_stealing: VoiceStealing,
+
+    // The workers for VoiceRendering::Parallel, kept for as long as that's
+    // the setting.
+    voice_pool: Option<VoicePool>,
+
+    // How every voice sounds.
+    patch: Patch,
+
+    voices: Vec<Voice>,
+
+    // Shared by every voice, and rendered a block at a time before the voices
+    // are, so that the voices can use them from any thread.
+    lfos: [Lfo; Patch::LFO_COUNT],
+    lfo_blocks: [Vec<f32>; Patch::LFO_COUNT],
+
+    // In beats per minute, for LFOs synced to tempo.
+    tempo: f32,
+
+    // From 0.0 to 1.0.
+    mod_wheel: f32,
+
+    // From -1.0 to 1.0, where 0.0 is no bend.
+    pitch_bend: f32,
+
+    // How many notes have started, for telling which voices are oldest.
+    notes_started: u64,
+
+    // Events waiting for the block that they belong in.
+    scheduled: Vec<(Instant, TimedEvent)>,
+
+    // One block of output per voice, reused from one call to the next.
+    voice_blocks: Vec<Vec<StereoSample>>,
+
+    // For measuring throughput.
+    render_time: Duration,
+    frames_rendered: usize,
 }
 
 impl Synthesizer {
+    pub const DEFAULT_VOICE_COUNT: usize = 8;
+    pub const MAX_VOICE_COUNT: usize = 32;
+
+    pub const DEFAULT_TEMPO: f32 = 120.0;
+    pub const MIN_TEMPO: f32 = 20.0;
+    pub const MAX_TEMPO: f32 = 300.0;
+
+    /// How many semitones the pitch bend moves every note at either extreme.
+    pub const PITCH_BEND_RANGE: f32 = 2.0;
+
+    // Each voice's share of the output. Leaves room for a four-note chord at
+    // full velocity before anything clips.
+    const VOICE_GAIN: f32 = 0.25;
+
+    // How long play and pause take to fade in and out.
+    const PAUSE_FADE_SECONDS: f32 = 0.005;
+
     pub fn new_with(sample_rate: usize) -> Self {
-        Self {
+        let mut r = Self {
             sample_rate,
-            sample_clock: 0,
-            frequency: 440.0,
             is_playing: true,
+            output_gain: 0.0,
 
             fake_delay: 1,
-        }
+
+            voice_rendering: VoiceRendering::default(),
+            voice_pool: None,
+            voice_stealing: VoiceStealing::default(),
+            patch: Patch::default(),
+            voices: Vec::default(),
+            lfos: Default::default(),
+            lfo_blocks: Default::default(),
+            tempo: Self::DEFAULT_TEMPO,
+            mod_wheel: 0.0,
+            pitch_bend: 0.0,
+            notes_started: 0,
+            scheduled: Vec::default(),
+            voice_blocks: Vec::default(),
+            render_time: Duration::ZERO,
+            frames_rendered: 0,
+        };
+        r.set_voice_count(Self::DEFAULT_VOICE_COUNT);
+        r
+    }
+
+    /// Voices keep track of where they are in their cycles rather than in
+    /// samples, so they carry on at the new rate without a glitch.
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
     }
 
     pub fn play(&mut self) {
@@ -32,49 +166,369 @@ impl Synthesizer {
         self.is_playing = false;
     }
 
-    pub fn change_frequency(&mut self) {
-        self.frequency = self.frequency * 1.01;
+    pub fn is_playing(&self) -> bool {
+        self.is_playing
     }
 
-    pub fn frequency(&self) -> f32 {
-        self.frequency
+    /// Starts playing `note`, a MIDI note number, at `velocity`, which goes
+    /// from 0 to 127.
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        let voice = self.allocate_voice(note);
+        self.notes_started += 1;
+        self.voices[voice].start(note, velocity, self.notes_started);
     }
 
-    fn make_sound(&self, freq: f32) -> f32 {
-        (self.sample_clock as f32 * freq * 2.0 * std::f32::consts::PI / self.sample_rate as f32)
-            .sin()
+    /// Releases every voice that's holding `note`.
+    pub fn note_off(&mut self, note: u8) {
+        for voice in self.voices.iter_mut() {
+            if voice.held_note() == Some(note) {
+                voice.release();
+            }
+        }
     }
 
-    fn tick(&mut self) {
-        self.sample_clock = self.sample_clock + 1;
+    pub fn all_notes_off(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.release();
+        }
     }
 
-    pub fn generate_audio(&mut self, count: usize, queue: AudioQueue) {
-        std::thread::sleep(Duration::from_micros(self.fake_delay));
-        for _ in 0..count {
-            let voice_count = 8;
-            let mut sum = 0.0;
-            for i in 0..voice_count {
-                let sample = if self.is_playing {
-                    self.make_sound(self.frequency * (1.0 + (i as f32 * (1.0 / 1000.0))))
-                } else {
-                    0.0
-                };
-                sum += sample;
+    /// The notes that are held, lowest first, without duplicates. Notes that
+    /// have been released but are still fading out don't count. Notes that
+    /// are scheduled to start or stop count as if they already had.
+    pub fn active_notes(&self) -> Vec<u8> {
+        let mut notes: Vec<u8> = self.voices.iter().filter_map(|v| v.held_note()).collect();
+        for (_, event) in self.scheduled.iter() {
+            match *event {
+                TimedEvent::NoteOn(note, _) => notes.push(note),
+                TimedEvent::NoteOff(note) => notes.retain(|n| *n != note),
+                TimedEvent::AllNotesOff => notes.clear(),
+                TimedEvent::SetModWheel(_) | TimedEvent::SetPitchBend(_) => {}
+            }
+        }
+        notes.sort_unstable();
+        notes.dedup();
+        notes
+    }
+
+    /// Picks the voice that should play a new `note`.
+    fn allocate_voice(&self, note: u8) -> usize {
+        if self.voice_stealing == VoiceStealing::SameNote {
+            if let Some(i) = self.voices.iter().position(|v| v.note() == Some(note)) {
+                return i;
+            }
+        }
+        if let Some(i) = self.voices.iter().position(|v| !v.is_active()) {
+            return i;
+        }
+        let oldest = |(_, v): &(usize, &Voice)| v.started();
+        match self.voice_stealing {
+            // Cutting a fade short is less noticeable than cutting off a note
+            // that someone is still holding.
+            VoiceStealing::Oldest | VoiceStealing::SameNote => self
+                .voices
+                .iter()
+                .enumerate()
+                .min_by_key(|voice| (voice.1.held_note().is_some(), oldest(voice))),
+            VoiceStealing::Quietest => self.voices.iter().enumerate().min_by(|a, b| {
+                a.1.level()
+                    .total_cmp(&b.1.level())
+                    .then_with(|| oldest(a).cmp(&oldest(b)))
+            }),
+        }
+        .map_or(0, |(i, _)| i)
+    }
+
+    pub fn voice_count(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Adds or removes voices. Notes playing on removed voices stop.
+    pub fn set_voice_count(&mut self, voice_count: usize) {
+        let voice_count = voice_count.clamp(1, Self::MAX_VOICE_COUNT);
+        while self.voices.len() < voice_count {
+            let oscillator = Oscillator::new_with(self.voices.len() as u32 + 1);
+            self.voices.push(Voice::new_with(oscillator));
+        }
+        self.voices.truncate(voice_count);
+        self.voice_blocks.resize(voice_count, Vec::default());
+    }
+
+    pub fn voice_stealing(&self) -> VoiceStealing {
+        self.voice_stealing
+    }
+
+    pub fn set_voice_stealing(&mut self, voice_stealing: VoiceStealing) {
+        self.voice_stealing = voice_stealing;
+    }
+
+    pub fn patch(&self) -> &Patch {
+        &self.patch
+    }
+
+    /// Applies to every voice right away, including notes that are playing.
+    pub fn set_patch(&mut self, patch: &Patch) {
+        self.patch = patch.clamped();
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.patch.waveform = waveform;
+    }
+
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.patch.pulse_width =
+            pulse_width.clamp(Oscillator::MIN_PULSE_WIDTH, Oscillator::MAX_PULSE_WIDTH);
+    }
+
+    pub fn set_envelope(&mut self, envelope: EnvelopeSettings) {
+        self.patch.envelope = envelope.clamped();
+    }
+
+    pub fn set_filter(&mut self, filter: FilterSettings) {
+        self.patch.filter = filter.clamped();
+    }
+
+    /// Ignores LFOs that don't exist.
+    pub fn set_lfo(&mut self, index: usize, lfo: LfoSettings) {
+        if let Some(settings) = self.patch.lfos.get_mut(index) {
+            *settings = lfo.clamped();
+        }
+    }
+
+    /// Ignores routes that don't exist.
+    pub fn set_mod_route(&mut self, index: usize, route: ModRoute) {
+        if let Some(settings) = self.patch.mod_matrix.routes.get_mut(index) {
+            *settings = ModRoute {
+                depth: route.depth.clamp(-1.0, 1.0),
+                ..route
+            };
+        }
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.clamp(Self::MIN_TEMPO, Self::MAX_TEMPO);
+    }
+
+    pub fn mod_wheel(&self) -> f32 {
+        self.mod_wheel
+    }
+
+    pub fn set_mod_wheel(&mut self, mod_wheel: f32) {
+        self.mod_wheel = mod_wheel.clamp(0.0, 1.0);
+    }
+
+    pub fn pitch_bend(&self) -> f32 {
+        self.pitch_bend
+    }
+
+    pub fn set_pitch_bend(&mut self, pitch_bend: f32) {
+        self.pitch_bend = pitch_bend.clamp(-1.0, 1.0);
+    }
+
+    /// Queues `event` to happen at `when`. See [Self::generate_audio] for
+    /// where in the output that ends up.
+    pub fn schedule(&mut self, when: Instant, event: TimedEvent) {
+        self.scheduled.push((when, event));
+    }
+
+    fn perform(&mut self, event: TimedEvent) {
+        match event {
+            TimedEvent::NoteOn(note, velocity) => self.note_on(note, velocity),
+            TimedEvent::NoteOff(note) => self.note_off(note),
+            TimedEvent::AllNotesOff => self.all_notes_off(),
+            TimedEvent::SetModWheel(mod_wheel) => self.set_mod_wheel(mod_wheel),
+            TimedEvent::SetPitchBend(pitch_bend) => self.set_pitch_bend(pitch_bend),
+        }
+    }
+
+    /// Takes the scheduled events that belong in a block of `count` frames
+    /// rendered at `now`, in order, each with the frame it lands on.
+    fn take_scheduled(&mut self, count: usize, now: Instant) -> Vec<(usize, TimedEvent)> {
+        let sample_rate = self.sample_rate as f64;
+        let block_start = now
+            .checked_sub(Duration::from_secs_f64(count as f64 / sample_rate.max(1.0)))
+            .unwrap_or(now);
+        let last_frame = count.saturating_sub(1);
+
+        // Stable, so that events at the same moment keep their order.
+        self.scheduled.sort_by_key(|(when, _)| *when);
+        let due = self.scheduled.partition_point(|(when, _)| *when <= now);
+        self.scheduled
+            .drain(..due)
+            .map(|(when, event)| {
+                let since_start = when.saturating_duration_since(block_start);
+                let frame = (since_start.as_secs_f64() * sample_rate).round() as usize;
+                (frame.min(last_frame), event)
+            })
+            .collect()
+    }
+
+    /// Fills each of `self.voice_blocks` with `count` samples of its voice,
+    /// either here or spread across worker threads. If a worker panics, every
+    /// voice starts over, and the blocks are silent.
+    fn render_voices(&mut self, count: usize) -> Result<(), VoicePanic> {
+        let sample_rate = self.sample_rate;
+        for ((lfo, block), settings) in self
+            .lfos
+            .iter_mut()
+            .zip(self.lfo_blocks.iter_mut())
+            .zip(self.patch.lfos.iter())
+        {
+            block.resize(count, 0.0);
+            lfo.render(block, settings, self.tempo, sample_rate);
+        }
+        for block in self.voice_blocks.iter_mut() {
+            block.resize(count, StereoSample::default());
+        }
+
+        let patch = &self.patch;
+        let modulation = SharedModulation {
+            lfos: [&self.lfo_blocks[0], &self.lfo_blocks[1]],
+            mod_wheel: self.mod_wheel,
+            pitch_bend: self.pitch_bend * Self::PITCH_BEND_RANGE,
+        };
+        match self.voice_pool.as_mut() {
+            None => {
+                for (voice, block) in self.voices.iter_mut().zip(self.voice_blocks.iter_mut()) {
+                    voice.render(block, sample_rate, patch, &modulation);
+                }
+                Ok(())
+            }
+            Some(voice_pool) => {
+                let result = voice_pool.render(
+                    &mut self.voices,
+                    &mut self.voice_blocks,
+                    sample_rate,
+                    patch,
+                    &modulation,
+                );
+                if result.is_err() {
+                    // A voice that panicked might be in any state, or gone,
+                    // and its block half written. Better a dropped note than
+                    // a stuck or screeching one.
+                    let voice_count = self.voice_blocks.len();
+                    self.voices.clear();
+                    self.set_voice_count(voice_count);
+                    for block in self.voice_blocks.iter_mut() {
+                        block.fill(StereoSample::default());
+                    }
+                }
+                result
             }
-            let sample = sum / voice_count as f32;
-
-            // Normally we'd produce and push empty samples even if we weren't
-            // playing, but for this demo it's more useful to let the queue
-            // shrink.
-            if self.is_playing {
-                let stereo_sample = StereoSample {
-                    left: sample,
-                    right: sample,
-                };
-                let _ = queue.force_push(stereo_sample);
+        }
+    }
+
+    /// Renders `count` frames onto `queue`.
+    ///
+    /// Events from [Self::schedule] land partway through the block, on the
+    /// frame that matches when they happened. We treat the block as covering
+    /// the `count` frames' worth of time just before `now`, which is normally
+    /// when the request for the block arrived, so that events keep
+    /// their spacing at the cost of up to a block of extra latency. Anything
+    /// older than that lands on the first frame.
+    ///
+    /// A voice worker panicking doesn't stop the block from being rendered,
+    /// but the voices start over, and we say so afterward.
+    pub fn generate_audio(
+        &mut self,
+        count: usize,
+        queue: AudioQueue,
+        now: Instant,
+    ) -> Result<(), VoicePanic> {
+        let mut result = Ok(());
+        let mut events = self.take_scheduled(count, now).into_iter().peekable();
+        std::thread::sleep(Duration::from_micros(self.fake_delay));
+
+        // Normally we'd produce and push empty samples even if we weren't
+        // playing, but for this demo it's more useful to let the queue
+        // shrink. We do finish fading out first, though.
+        if self.is_playing || self.output_gain > 0.0 {
+            let start = Instant::now();
+            let target_gain = if self.is_playing { 1.0 } else { 0.0 };
+            let gain_step = 1.0 / (Self::PAUSE_FADE_SECONDS * self.sample_rate as f32).max(1.0);
+
+            // Rendered in pieces that end wherever the next event lands.
+            let mut frame = 0;
+            while frame < count {
+                while let Some((_, event)) = events.next_if(|(at, _)| *at <= frame) {
+                    self.perform(event);
+                }
+                let end = events.peek().map_or(count, |(at, _)| *at);
+                if let Err(e) = self.render_voices(end - frame) {
+                    result = Err(e);
+                }
+                self.mix_voices(end - frame, target_gain, gain_step, &queue);
+                frame = end;
             }
-            self.tick();
+            self.render_time += start.elapsed();
+            self.frames_rendered += count;
+        }
+
+        // Whatever didn't land on a frame still happens.
+        for (_, event) in events {
+            self.perform(event);
+        }
+        result
+    }
+
+    /// Mixes the first `count` samples of `self.voice_blocks` onto `queue`,
+    /// moving the output gain toward `target_gain` as it goes.
+    fn mix_voices(&mut self, count: usize, target_gain: f32, gain_step: f32, queue: &AudioQueue) {
+        // Always mixed in voice order, so that the result doesn't depend on
+        // how the voices were rendered.
+        for i in 0..count {
+            self.output_gain = if target_gain > self.output_gain {
+                (self.output_gain + gain_step).min(target_gain)
+            } else {
+                (self.output_gain - gain_step).max(target_gain)
+            };
+            let (left, right) = self
+                .voice_blocks
+                .iter()
+                .fold((0.0, 0.0), |(left, right), block| {
+                    (left + block[i].left, right + block[i].right)
+                });
+            let gain = Self::VOICE_GAIN * self.output_gain;
+            let _ = queue.force_push(StereoSample {
+                left: left * gain,
+                right: right * gain,
+            });
+        }
+    }
+
+    pub fn voice_rendering(&self) -> VoiceRendering {
+        self.voice_rendering
+    }
+
+    /// Also restarts the throughput measurement, so that it reflects only the
+    /// new choice.
+    pub fn set_voice_rendering(&mut self, voice_rendering: VoiceRendering) {
+        self.voice_rendering = voice_rendering;
+        self.voice_pool = match voice_rendering {
+            VoiceRendering::Serial => None,
+            VoiceRendering::Parallel(worker_count) => match self.voice_pool.take() {
+                Some(voice_pool) if voice_pool.worker_count() == worker_count.max(1) => {
+                    Some(voice_pool)
+                }
+                _ => Some(VoicePool::new_with(worker_count)),
+            },
+        };
+        self.render_time = Duration::ZERO;
+        self.frames_rendered = 0;
+    }
+
+    /// How many frames per second the synthesizer has rendered, not counting
+    /// its fake delay, since the voice rendering last changed. None until
+    /// there's something to measure.
+    pub fn frames_per_second(&self) -> Option<f64> {
+        if self.render_time.is_zero() {
+            None
+        } else {
+            Some(self.frames_rendered as f64 / self.render_time.as_secs_f64())
         }
     }
 
@@ -86,3 +540,195 @@ impl Synthesizer {
         self.fake_delay = fake_delay;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::queue::ArrayQueue;
+    use std::sync::Arc;
+
+    const SAMPLE_RATE: usize = 48000;
+    const BLOCK: usize = 480;
+
+    fn block_time(frames: usize) -> Duration {
+        Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+    }
+
+    /// The note on each voice, in voice order.
+    fn voice_notes(synthesizer: &Synthesizer) -> Vec<Option<u8>> {
+        synthesizer.voices.iter().map(Voice::note).collect()
+    }
+
+    #[test]
+    fn fills_free_voices_before_stealing() {
+        let mut synthesizer = Synthesizer::new_with(SAMPLE_RATE);
+        synthesizer.set_voice_count(4);
+        for note in 60..64 {
+            synthesizer.note_on(note, 100);
+        }
+        assert_eq!(
+            voice_notes(&synthesizer),
+            vec![Some(60), Some(61), Some(62), Some(63)]
+        );
+        assert_eq!(synthesizer.active_notes(), vec![60, 61, 62, 63]);
+    }
+
+    #[test]
+    fn steals_oldest_released_then_oldest_held() {
+        let mut synthesizer = Synthesizer::new_with(SAMPLE_RATE);
+        synthesizer.set_voice_count(4);
+        for note in 60..64 {
+            synthesizer.note_on(note, 100);
+        }
+
+        // Released, but still fading out, so their voices are busy.
+        synthesizer.note_off(62);
+        synthesizer.note_off(61);
+        assert_eq!(synthesizer.active_notes(), vec![60, 63]);
+
+        synthesizer.note_on(70, 100);
+        assert_eq!(
+            voice_notes(&synthesizer),
+            vec![Some(60), Some(70), Some(62), Some(63)]
+        );
+        synthesizer.note_on(71, 100);
+        assert_eq!(
+            voice_notes(&synthesizer),
+            vec![Some(60), Some(70), Some(71), Some(63)]
+        );
+
+        // Everything's held now, so the oldest held note goes.
+        synthesizer.note_on(72, 100);
+        assert_eq!(
+            voice_notes(&synthesizer),
+            vec![Some(72), Some(70), Some(71), Some(63)]
+        );
+        assert_eq!(synthesizer.active_notes(), vec![63, 70, 71, 72]);
+
+        // Letting go of the stolen note doesn't stop the note that took its
+        // voice.
+        synthesizer.note_off(60);
+        assert_eq!(synthesizer.active_notes(), vec![63, 70, 71, 72]);
+    }
+
+    #[test]
+    fn same_note_reuses_its_voice() {
+        let mut synthesizer = Synthesizer::new_with(SAMPLE_RATE);
+        synthesizer.set_voice_count(4);
+        synthesizer.set_voice_stealing(VoiceStealing::SameNote);
+        synthesizer.note_on(60, 100);
+        synthesizer.note_on(61, 100);
+        synthesizer.note_on(60, 100);
+        assert_eq!(
+            voice_notes(&synthesizer),
+            vec![Some(60), Some(61), None, None]
+        );
+    }
+
+    #[test]
+    fn quietest_is_stolen() {
+        let mut synthesizer = Synthesizer::new_with(SAMPLE_RATE);
+        synthesizer.set_voice_count(2);
+        synthesizer.set_voice_stealing(VoiceStealing::Quietest);
+        synthesizer.note_on(60, 127);
+        synthesizer.note_on(61, 10);
+        synthesizer
+            .generate_audio(BLOCK, Arc::new(ArrayQueue::new(BLOCK)), Instant::now())
+            .unwrap();
+        synthesizer.note_on(62, 100);
+        assert_eq!(voice_notes(&synthesizer), vec![Some(60), Some(62)]);
+    }
+
+    #[test]
+    fn scheduled_events_land_where_they_happened() {
+        let mut synthesizer = Synthesizer::new_with(SAMPLE_RATE);
+
+        // Ahead of the clock, so that nothing depends on how long the test
+        // takes, and so that the events before it can't come before the
+        // earliest Instant.
+        let now = Instant::now() + Duration::from_secs(1);
+        synthesizer.schedule(now - block_time(BLOCK / 4), TimedEvent::NoteOff(60));
+        synthesizer.schedule(now - block_time(BLOCK * 3 / 4), TimedEvent::NoteOn(60, 100));
+        synthesizer.schedule(now - block_time(BLOCK * 3), TimedEvent::AllNotesOff);
+        synthesizer.schedule(now + block_time(1), TimedEvent::NoteOn(64, 100));
+
+        let due = synthesizer.take_scheduled(BLOCK, now);
+        assert_eq!(
+            due,
+            vec![
+                (0, TimedEvent::AllNotesOff),
+                (BLOCK / 4, TimedEvent::NoteOn(60, 100)),
+                (BLOCK * 3 / 4, TimedEvent::NoteOff(60)),
+            ]
+        );
+        assert_eq!(synthesizer.active_notes(), vec![64]);
+    }
+
+    #[test]
+    fn note_starts_partway_through_block() {
+        let mut synthesizer = Synthesizer::new_with(SAMPLE_RATE);
+        synthesizer.set_fake_delay(0);
+        let now = Instant::now() + Duration::from_secs(1);
+        synthesizer.schedule(now - block_time(BLOCK / 2), TimedEvent::NoteOn(69, 127));
+        let queue = Arc::new(ArrayQueue::new(BLOCK));
+        synthesizer
+            .generate_audio(BLOCK, Arc::clone(&queue), now)
+            .unwrap();
+
+        let samples: Vec<StereoSample> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(samples.len(), BLOCK);
+        let first_sound = samples
+            .iter()
+            .position(|s| s.left != 0.0 || s.right != 0.0)
+            .expect("the note should sound within the block");
+        // The sine and the envelope both start from zero, so the first
+        // frame of the note can be silent.
+        assert!(
+            (BLOCK / 2..=BLOCK / 2 + 1).contains(&first_sound),
+            "note started at frame {}",
+            first_sound
+        );
+        assert_eq!(synthesizer.active_notes(), vec![69]);
+    }
+
+    /// Renders a few blocks of a chord, releasing some of it partway.
+    fn render_chord(voice_rendering: VoiceRendering) -> Vec<StereoSample> {
+        let mut synthesizer = Synthesizer::new_with(SAMPLE_RATE);
+        synthesizer.set_fake_delay(0);
+        synthesizer.set_voice_count(8);
+        synthesizer.set_voice_rendering(voice_rendering);
+        for note in [48, 55, 60, 64, 67, 71] {
+            synthesizer.note_on(note, 100);
+        }
+        let queue = Arc::new(ArrayQueue::new(BLOCK * 8));
+        for i in 0..8 {
+            if i == 4 {
+                synthesizer.note_off(60);
+                synthesizer.note_off(67);
+            }
+            synthesizer
+                .generate_audio(BLOCK, Arc::clone(&queue), Instant::now())
+                .unwrap();
+        }
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn parallel_rendering_matches_serial() {
+        let bits = |samples: Vec<StereoSample>| -> Vec<(u32, u32)> {
+            samples
+                .iter()
+                .map(|s| (s.left.to_bits(), s.right.to_bits()))
+                .collect()
+        };
+        let serial = bits(render_chord(VoiceRendering::Serial));
+        assert!(serial.iter().any(|(left, _)| *left != 0));
+        for worker_count in [1, 2, 3, 8] {
+            assert!(
+                serial == bits(render_chord(VoiceRendering::Parallel(worker_count))),
+                "{} workers rendered something different",
+                worker_count
+            );
+        }
+    }
+}
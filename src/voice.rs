@@ -0,0 +1,215 @@
+use crate::{
+    envelope::Envelope,
+    filter::Filter,
+    modulation::{ModDestination, ModSourceValues},
+    oscillator::Oscillator,
+    patch::Patch,
+    stream::StereoSample,
+};
+use std::fmt::{Debug, Display};
+
+/// Which voice [crate::synthesizer::Synthesizer] takes over for a new note
+/// when all of them are busy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VoiceStealing {
+    // The voice whose note started longest ago, preferring voices that have
+    // been released and are only fading out over those still held.
+    #[default]
+    Oldest,
+
+    // The voice that's currently the least loud, or the oldest of those if
+    // there's a tie.
+    Quietest,
+
+    // A voice that's already playing the same note, even if others are free.
+    // Otherwise like Oldest.
+    SameNote,
+}
+impl VoiceStealing {
+    pub const ALL: [VoiceStealing; 3] = [
+        VoiceStealing::Oldest,
+        VoiceStealing::Quietest,
+        VoiceStealing::SameNote,
+    ];
+}
+impl Display for VoiceStealing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VoiceStealing::Oldest => "Steal oldest",
+            VoiceStealing::Quietest => "Steal quietest",
+            VoiceStealing::SameNote => "Steal same note",
+        })
+    }
+}
+
+/// The frequency in Hz of a MIDI note number, where 69 is A above middle C.
+pub fn note_to_frequency(note: u8) -> f32 {
+    440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+/// The name of a MIDI note number, like "C4" for middle C (60).
+pub fn note_name(note: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!("{}{}", NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// One note's worth of the synthesizer.
+#[derive(Clone, Debug)]
+pub struct Voice {
+    oscillator: Oscillator,
+    envelope: Envelope,
+    filter: Filter,
+    filter_envelope: Envelope,
+
+    // The MIDI note we're playing, or last played.
+    note: u8,
+
+    // Whether the note's key is still down. If not, we might still be
+    // sounding until the envelope's release is over.
+    is_held: bool,
+
+    // From 0.0 to 1.0.
+    velocity: f32,
+
+    // When the note started, as a count of notes started before it. Used to
+    // find the oldest voice.
+    started: u64,
+}
+impl Voice {
+    pub fn new_with(oscillator: Oscillator) -> Self {
+        Self {
+            oscillator,
+            envelope: Envelope::default(),
+            filter: Filter::default(),
+            filter_envelope: Envelope::default(),
+            note: 0,
+            is_held: false,
+            velocity: 0.0,
+            started: 0,
+        }
+    }
+
+    /// The note we're sounding, or None if we're free.
+    pub fn note(&self) -> Option<u8> {
+        self.is_active().then_some(self.note)
+    }
+
+    /// The note whose key is down, or None if it's been released.
+    pub fn held_note(&self) -> Option<u8> {
+        self.is_held.then_some(self.note)
+    }
+
+    /// True until the release is over.
+    pub fn is_active(&self) -> bool {
+        !self.envelope.is_idle()
+    }
+
+    pub fn started(&self) -> u64 {
+        self.started
+    }
+
+    /// How loud the voice is right now, from 0.0 to 1.0.
+    pub fn level(&self) -> f32 {
+        self.envelope.level() * self.velocity
+    }
+
+    /// Starts playing `note`, abandoning whatever the voice was doing. The
+    /// oscillator keeps its phase, and the envelope its level, so a stolen
+    /// voice doesn't click any more than it has to.
+    pub fn start(&mut self, note: u8, velocity: u8, started: u64) {
+        if !self.is_active() {
+            // Whatever's left in the filter is from a note long gone.
+            self.filter.reset();
+        }
+        self.note = note;
+        self.is_held = true;
+        self.velocity = velocity.min(127) as f32 / 127.0;
+        self.started = started;
+        self.envelope.note_on();
+        self.filter_envelope.note_on();
+    }
+
+    /// Lets go of the note. It fades out according to the envelope's release.
+    pub fn release(&mut self) {
+        self.is_held = false;
+        self.envelope.note_off();
+        self.filter_envelope.note_off();
+    }
+
+    /// Fills `block` with the voice's output at `sample_rate`, played
+    /// according to `patch`, or with silence if the voice is free.
+    pub fn render(
+        &mut self,
+        block: &mut [StereoSample],
+        sample_rate: usize,
+        patch: &Patch,
+        modulation: &SharedModulation,
+    ) {
+        if !self.is_active() {
+            block.fill(StereoSample::default());
+            return;
+        }
+        let filter = &patch.filter;
+        self.oscillator.set_waveform(patch.waveform);
+        let phase_increment = note_to_frequency(self.note) as f64 / sample_rate as f64;
+
+        // Key tracking doesn't change during the note, so it's worked out once.
+        let key_tracking = filter.key_tracking * (self.note as f32 - 60.0) / 12.0;
+        for (i, sample) in block.iter_mut().enumerate() {
+            let level = self.envelope.next_level(&patch.envelope, sample_rate);
+            let filter_level = self
+                .filter_envelope
+                .next_level(&filter.envelope, sample_rate);
+            let amounts = patch.mod_matrix.apply(&ModSourceValues {
+                lfo1: modulation.lfos[0][i],
+                lfo2: modulation.lfos[1][i],
+                amp_envelope: level,
+                filter_envelope: filter_level,
+                velocity: self.velocity,
+                mod_wheel: modulation.mod_wheel,
+            });
+
+            self.oscillator
+                .set_pulse_width(patch.pulse_width + amounts.get(ModDestination::PulseWidth));
+            let raw = self.oscillator.next_sample(
+                phase_increment
+                    * ((amounts.get(ModDestination::Pitch) + modulation.pitch_bend) as f64 / 12.0)
+                        .exp2(),
+            );
+            let cutoff = filter.cutoff
+                * (filter.envelope_amount * filter_level
+                    + key_tracking
+                    + amounts.get(ModDestination::Cutoff))
+                .exp2();
+            let filtered =
+                self.filter
+                    .process(raw, filter.mode, cutoff, filter.resonance, sample_rate);
+            let output = filtered
+                * level
+                * self.velocity
+                * (1.0 + amounts.get(ModDestination::Amplitude)).max(0.0);
+
+            // Equal-power panning, scaled so that the center is unchanged.
+            let angle = (amounts.get(ModDestination::Pan).clamp(-1.0, 1.0) + 1.0)
+                * std::f32::consts::FRAC_PI_4;
+            *sample = StereoSample {
+                left: output * angle.cos() * std::f32::consts::SQRT_2,
+                right: output * angle.sin() * std::f32::consts::SQRT_2,
+            };
+        }
+    }
+}
+
+/// Modulation sources that all voices share, for one block.
+#[derive(Debug)]
+pub struct SharedModulation<'a> {
+    // Each LFO's value for every sample in the block.
+    pub lfos: [&'a [f32]; Patch::LFO_COUNT],
+
+    pub mod_wheel: f32,
+
+    // In semitones.
+    pub pitch_bend: f32,
+}
@@ -0,0 +1,271 @@
+//! Worker threads that render [Voice]s for [crate::synthesizer::Synthesizer]
+//! when it's set to [crate::synthesizer::VoiceRendering::Parallel]. The
+//! threads live as long as the setting does, rather than being started for
+//! every block, because a block that's full of events gets rendered in many
+//! short pieces.
+
+use crate::{
+    patch::Patch,
+    stream::StereoSample,
+    voice::{SharedModulation, Voice},
+};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::{
+    fmt::{Debug, Display},
+    panic::{catch_unwind, AssertUnwindSafe},
+    thread::JoinHandle,
+};
+
+/// One worker's share of a piece of a block: the voices that it renders, and
+/// everything they need to do it. Jobs go to the workers and come back, so
+/// their buffers are reused rather than reallocated every time.
+#[derive(Debug, Default)]
+struct VoiceJob {
+    voices: Vec<Voice>,
+    blocks: Vec<Vec<StereoSample>>,
+    sample_rate: usize,
+    patch: Patch,
+
+    // Copies of what SharedModulation borrows, which can't cross threads.
+    lfo_blocks: [Vec<f32>; Patch::LFO_COUNT],
+    mod_wheel: f32,
+    pitch_bend: f32,
+
+    // Whether a voice panicked while rendering this job.
+    panicked: bool,
+}
+impl VoiceJob {
+    fn render(&mut self) {
+        let modulation = SharedModulation {
+            lfos: [&self.lfo_blocks[0], &self.lfo_blocks[1]],
+            mod_wheel: self.mod_wheel,
+            pitch_bend: self.pitch_bend,
+        };
+        for (voice, block) in self.voices.iter_mut().zip(self.blocks.iter_mut()) {
+            voice.render(block, self.sample_rate, &self.patch, &modulation);
+        }
+    }
+}
+
+/// Why [VoicePool::render] couldn't render every voice. The voices may be in
+/// any state, and some of them may be missing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VoicePanic;
+impl Display for VoicePanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A voice worker panicked")
+    }
+}
+impl std::error::Error for VoicePanic {}
+
+#[derive(Debug)]
+struct Worker {
+    jobs: Sender<(usize, VoiceJob)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// A fixed number of worker threads, each of which renders a contiguous run
+/// of voices.
+#[derive(Debug)]
+pub struct VoicePool {
+    workers: Vec<Worker>,
+
+    // Finished jobs, each with the index of the worker that it went to.
+    finished: Receiver<(usize, VoiceJob)>,
+
+    // Indexed like workers. Each is Some except while its worker has it.
+    jobs: Vec<Option<VoiceJob>>,
+}
+impl VoicePool {
+    pub fn new_with(worker_count: usize) -> Self {
+        let (finished_sender, finished) = unbounded();
+        let workers = (0..worker_count.max(1))
+            .map(|i| {
+                let (jobs, job_receiver) = unbounded::<(usize, VoiceJob)>();
+                let finished_sender = finished_sender.clone();
+                let handle = std::thread::Builder::new()
+                    .name(format!("voice worker {}", i))
+                    .spawn(move || {
+                        for (index, mut job) in job_receiver {
+                            // The voices come back either way, so that the
+                            // synthesizer can decide what to do with them.
+                            job.panicked = catch_unwind(AssertUnwindSafe(|| job.render())).is_err();
+                            if finished_sender.send((index, job)).is_err() {
+                                break;
+                            }
+                        }
+                    })
+                    .ok();
+                Worker { jobs, handle }
+            })
+            .collect::<Vec<_>>();
+        let jobs = workers.iter().map(|_| Some(VoiceJob::default())).collect();
+        Self {
+            workers,
+            finished,
+            jobs,
+        }
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Renders each of `voices` into the matching one of `blocks`, spread
+    /// across the workers, and waits for them all to finish. On success, the
+    /// voices and blocks are back where they started.
+    pub fn render(
+        &mut self,
+        voices: &mut Vec<Voice>,
+        blocks: &mut Vec<Vec<StereoSample>>,
+        sample_rate: usize,
+        patch: &Patch,
+        modulation: &SharedModulation,
+    ) -> Result<(), VoicePanic> {
+        let voices_per_worker = voices.len().div_ceil(self.workers.len()).max(1);
+        let mut voices_left = voices.drain(..);
+        let mut blocks_left = blocks.drain(..);
+        let mut sent = 0;
+        for (index, (worker, slot)) in self.workers.iter().zip(self.jobs.iter_mut()).enumerate() {
+            let Some(mut job) = slot.take() else {
+                continue;
+            };
+            job.voices
+                .extend(voices_left.by_ref().take(voices_per_worker));
+            job.blocks
+                .extend(blocks_left.by_ref().take(voices_per_worker));
+            job.sample_rate = sample_rate;
+            job.patch.clone_from(patch);
+            for (copy, lfo) in job.lfo_blocks.iter_mut().zip(modulation.lfos) {
+                copy.clear();
+                copy.extend_from_slice(lfo);
+            }
+            job.mod_wheel = modulation.mod_wheel;
+            job.pitch_bend = modulation.pitch_bend;
+            match worker.jobs.send((index, job)) {
+                Ok(()) => sent += 1,
+
+                // The worker never started. Its voices stay as they were.
+                Err(e) => *slot = Some(e.into_inner().1),
+            }
+        }
+        drop(voices_left);
+        drop(blocks_left);
+
+        let mut result = Ok(());
+        for _ in 0..sent {
+            let Ok((index, job)) = self.finished.recv() else {
+                return Err(VoicePanic);
+            };
+            if job.panicked {
+                result = Err(VoicePanic);
+            }
+            self.jobs[index] = Some(job);
+        }
+        if sent < self.workers.len() {
+            result = Err(VoicePanic);
+        }
+
+        // Back in their original order.
+        for job in self.jobs.iter_mut().flatten() {
+            voices.append(&mut job.voices);
+            blocks.append(&mut job.blocks);
+        }
+        result
+    }
+}
+impl Drop for VoicePool {
+    fn drop(&mut self) {
+        for Worker { jobs, handle } in self.workers.drain(..) {
+            // Closing the channel tells the worker to exit.
+            drop(jobs);
+            if let Some(handle) = handle {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::Oscillator;
+
+    const BLOCK: usize = 64;
+
+    fn playing_voices(count: usize) -> Vec<Voice> {
+        (0..count)
+            .map(|i| {
+                let mut voice = Voice::new_with(Oscillator::new_with(i as u32 + 1));
+                voice.start(60 + i as u8, 100, i as u64);
+                voice
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renders_every_voice_in_place() {
+        let mut pool = VoicePool::new_with(3);
+        let mut voices = playing_voices(8);
+        let mut blocks = vec![vec![StereoSample::default(); BLOCK]; voices.len()];
+        let lfo_block = vec![0.0; BLOCK];
+        let modulation = SharedModulation {
+            lfos: [&lfo_block, &lfo_block],
+            mod_wheel: 0.0,
+            pitch_bend: 0.0,
+        };
+        let result = pool.render(
+            &mut voices,
+            &mut blocks,
+            48000,
+            &Patch::default(),
+            &modulation,
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(voices.len(), 8);
+        assert_eq!(blocks.len(), 8);
+
+        // Still in order.
+        let notes: Vec<_> = voices.iter().filter_map(Voice::note).collect();
+        assert_eq!(notes, (60..68).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn reports_panicking_voice_and_keeps_working() {
+        let mut pool = VoicePool::new_with(2);
+        let mut voices = playing_voices(4);
+        let mut blocks = vec![vec![StereoSample::default(); BLOCK]; voices.len()];
+
+        // LFO blocks shorter than the voice blocks make every voice panic.
+        let modulation = SharedModulation {
+            lfos: [&[], &[]],
+            mod_wheel: 0.0,
+            pitch_bend: 0.0,
+        };
+        let result = pool.render(
+            &mut voices,
+            &mut blocks,
+            48000,
+            &Patch::default(),
+            &modulation,
+        );
+        assert_eq!(result, Err(VoicePanic));
+        assert_eq!(voices.len(), 4);
+        assert_eq!(blocks.len(), 4);
+
+        // The workers survived.
+        let lfo_block = vec![0.0; BLOCK];
+        let modulation = SharedModulation {
+            lfos: [&lfo_block, &lfo_block],
+            ..modulation
+        };
+        let result = pool.render(
+            &mut voices,
+            &mut blocks,
+            48000,
+            &Patch::default(),
+            &modulation,
+        );
+        assert_eq!(result, Ok(()));
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{
+    stream::{AudioQueue, StereoSample},
+    synthesizer::Synthesizer,
+};
+use crossbeam::queue::ArrayQueue;
+use std::{
+    fmt::{Debug, Display},
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// The kinds of WAV file we know how to write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    #[default]
+    Float32,
+}
+impl WavFormat {
+    pub const ALL: [WavFormat; 3] = [WavFormat::Int16, WavFormat::Int24, WavFormat::Float32];
+
+    /// Parses the names used on the command line.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "int16" => Some(WavFormat::Int16),
+            "int24" => Some(WavFormat::Int24),
+            "float32" => Some(WavFormat::Float32),
+            _ => None,
+        }
+    }
+
+    fn spec(&self, sample_rate: u32) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            WavFormat::Int16 => (16, hound::SampleFormat::Int),
+            WavFormat::Int24 => (24, hound::SampleFormat::Int),
+            WavFormat::Float32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+impl Display for WavFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WavFormat::Int16 => "16-bit",
+            WavFormat::Int24 => "24-bit",
+            WavFormat::Float32 => "32-bit float",
+        })
+    }
+}
+
+/// Writes [StereoSample]s to a WAV file in any [WavFormat].
+pub struct WavWriter {
+    writer: hound::WavWriter<BufWriter<File>>,
+    format: WavFormat,
+}
+impl Debug for WavWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WavWriter")
+            .field("writer", &"(skipped)")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, format: WavFormat) -> Result<Self, hound::Error> {
+        Ok(Self {
+            writer: hound::WavWriter::create(path, format.spec(sample_rate))?,
+            format,
+        })
+    }
+
+    pub fn write(&mut self, sample: &StereoSample) -> Result<(), hound::Error> {
+        self.write_one(sample.left)?;
+        self.write_one(sample.right)
+    }
+
+    /// Integer formats clip at full scale, just as the audio interface would.
+    fn write_one(&mut self, sample: f32) -> Result<(), hound::Error> {
+        match self.format {
+            WavFormat::Int16 => self
+                .writer
+                .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+            WavFormat::Int24 => self
+                .writer
+                .write_sample((sample.clamp(-1.0, 1.0) * 8_388_607.0) as i32),
+            WavFormat::Float32 => self.writer.write_sample(sample),
+        }
+    }
+
+    /// Updates the file's header with its final length. The file is still
+    /// readable if this never happens, but most tools will think it's empty.
+    pub fn finalize(self) -> Result<(), hound::Error> {
+        self.writer.finalize()
+    }
+}
+
+/// Renders `seconds` of `synthesizer`'s output at its current sample rate, and
+/// writes it to `path`. This runs as fast as the synthesizer can go, without
+/// involving the audio interface at all, so it's good for capturing output for
+/// regression checks.
+pub fn render_to_wav(
+    synthesizer: &mut Synthesizer,
+    seconds: f32,
+    path: &Path,
+    format: WavFormat,
+) -> Result<(), hound::Error> {
+    const CHUNK_SIZE: usize = 1024;
+
+    let sample_rate = synthesizer.sample_rate;
+    let mut writer = WavWriter::create(path, sample_rate as u32, format)?;
+    let queue: AudioQueue = Arc::new(ArrayQueue::new(CHUNK_SIZE));
+    let mut remaining = (seconds.max(0.0) * sample_rate as f32) as usize;
+    while remaining > 0 {
+        let count = remaining.min(CHUNK_SIZE);
+        synthesizer
+            .generate_audio(count, Arc::clone(&queue), Instant::now())
+            .map_err(|e| hound::Error::IoError(std::io::Error::other(e)))?;
+        while let Some(sample) = queue.pop() {
+            writer.write(&sample)?;
+        }
+        remaining -= count;
+    }
+    writer.finalize()
+}
+
+/// Where the stream callback leaves copies of the samples it plays, for a
+/// [Recorder] to pick up. The callback copies samples here only while a
+/// recording is in progress.
+#[derive(Debug)]
+pub struct RecordingTap {
+    is_recording: AtomicBool,
+    queue: ArrayQueue<StereoSample>,
+}
+impl Default for RecordingTap {
+    fn default() -> Self {
+        Self {
+            is_recording: AtomicBool::new(false),
+
+            // A little over a second at typical rates. The recorder empties
+            // this much more often than that, so if it ever fills, the disk
+            // is having a very bad day.
+            queue: ArrayQueue::new(65536),
+        }
+    }
+}
+impl RecordingTap {
+    /// Called from the stream callback, so it must never block. If the
+    /// recorder has fallen behind, the oldest samples are dropped.
+    pub fn push(&self, sample: StereoSample) {
+        if self.is_recording.load(Ordering::Relaxed) {
+            let _ = self.queue.force_push(sample);
+        }
+    }
+}
+
+/// Copies everything the audio stream plays into a WAV file. The writing
+/// happens on a thread of its own, so that the stream callback never waits on
+/// the disk.
+#[derive(Debug)]
+pub struct Recorder {
+    tap: Arc<RecordingTap>,
+    path: PathBuf,
+    sample_rate: u32,
+    should_stop: Arc<AtomicBool>,
+
+    // Set by the writer thread if it gives up.
+    has_failed: Arc<AtomicBool>,
+    writer_thread: Option<JoinHandle<Result<(), hound::Error>>>,
+}
+impl Recorder {
+    /// How long the writer thread sleeps when it has caught up with the tap.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Creates the file and starts recording into it. Anything that's wrong
+    /// with the path shows up here rather than later.
+    pub fn start(
+        tap: &Arc<RecordingTap>,
+        path: &Path,
+        sample_rate: u32,
+        format: WavFormat,
+    ) -> Result<Self, hound::Error> {
+        let writer = WavWriter::create(path, sample_rate, format)?;
+
+        // Anything left over from an earlier recording isn't ours.
+        while tap.queue.pop().is_some() {}
+        tap.is_recording.store(true, Ordering::Relaxed);
+
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let has_failed = Arc::new(AtomicBool::new(false));
+        let writer_thread = {
+            let tap = Arc::clone(tap);
+            let should_stop = Arc::clone(&should_stop);
+            let has_failed = Arc::clone(&has_failed);
+            std::thread::spawn(move || {
+                let result = Self::write_until_stopped(&tap, &should_stop, writer);
+                if result.is_err() {
+                    // Nobody will drain the tap now, so stop filling it.
+                    tap.is_recording.store(false, Ordering::Relaxed);
+                    has_failed.store(true, Ordering::Relaxed);
+                }
+                result
+            })
+        };
+        Ok(Self {
+            tap: Arc::clone(tap),
+            path: path.to_path_buf(),
+            sample_rate,
+            should_stop,
+            has_failed,
+            writer_thread: Some(writer_thread),
+        })
+    }
+
+    fn write_until_stopped(
+        tap: &RecordingTap,
+        should_stop: &AtomicBool,
+        mut writer: WavWriter,
+    ) -> Result<(), hound::Error> {
+        loop {
+            // Check before draining, so that the last drain after we're told
+            // to stop catches everything.
+            let is_stopping = should_stop.load(Ordering::Relaxed);
+            while let Some(sample) = tap.queue.pop() {
+                writer.write(&sample)?;
+            }
+            if is_stopping {
+                break;
+            }
+            std::thread::sleep(Self::POLL_INTERVAL);
+        }
+        writer.finalize()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Whether the writer thread gave up partway through, in which case
+    /// [Self::stop] returns the reason.
+    pub fn has_failed(&self) -> bool {
+        self.has_failed.load(Ordering::Relaxed)
+    }
+
+    /// Stops recording, writes whatever's still waiting, and closes the file.
+    pub fn stop(mut self) -> Result<(), hound::Error> {
+        self.stop_writer_thread()
+    }
+
+    fn stop_writer_thread(&mut self) -> Result<(), hound::Error> {
+        self.tap.is_recording.store(false, Ordering::Relaxed);
+        self.should_stop.store(true, Ordering::Relaxed);
+        if let Some(writer_thread) = self.writer_thread.take() {
+            // A panic in the writer thread means the file is as good as gone.
+            writer_thread
+                .join()
+                .unwrap_or(Err(hound::Error::Unsupported))
+        } else {
+            Ok(())
+        }
+    }
+}
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.stop_writer_thread();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_write_stops_recording() {
+        let tap = Arc::new(RecordingTap::default());
+
+        // Opens fine, but every write past the header's buffer fails.
+        let recorder = Recorder::start(&tap, Path::new("/dev/full"), 48000, WavFormat::Float32)
+            .expect("opening /dev/full");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !recorder.has_failed() {
+            assert!(Instant::now() < deadline, "the writer never failed");
+            for _ in 0..4096 {
+                tap.push(StereoSample::default());
+            }
+            std::thread::sleep(Recorder::POLL_INTERVAL);
+        }
+        assert!(!tap.is_recording.load(Ordering::Relaxed));
+        assert!(recorder.stop().is_err());
+    }
+}